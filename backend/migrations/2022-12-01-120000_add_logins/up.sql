@@ -0,0 +1,16 @@
+-- every issued session token belongs to a login, identified by the `jti` claim of the token
+-- renewed tokens keep the `jti`, so revoking a login invalidates all of its tokens
+CREATE TABLE logins
+(
+    jti TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    -- what the user logged in from, for showing in the list of active logins
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMP NOT NULL,
+    -- the login can't be used after this time even if it was not revoked (max session age)
+    expires_at TIMESTAMP NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX logins_user_id_idx ON logins (user_id);
@@ -0,0 +1,8 @@
+-- roles are ordered, each one includes the rights of the previous ones:
+-- student < teaching_assistant < teacher < admin
+ALTER TABLE users
+    ADD COLUMN role TEXT NOT NULL DEFAULT 'student'
+        CHECK (role IN ('student', 'teaching_assistant', 'teacher', 'admin'));
+
+-- everyone who has already created a session is a teacher
+UPDATE users SET role = 'teacher' WHERE id IN (SELECT owner_id FROM sessions);
@@ -0,0 +1,15 @@
+-- users that can access a session, in addition to its creator (sessions.owner_id)
+-- permissions are ordered, each one includes the rights of the previous ones: viewer < marker < owner
+CREATE TABLE session_members
+(
+    session_id INTEGER NOT NULL REFERENCES sessions(id),
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    permission TEXT NOT NULL CHECK (permission IN ('viewer', 'marker', 'owner')),
+    PRIMARY KEY (session_id, user_id)
+);
+
+CREATE INDEX session_members_user_id_idx ON session_members (user_id);
+
+-- the creator is always a member with the owner permission, so that the access checks only need this table
+INSERT INTO session_members (session_id, user_id, permission)
+SELECT id, owner_id, 'owner' FROM sessions;
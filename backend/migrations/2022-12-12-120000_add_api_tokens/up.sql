@@ -0,0 +1,19 @@
+-- long-lived tokens for scripts, sent in the `Authorization: Bearer` header
+CREATE TABLE api_tokens
+(
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    -- given by the user to tell their tokens apart
+    name TEXT NOT NULL,
+    -- SHA-256 of the token, the token itself is only shown once when it is created
+    token_hash BYTEA NOT NULL UNIQUE,
+    -- what the token can be used for, e.g. 'sessions:read' or 'marks:write'
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    -- NULL if the token does not expire
+    expires_at TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
@@ -0,0 +1,2 @@
+DROP TABLE used_webauthn_flows;
+DROP TABLE webauthn_credentials;
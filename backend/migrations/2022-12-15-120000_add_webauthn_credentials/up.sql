@@ -0,0 +1,25 @@
+-- passkeys of staff users for WebAuthn login
+CREATE TABLE webauthn_credentials
+(
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    -- given by the user to tell their passkeys apart
+    name TEXT NOT NULL,
+    -- as returned by the authenticator, unique across all users
+    credential_id BYTEA NOT NULL UNIQUE,
+    -- the serialized webauthn-rs passkey, including the public key and the signature counter
+    passkey TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    last_used_at TIMESTAMP
+);
+
+CREATE INDEX webauthn_credentials_user_id_idx ON webauthn_credentials (user_id);
+
+-- passkey login ceremonies that were already finished, kept until they expire so that each state cookie works only once
+CREATE TABLE used_webauthn_flows
+(
+    jti TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    used_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,27 @@
+-- TOTP second factor of teachers and admins
+CREATE TABLE totp_secrets
+(
+    user_id INTEGER PRIMARY KEY REFERENCES users(id),
+    -- AES-256-GCM encrypted with the key from the config: nonce, ciphertext and tag
+    secret BYTEA NOT NULL,
+    -- the secret is only used for logging in after the user has entered a code from it
+    confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL,
+    -- time step of the last accepted code, so that a code can't be used twice
+    last_used_step BIGINT,
+    -- consecutive wrong codes, the verification is locked for a while once there are too many
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    locked_until TIMESTAMP
+);
+
+-- single-use codes for when the authenticator app is lost
+CREATE TABLE recovery_codes
+(
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    -- SHA-256 of the code, the codes are only shown once
+    code_hash BYTEA NOT NULL,
+    used_at TIMESTAMP
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
@@ -0,0 +1,25 @@
+-- passwords of local accounts, for deployments without an identity provider
+CREATE TABLE user_passwords
+(
+    user_id INTEGER PRIMARY KEY REFERENCES users(id),
+    -- Argon2id in the PHC string format
+    password_hash TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL,
+    -- consecutive wrong passwords, the account is locked for a while once there are too many
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    locked_until TIMESTAMP
+);
+
+-- single-use tokens given out by admins for setting a password
+CREATE TABLE password_tokens
+(
+    -- SHA-256 of the token, the token itself is only shown to the admin once
+    token_hash BYTEA PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    purpose TEXT NOT NULL CHECK (purpose IN ('invite', 'reset')),
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    used BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX password_tokens_user_id_idx ON password_tokens (user_id);
@@ -0,0 +1,8 @@
+-- magic login links that were already used, kept until they expire so that each works only once
+CREATE TABLE used_magic_links
+(
+    jti TEXT PRIMARY KEY,
+    email TEXT NOT NULL,
+    used_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
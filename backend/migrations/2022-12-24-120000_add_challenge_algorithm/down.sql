@@ -0,0 +1,3 @@
+ALTER TABLE sessions
+    DROP COLUMN challenge_tag_length,
+    DROP COLUMN challenge_algorithm;
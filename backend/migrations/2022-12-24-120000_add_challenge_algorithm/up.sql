@@ -0,0 +1,7 @@
+-- the MAC of the QR code challenges, existing sessions keep the legacy one
+ALTER TABLE sessions
+    ADD COLUMN challenge_algorithm TEXT NOT NULL DEFAULT 'hmac-sha1'
+        CHECK (challenge_algorithm IN ('hmac-sha1', 'hmac-sha256')),
+    -- in bytes, always 4 for HMAC-SHA1
+    ADD COLUMN challenge_tag_length INTEGER NOT NULL DEFAULT 4
+        CHECK (challenge_tag_length BETWEEN 4 AND 32);
@@ -0,0 +1,3 @@
+ALTER TABLE sessions
+    DROP COLUMN jitter_window_ms,
+    DROP COLUMN qr_interval_ms;
@@ -0,0 +1,4 @@
+-- overrides of the challenge timing from the config, NULL to use the config
+ALTER TABLE sessions
+    ADD COLUMN qr_interval_ms INTEGER CHECK (qr_interval_ms > 0),
+    ADD COLUMN jitter_window_ms INTEGER CHECK (jitter_window_ms >= 0);
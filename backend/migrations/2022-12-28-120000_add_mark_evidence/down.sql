@@ -0,0 +1,6 @@
+DROP INDEX marks_session_id_challenge_index_idx;
+
+ALTER TABLE marks
+    DROP COLUMN late_ms,
+    DROP COLUMN ip,
+    DROP COLUMN challenge_index;
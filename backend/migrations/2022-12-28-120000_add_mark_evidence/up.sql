@@ -0,0 +1,8 @@
+-- how the challenge was redeemed, for spotting forwarded QR codes, NULL for manual marks
+ALTER TABLE marks
+    ADD COLUMN challenge_index INTEGER,
+    ADD COLUMN ip TEXT,
+    -- how long after its code was replaced on the screen the challenge was submitted
+    ADD COLUMN late_ms INTEGER;
+
+CREATE INDEX marks_session_id_challenge_index_idx ON marks (session_id, challenge_index);
@@ -0,0 +1,10 @@
+ALTER TABLE marks
+    DROP COLUMN distance_m,
+    DROP COLUMN geofence_position;
+
+ALTER TABLE sessions
+    DROP CONSTRAINT sessions_geofence_check,
+    DROP COLUMN geofence_policy,
+    DROP COLUMN radius_m,
+    DROP COLUMN longitude,
+    DROP COLUMN latitude;
@@ -0,0 +1,17 @@
+-- the place of the session, the challenges are checked against it if it is set
+ALTER TABLE sessions
+    ADD COLUMN latitude DOUBLE PRECISION,
+    ADD COLUMN longitude DOUBLE PRECISION,
+    ADD COLUMN radius_m INTEGER CHECK (radius_m > 0),
+    -- what happens to the challenges from outside: `reject` them or accept them with a `flag`
+    ADD COLUMN geofence_policy TEXT CHECK (geofence_policy IN ('reject', 'flag')),
+    ADD CONSTRAINT sessions_geofence_check CHECK (
+        (latitude IS NULL) = (longitude IS NULL)
+        AND (latitude IS NULL) = (radius_m IS NULL)
+        AND (latitude IS NULL) = (geofence_policy IS NULL)
+    );
+
+-- where the device was relative to the session place, NULL for sessions without one
+ALTER TABLE marks
+    ADD COLUMN geofence_position TEXT CHECK (geofence_position IN ('inside', 'outside', 'unknown')),
+    ADD COLUMN distance_m INTEGER;
@@ -0,0 +1,3 @@
+ALTER TABLE sessions
+    DROP COLUMN network_policy,
+    DROP COLUMN allowed_networks;
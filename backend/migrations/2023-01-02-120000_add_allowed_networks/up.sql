@@ -0,0 +1,5 @@
+-- overrides of the allowed client networks from the config, in the CIDR notation
+ALTER TABLE sessions
+    ADD COLUMN allowed_networks TEXT[],
+    -- what happens to the challenges from other networks: `reject` them or accept them with a `flag`
+    ADD COLUMN network_policy TEXT CHECK (network_policy IN ('reject', 'flag'));
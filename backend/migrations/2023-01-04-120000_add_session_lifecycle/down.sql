@@ -0,0 +1,5 @@
+ALTER TABLE marks
+    DROP COLUMN note;
+
+ALTER TABLE sessions
+    DROP COLUMN end_time;
@@ -0,0 +1,8 @@
+-- when the session was closed, NULL while it is still ongoing. `active` is now whether it accepts check-ins,
+--   so a paused session is active = FALSE with no end time
+ALTER TABLE sessions
+    ADD COLUMN end_time TIMESTAMP;
+
+-- who added the mark and why, for the marks added after the session was closed
+ALTER TABLE marks
+    ADD COLUMN note TEXT;
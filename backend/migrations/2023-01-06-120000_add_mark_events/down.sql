@@ -0,0 +1 @@
+DROP TABLE mark_events;
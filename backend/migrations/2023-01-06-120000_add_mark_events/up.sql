@@ -0,0 +1,13 @@
+-- changes of the marks, in order, for the attendance feed of the teacher page. The id is the cursor of the feed
+CREATE TABLE mark_events
+(
+    id BIGSERIAL PRIMARY KEY,
+    session_id INTEGER NOT NULL REFERENCES sessions(id),
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    -- not a reference, the removed marks are gone
+    mark_id INTEGER NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('added', 'removed')),
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX mark_events_session_id_id_idx ON mark_events (session_id, id);
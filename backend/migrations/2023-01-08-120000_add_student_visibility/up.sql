@@ -0,0 +1,4 @@
+-- override of what the students are told about the others marked in the session: nothing (`hidden`),
+--   their number (`count`) or also the names of the last ones (`names`)
+ALTER TABLE sessions
+    ADD COLUMN student_visibility TEXT CHECK (student_visibility IN ('hidden', 'count', 'names'));
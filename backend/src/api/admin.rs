@@ -0,0 +1,73 @@
+use crate::api::auth::{AuthError, UserClaims, UserToken};
+use crate::api::error::ApiResult;
+use crate::api::models;
+use crate::db;
+use crate::db::models::Role;
+use crate::db::DbData;
+use actix_web::{delete, get, put, web};
+use tracing::{info, Span};
+
+/// List users that have a role other than student
+#[get("/admin/users")]
+async fn get_staff(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+) -> ApiResult<web::Json<Vec<models::UserWithRole>>> {
+    user.require_role(Role::Admin)?;
+
+    let users = db
+        .send(db::GetStaff {
+            span: Span::current(),
+        })
+        .await??;
+
+    Ok(web::Json(users.into_iter().map(|u| u.into()).collect()))
+}
+
+async fn change_role(
+    user: UserClaims,
+    db: DbData,
+    username: String,
+    role: Role,
+) -> ApiResult<web::Json<models::UserWithRole>> {
+    user.require_role(Role::Admin)?;
+    // so that there is always at least one admin left
+    if username == user.username {
+        return Err(AuthError::OwnRoleChange.into());
+    }
+
+    let target = db
+        .send(db::SetUserRole {
+            span: Span::current(),
+            username,
+            role,
+        })
+        .await??;
+
+    info!(
+        "User {:?} set the role of {:?} to {}",
+        user.username, target.username, target.role
+    );
+
+    Ok(web::Json(target.into()))
+}
+
+#[put("/admin/users/{username}/role")]
+async fn set_role(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    path: web::Path<models::UserRef>,
+    body: web::Json<models::SetRole>,
+) -> ApiResult<web::Json<models::UserWithRole>> {
+    change_role(user, db, path.into_inner().username, body.into_inner().role).await
+}
+
+/// Revoke the role of the user, making them a student
+#[delete("/admin/users/{username}/role")]
+async fn reset_role(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    path: web::Path<models::UserRef>,
+) -> ApiResult<web::Json<models::UserWithRole>> {
+    change_role(user, db, path.into_inner().username, Role::Student).await
+}
@@ -1,17 +1,116 @@
 use crate::api::error::ApiError;
+use crate::db;
 use crate::db::models as db_models;
+use crate::db::models::{Role, Scope};
+use crate::db::DbData;
 use actix_web::cookie::time::OffsetDateTime;
-use actix_web::cookie::{Cookie, Expiration};
-use actix_web::http::StatusCode;
+use actix_web::cookie::{Cookie, CookieBuilder, Expiration, SameSite};
+use actix_web::http::{header, StatusCode};
 use actix_web::web::ServiceConfig;
 use actix_web::{web, FromRequest};
-use anyhow::{anyhow, Context, Result};
-use chrono::Duration;
-use ed25519_dalek::{Keypair, KEYPAIR_LENGTH};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::TimeZone;
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, SECRET_KEY_LENGTH};
+use futures::future::LocalBoxFuture;
 use jwt_compact::alg::Ed25519;
 use jwt_compact::{AlgorithmExt, TimeOptions, Token, UntrustedToken};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tracing::warn;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tracing::{info, warn, Span};
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Id of the key used to sign new tokens (put into the `kid` header)
+    pub signing_key: Option<String>,
+    /// Keys that are accepted when validating tokens
+    #[serde(default)]
+    pub keys: Vec<KeyConfig>,
+    /// Path to a secret file with more keys, in the same format as this section
+    ///
+    /// Keys from the file are added to the `keys` list, `signing_key` from the file takes precedence
+    pub keys_file: Option<PathBuf>,
+    /// Put into the `iss` claim of the tokens, tokens with a different issuer are rejected
+    #[serde(default = "default_issuer")]
+    pub issuer: String,
+    /// Put into the `aud` claim of the tokens, tokens with a different audience are rejected
+    #[serde(default = "default_audience")]
+    pub audience: String,
+    /// Allowed clock skew when checking `exp` and `nbf` of the tokens
+    #[serde(default = "default_leeway", with = "humantime_serde")]
+    pub leeway: std::time::Duration,
+    #[serde(default)]
+    pub cookie: CookieConfig,
+}
+
+fn default_issuer() -> String {
+    "baam".to_string()
+}
+fn default_audience() -> String {
+    "baam".to_string()
+}
+fn default_leeway() -> std::time::Duration {
+    std::time::Duration::from_secs(60)
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CookieConfig {
+    #[serde(default = "default_cookie_name")]
+    pub name: String,
+    /// Domain attribute of the cookie, if not set the cookie is only sent to the exact host
+    pub domain: Option<String>,
+    /// How long a token is valid. It is re-issued by `RenewSession` once past half of this
+    #[serde(default = "default_lifetime", with = "humantime_serde")]
+    pub lifetime: std::time::Duration,
+    /// Tokens are not renewed past this time since login, so the user has to log in again
+    #[serde(default = "default_max_session_age", with = "humantime_serde")]
+    pub max_session_age: std::time::Duration,
+    #[serde(default = "default_secure")]
+    pub secure: bool,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            name: default_cookie_name(),
+            domain: None,
+            lifetime: default_lifetime(),
+            max_session_age: default_max_session_age(),
+            secure: default_secure(),
+        }
+    }
+}
+
+fn default_cookie_name() -> String {
+    "session".to_string()
+}
+fn default_lifetime() -> std::time::Duration {
+    std::time::Duration::from_secs(3 * 60 * 60)
+}
+fn default_max_session_age() -> std::time::Duration {
+    std::time::Duration::from_secs(24 * 60 * 60)
+}
+fn default_secure() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct KeyConfig {
+    pub id: String,
+    /// Base64-encoded ed25519 keypair (secret key followed by the public key)
+    pub keypair: Option<String>,
+    /// Base64-encoded ed25519 public key. Enough for keys that are only used for validation
+    pub public_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct KeysFile {
+    signing_key: Option<String>,
+    #[serde(default)]
+    keys: Vec<KeyConfig>,
+}
 
 // pub type Authority = actix_jwt_auth_middleware::Authority<UserClaims>;
 
@@ -19,7 +118,18 @@ use tracing::warn;
 pub enum AuthError {
     NoCookie,
     UnparsableToken(jwt_compact::ParseError),
+    UnknownKey(Option<String>),
     InvalidToken(jwt_compact::ValidationError),
+    WrongIssuer(String),
+    WrongAudience(String),
+    WrongPurpose(String),
+    MissingClaim(&'static str),
+    RevokedToken,
+    InsufficientRole(Role),
+    OwnRoleChange,
+    InvalidApiToken,
+    MissingScope(Scope),
+    SecondFactorRequired,
 }
 
 impl ApiError for AuthError {
@@ -33,7 +143,36 @@ impl ApiError for AuthError {
                 StatusCode::BAD_REQUEST,
                 "Could not parse session token".to_string(),
             ),
-            AuthError::InvalidToken(_) => (
+            AuthError::InsufficientRole(role) => (
+                StatusCode::FORBIDDEN,
+                format!("This action requires the {} role", role),
+            ),
+            AuthError::OwnRoleChange => (
+                StatusCode::BAD_REQUEST,
+                "You can't change your own role".to_string(),
+            ),
+            AuthError::InvalidApiToken => (
+                StatusCode::UNAUTHORIZED,
+                "The API token is invalid, expired or revoked".to_string(),
+            ),
+            AuthError::MissingScope(scope) => (
+                StatusCode::FORBIDDEN,
+                format!("This action requires an API token with the {} scope", scope),
+            ),
+            AuthError::SecondFactorRequired => (
+                StatusCode::UNAUTHORIZED,
+                "Please verify your second factor at /api/totp/verify".to_string(),
+            ),
+            AuthError::RevokedToken => (
+                StatusCode::UNAUTHORIZED,
+                "Your session was logged out, please relogin".to_string(),
+            ),
+            AuthError::UnknownKey(_)
+            | AuthError::InvalidToken(_)
+            | AuthError::WrongIssuer(_)
+            | AuthError::WrongAudience(_)
+            | AuthError::WrongPurpose(_)
+            | AuthError::MissingClaim(_) => (
                 StatusCode::UNAUTHORIZED,
                 "Your session token does not pass validation, probably you should relogin"
                     .to_string(),
@@ -42,51 +181,188 @@ impl ApiError for AuthError {
     }
 }
 
+/// Claims of one kind of token signed by the [`Authority`]
+///
+/// All the tokens are signed with the same key for the same audience, so the purpose is put into the token
+///   to keep one kind from being accepted as another
+pub trait TokenClaims: Serialize + DeserializeOwned + Clone {
+    const PURPOSE: &'static str;
+}
+
+/// Registered claims that are not covered by [`jwt_compact::Claims`]
+#[derive(Serialize, Deserialize)]
+struct RegisteredClaims<T> {
+    iss: String,
+    aud: String,
+    typ: String,
+    #[serde(flatten)]
+    custom: T,
+}
+
 pub struct Authority {
-    pub cookie_name: &'static str,
-    key_pair: Keypair,
+    pub cookie_name: String,
+    cookie_domain: Option<String>,
+    cookie_secure: bool,
+    keys: AuthKeys,
     header: jwt_compact::Header,
+    issuer: String,
+    audience: String,
     time_options: TimeOptions,
     duration: Duration,
+    max_session_age: Duration,
 }
 
 impl Authority {
-    pub fn new(cookie_name: &'static str, key_pair: Keypair) -> Self {
-        Self {
-            cookie_name,
-            key_pair,
-            header: jwt_compact::Header::default(),
-            time_options: TimeOptions::default(),
-            duration: Duration::hours(3),
-        }
+    pub fn new(config: &Config, keys: AuthKeys) -> Result<Self> {
+        let cookie = &config.cookie;
+        Ok(Self {
+            cookie_name: cookie.name.clone(),
+            cookie_domain: cookie.domain.clone(),
+            cookie_secure: cookie.secure,
+            header: jwt_compact::Header::default().with_key_id(keys.signing_key_id.clone()),
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            time_options: TimeOptions::from_leeway(
+                Duration::from_std(config.leeway).context("Invalid leeway")?,
+            ),
+            duration: Duration::from_std(cookie.lifetime).context("Invalid cookie lifetime")?,
+            max_session_age: Duration::from_std(cookie.max_session_age)
+                .context("Invalid max session age")?,
+        })
     }
 
-    /// Produce cookie for user authentication
-    pub fn create_signed_cookie(&self, claims: UserClaims) -> Result<Cookie> {
-        let claims = jwt_compact::Claims::new(claims)
-            .set_duration_and_issuance(&self.time_options, self.duration);
+    pub fn max_session_age(&self) -> Duration {
+        self.max_session_age
+    }
+
+    /// Whether the cookies are only sent over HTTPS. The login flows use it for their own cookies too
+    pub fn cookie_secure(&self) -> bool {
+        self.cookie_secure
+    }
+
+    /// Sign arbitrary claims with the current signing key, returning the token and its expiration time
+    pub fn sign_token<T: TokenClaims>(
+        &self,
+        claims: T,
+        duration: Duration,
+    ) -> Result<(String, DateTime<Utc>)> {
+        let claims = jwt_compact::Claims::new(RegisteredClaims {
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            typ: T::PURPOSE.to_string(),
+            custom: claims,
+        })
+        .set_duration_and_issuance(&self.time_options, duration)
+        .set_not_before(Utc::now());
         let compact_token = Ed25519
-            .token(self.header.clone(), &claims, &self.key_pair)
+            .token(self.header.clone(), &claims, &self.keys.signing_key)
             .context("Could not create the token")?;
-        Ok(Cookie::build(self.cookie_name, compact_token)
-            .secure(true)
-            .http_only(true)
+        Ok((compact_token, claims.expiration.unwrap()))
+    }
+
+    /// Check the signature of a token produced by [`Authority::sign_token`] and its standard claims:
+    ///   that it is already valid, has not expired and was issued by us for us, for the purpose of `T`
+    pub fn validate_token<T: TokenClaims>(
+        &self,
+        token: &str,
+    ) -> Result<jwt_compact::Claims<T>, AuthError> {
+        let token = self.validate_integrity::<RegisteredClaims<T>>(token)?;
+        let claims = token.claims();
+
+        claims
+            .validate_expiration(&self.time_options)
+            .and_then(|c| c.validate_maturity(&self.time_options))
+            .map_err(AuthError::InvalidToken)?;
+        if claims.custom.iss != self.issuer {
+            return Err(AuthError::WrongIssuer(claims.custom.iss.clone()));
+        }
+        if claims.custom.aud != self.audience {
+            return Err(AuthError::WrongAudience(claims.custom.aud.clone()));
+        }
+        if claims.custom.typ != T::PURPOSE {
+            return Err(AuthError::WrongPurpose(claims.custom.typ.clone()));
+        }
+
+        let mut result = jwt_compact::Claims::new(claims.custom.custom.clone());
+        result.expiration = claims.expiration;
+        result.not_before = claims.not_before;
+        result.issued_at = claims.issued_at;
+        Ok(result)
+    }
+
+    /// Produce cookie for user authentication
+    ///
+    /// The token never outlives the maximum session age, counted from `claims.auth_time`
+    pub fn create_signed_cookie(&self, claims: UserClaims) -> Result<Cookie<'static>> {
+        let remaining = claims.auth_time + self.max_session_age - Utc::now();
+        if remaining <= Duration::zero() {
+            bail!("Session has reached its maximum age");
+        }
+
+        let (compact_token, expiration) = self.sign_token(claims, self.duration.min(remaining))?;
+        Ok(self
+            .cookie_builder(compact_token)
             .expires(Expiration::DateTime(OffsetDateTime::from_unix_timestamp(
-                claims.expiration.unwrap().timestamp(),
+                expiration.timestamp(),
             )?))
             .finish())
     }
 
-    fn extract_from_cookie(&self, cookie: Option<Cookie>) -> Result<Token<UserClaims>, AuthError> {
-        let cookie = cookie.ok_or(AuthError::NoCookie)?;
-        let untrusted_token =
-            UntrustedToken::new(cookie.value()).map_err(AuthError::UnparsableToken)?;
+    /// Produce cookie that removes the authentication cookie from the browser
+    pub fn create_removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.cookie_builder(String::new()).finish();
+        cookie.make_removal();
+        cookie
+    }
 
-        let token = Ed25519
-            .validate_integrity(&untrusted_token, &self.key_pair.public)
-            .map_err(AuthError::InvalidToken)?;
+    /// Produce a fresh cookie if the token in the current one is past half of its lifetime at `now`
+    ///
+    /// Returns `None` if the cookie is still fresh, is not valid or the session has reached its maximum age
+    pub fn renew_cookie(&self, cookie: &Cookie, now: DateTime<Utc>) -> Option<Cookie<'static>> {
+        let claims = self.validate_token::<UserClaims>(cookie.value()).ok()?;
+        let (issued_at, expiration) = (claims.issued_at?, claims.expiration?);
+
+        if now < issued_at + (expiration - issued_at) / 2 {
+            return None;
+        }
+
+        self.create_signed_cookie(claims.custom)
+            .map_err(|e| info!("Not renewing the session cookie: {:?}", e))
+            .ok()
+    }
+
+    fn cookie_builder(&self, value: String) -> CookieBuilder<'static> {
+        let builder = Cookie::build(self.cookie_name.clone(), value)
+            .secure(self.cookie_secure)
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .path("/");
+        match &self.cookie_domain {
+            Some(domain) => builder.domain(domain.clone()),
+            None => builder,
+        }
+    }
+
+    fn validate_integrity<T: DeserializeOwned>(&self, token: &str) -> Result<Token<T>, AuthError> {
+        let untrusted_token = UntrustedToken::new(token).map_err(AuthError::UnparsableToken)?;
+
+        let key_id = untrusted_token.header().key_id.as_ref();
+        let public_key = key_id
+            .and_then(|id| self.keys.verifying_keys.get(id))
+            .ok_or_else(|| AuthError::UnknownKey(key_id.cloned()))?;
+
+        Ed25519
+            .validate_integrity(&untrusted_token, public_key)
+            .map_err(AuthError::InvalidToken)
+    }
 
-        Ok(token)
+    fn extract_from_cookie(
+        &self,
+        cookie: Option<Cookie>,
+    ) -> Result<jwt_compact::Claims<UserClaims>, AuthError> {
+        let cookie = cookie.ok_or(AuthError::NoCookie)?;
+        self.validate_token(cookie.value())
     }
 }
 
@@ -95,57 +371,491 @@ pub struct UserClaims {
     pub user_id: db_models::UserId,
     pub username: String,
     pub name: String,
+    /// When the user has logged in, kept as is when the token is renewed
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub auth_time: DateTime<Utc>,
+    /// Id of the login in the `logins` table, kept as is when the token is renewed
+    pub jti: String,
+    /// Refreshed from the database on every request, so that role changes apply immediately
+    pub role: Role,
+    /// Scopes of the API token the request was made with, `None` for session cookies
+    #[serde(skip)]
+    pub scopes: Option<Vec<Scope>>,
 }
 
-impl FromRequest for UserClaims {
-    // works
+impl TokenClaims for UserClaims {
+    const PURPOSE: &'static str = "session";
+}
+
+impl UserClaims {
+    pub fn new(user: db_models::User, login: &db_models::Login) -> Self {
+        Self {
+            user_id: user.id,
+            username: user.username,
+            name: user
+                .name
+                .expect("BUG: User must have a name to be logged in"),
+            auth_time: Utc.from_utc_datetime(&login.created_at),
+            jti: login.jti.clone(),
+            role: user.role,
+            scopes: None,
+        }
+    }
+
+    fn from_api_token(user: db_models::User, token: db_models::ApiToken) -> Self {
+        Self {
+            user_id: user.id,
+            name: user.name.unwrap_or_else(|| user.username.clone()),
+            username: user.username,
+            auth_time: Utc.from_utc_datetime(&token.created_at),
+            jti: format!("api-token-{}", token.id.0),
+            role: user.role,
+            scopes: Some(token.scopes),
+        }
+    }
+
+    /// Fail unless the user has the given role or a higher one
+    pub fn require_role(&self, role: Role) -> Result<(), AuthError> {
+        if self.role >= role {
+            Ok(())
+        } else {
+            Err(AuthError::InsufficientRole(role))
+        }
+    }
+
+    /// Fail if the request was made with an API token that does not have the given scope
+    pub fn require_scope(&self, scope: Scope) -> Result<(), AuthError> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(&scope) => Err(AuthError::MissingScope(scope)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Claims of the user together with the times of their session token
+///
+/// Use instead of [`UserClaims`] when the handler needs to know when the token expires
+///   or must not be available to API tokens
+pub struct UserToken {
+    pub claims: UserClaims,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl FromRequest for UserToken {
     type Error = crate::api::error::Error;
-    type Future = std::future::Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
     fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
-        std::future::ready(match req.app_data::<web::Data<Authority>>() {
-            Some(authority) => authority
-                .extract_from_cookie(req.cookie(authority.cookie_name))
-                .map(|token| token.claims().clone().custom) // TODO: we may want to provide a way to get standard claims like exp or iat
+        let req = req.clone();
+        Box::pin(async move {
+            let authority = req
+                .app_data::<web::Data<Authority>>()
+                .ok_or_else(|| anyhow!("Authority is not registered??"))?;
+            let db = req
+                .app_data::<DbData>()
+                .ok_or_else(|| anyhow!("Database is not registered??"))?;
+
+            let mut token = authority
+                .extract_from_cookie(req.cookie(&authority.cookie_name))
+                .and_then(|claims| {
+                    Ok(UserToken {
+                        issued_at: claims.issued_at.ok_or(AuthError::MissingClaim("iat"))?,
+                        expires_at: claims.expiration.ok_or(AuthError::MissingClaim("exp"))?,
+                        claims: claims.custom,
+                    })
+                })
                 .map_err(|e| {
                     warn!("Could not extract user claims from cookie: {:?}", e);
-                    e.into()
-                }),
-            None => Err(anyhow!("Authority is not registered??").into()),
+                    match e {
+                        AuthError::NoCookie
+                            if req.cookie(super::totp::PENDING_COOKIE_NAME).is_some() =>
+                        {
+                            AuthError::SecondFactorRequired
+                        }
+                        e => e,
+                    }
+                })?;
+
+            let login = db
+                .send(db::GetLogin {
+                    span: Span::current(),
+                    jti: token.claims.jti.clone(),
+                })
+                .await??;
+
+            match login {
+                Some((login, user)) if !login.revoked => {
+                    token.claims.role = user.role;
+                    Ok(token)
+                }
+                _ => {
+                    warn!("Token of a revoked login {:?} was used", token.claims.jti);
+                    Err(AuthError::RevokedToken.into())
+                }
+            }
         })
     }
 }
 
-impl From<db_models::User> for UserClaims {
-    fn from(u: db_models::User) -> Self {
-        Self {
-            user_id: u.id,
-            username: u.username,
-            name: u.name.expect("BUG: User must have a name to be logged in"),
-        }
+/// Extract the API token from the `Authorization: Bearer` header, if there is one
+fn bearer_token(req: &actix_web::HttpRequest) -> Option<Result<String, AuthError>> {
+    let value = req.headers().get(header::AUTHORIZATION)?;
+    Some(
+        value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|v| v.trim().to_string())
+            .ok_or(AuthError::InvalidApiToken),
+    )
+}
+
+/// Claims of the user, either from the session cookie or from an API token
+///
+/// Handlers that accept API tokens have to check the scope with [`UserClaims::require_scope`]
+impl FromRequest for UserClaims {
+    type Error = crate::api::error::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let api_token = match bearer_token(req) {
+            Some(api_token) => api_token,
+            None => {
+                let token = UserToken::from_request(req, payload);
+                return Box::pin(async move { Ok(token.await?.claims) });
+            }
+        };
+
+        let req = req.clone();
+        Box::pin(async move {
+            let db = req
+                .app_data::<DbData>()
+                .ok_or_else(|| anyhow!("Database is not registered??"))?;
+
+            let token = db
+                .send(db::UseApiToken {
+                    span: Span::current(),
+                    token_hash: super::tokens::hash_token(&api_token?),
+                })
+                .await??;
+
+            match token {
+                Some((token, user)) => Ok(UserClaims::from_api_token(user, token)),
+                None => {
+                    warn!("Unknown, expired or revoked API token was used");
+                    Err(AuthError::InvalidApiToken.into())
+                }
+            }
+        })
     }
 }
 
-pub struct AuthKeys(Keypair);
+/// Generate a random id for a new login
+pub fn new_token_id() -> String {
+    use rand::{rngs::OsRng, RngCore};
+
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+}
+
+/// Keys used to sign and validate session tokens
+///
+/// New tokens are always signed with the signing key, but tokens signed with any of the known keys
+///   are accepted. This allows to rotate keys without invalidating all the existing sessions.
+pub struct AuthKeys {
+    signing_key_id: String,
+    signing_key: Keypair,
+    verifying_keys: HashMap<String, PublicKey>,
+}
 impl Clone for AuthKeys {
     fn clone(&self) -> Self {
-        AuthKeys(Keypair::from_bytes(&self.0.to_bytes()).unwrap())
+        AuthKeys {
+            signing_key_id: self.signing_key_id.clone(),
+            signing_key: Keypair::from_bytes(&self.signing_key.to_bytes()).unwrap(),
+            verifying_keys: self.verifying_keys.clone(),
+        }
     }
 }
 
 impl AuthKeys {
-    pub const SIZE: usize = KEYPAIR_LENGTH;
-    pub fn new(bytes: [u8; Self::SIZE]) -> Result<Self> {
-        Ok(Self(Keypair::from_bytes(&bytes)?))
+    pub fn load(config: &Config) -> Result<Self> {
+        let mut signing_key_id = config.signing_key.clone();
+        let mut key_configs = config.keys.clone();
+
+        if let Some(path) = &config.keys_file {
+            let file: KeysFile = config::Config::builder()
+                .add_source(config::File::from(path.as_path()))
+                .build()
+                .and_then(|c| c.try_deserialize())
+                .with_context(|| format!("Loading keys file {:?}", path))?;
+            signing_key_id = file.signing_key.or(signing_key_id);
+            key_configs.extend(file.keys);
+        }
+
+        let signing_key_id =
+            signing_key_id.ok_or_else(|| anyhow!("No signing key id is configured"))?;
+
+        let mut signing_key = None;
+        let mut verifying_keys = HashMap::new();
+        for key in key_configs {
+            let public_key = match (&key.keypair, &key.public_key) {
+                (Some(keypair), None) => {
+                    let keypair = base64::decode(keypair)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|bytes| Ok(Keypair::from_bytes(&bytes)?))
+                        .with_context(|| format!("Parsing keypair {:?}", key.id))?;
+                    let public_key = keypair.public;
+                    if key.id == signing_key_id {
+                        signing_key = Some(keypair);
+                    }
+                    public_key
+                }
+                (None, Some(public_key)) => base64::decode(public_key)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|bytes| Ok(PublicKey::from_bytes(&bytes)?))
+                    .with_context(|| format!("Parsing public key {:?}", key.id))?,
+                _ => bail!(
+                    "Key {:?} must have exactly one of `keypair` or `public_key` set",
+                    key.id
+                ),
+            };
+            if verifying_keys.insert(key.id.clone(), public_key).is_some() {
+                bail!("Duplicate key id {:?}", key.id);
+            }
+        }
+
+        let signing_key = signing_key.ok_or_else(|| {
+            anyhow!(
+                "Signing key {:?} is not configured or does not have a private part",
+                signing_key_id
+            )
+        })?;
+
+        info!(
+            "Signing tokens with key {:?}, accepting keys {:?}",
+            signing_key_id,
+            verifying_keys.keys().collect::<Vec<_>>()
+        );
+
+        Ok(Self {
+            signing_key_id,
+            signing_key,
+            verifying_keys,
+        })
+    }
+
+    /// Generate a new random keypair, returning it and its public key (both base64-encoded)
+    pub fn generate() -> Result<(String, String)> {
+        use rand::{rngs::OsRng, RngCore};
+
+        let mut secret = [0u8; SECRET_KEY_LENGTH];
+        OsRng.fill_bytes(&mut secret);
+        let secret = SecretKey::from_bytes(&secret)?;
+        let public = PublicKey::from(&secret);
+        let keypair = Keypair { secret, public };
+
+        Ok((
+            base64::encode(keypair.to_bytes()),
+            base64::encode(keypair.public.as_bytes()),
+        ))
     }
+}
 
-    #[allow(unused)]
-    pub fn try_new(bytes: &[u8]) -> Result<Self> {
-        Ok(Self(Keypair::from_bytes(bytes)?))
+pub fn configure(authority: web::Data<Authority>) -> impl Fn(&mut ServiceConfig) + Clone {
+    move |cfg: &mut ServiceConfig| {
+        cfg.app_data(authority.clone());
     }
 }
 
-pub fn configure(keys: AuthKeys) -> Result<impl Fn(&mut ServiceConfig) + Clone> {
-    Ok(move |cfg: &mut ServiceConfig| {
-        cfg.app_data(web::Data::new(Authority::new("session", keys.clone().0)));
-    })
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) fn config(lifetime: std::time::Duration) -> Config {
+        let (keypair, _) = AuthKeys::generate().unwrap();
+        Config {
+            signing_key: Some("test".to_string()),
+            keys: vec![KeyConfig {
+                id: "test".to_string(),
+                keypair: Some(keypair),
+                public_key: None,
+            }],
+            keys_file: None,
+            issuer: default_issuer(),
+            audience: default_audience(),
+            leeway: default_leeway(),
+            cookie: CookieConfig {
+                lifetime,
+                ..CookieConfig::default()
+            },
+        }
+    }
+
+    pub(crate) fn authority(config: &Config) -> Authority {
+        Authority::new(config, AuthKeys::load(config).unwrap()).unwrap()
+    }
+
+    fn claims(auth_time: DateTime<Utc>) -> UserClaims {
+        UserClaims {
+            user_id: db_models::UserId(1),
+            username: "test".to_string(),
+            name: "Test".to_string(),
+            auth_time,
+            jti: new_token_id(),
+            role: Role::Teacher,
+            scopes: None,
+        }
+    }
+
+    #[test]
+    fn test_renew_cookie() {
+        let authority = authority(&config(std::time::Duration::from_secs(60 * 60)));
+
+        let auth_time = Utc::now();
+        let cookie = authority.create_signed_cookie(claims(auth_time)).unwrap();
+        assert!(authority.renew_cookie(&cookie, auth_time).is_none());
+        assert!(authority
+            .renew_cookie(&cookie, auth_time + Duration::minutes(29))
+            .is_none());
+
+        let renewed = authority
+            .renew_cookie(&cookie, auth_time + Duration::minutes(31))
+            .unwrap();
+        let claims = authority
+            .validate_token::<UserClaims>(renewed.value())
+            .unwrap();
+        assert_eq!(claims.custom.auth_time.timestamp(), auth_time.timestamp());
+    }
+
+    #[test]
+    fn test_max_session_age() {
+        let authority = authority(&config(default_lifetime()));
+
+        // the token is cut short so that it expires with the session
+        let cookie = authority
+            .create_signed_cookie(claims(Utc::now() - Duration::hours(23)))
+            .unwrap();
+        let claims_ = authority
+            .validate_token::<UserClaims>(cookie.value())
+            .unwrap();
+        assert!(claims_.expiration.unwrap() - claims_.issued_at.unwrap() <= Duration::hours(1));
+
+        assert!(authority
+            .create_signed_cookie(claims(Utc::now() - Duration::hours(25)))
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_claims() {
+        let config = config(default_lifetime());
+        let authority_ = authority(&config);
+
+        // expiration is checked with leeway
+        let (token, _) = authority_
+            .sign_token(claims(Utc::now()), Duration::seconds(-30))
+            .unwrap();
+        assert!(authority_.validate_token::<UserClaims>(&token).is_ok());
+        let (token, _) = authority_
+            .sign_token(claims(Utc::now()), Duration::seconds(-120))
+            .unwrap();
+        assert!(matches!(
+            authority_.validate_token::<UserClaims>(&token),
+            Err(AuthError::InvalidToken(_))
+        ));
+
+        // same keys, but a different deployment
+        let other = authority(&Config {
+            issuer: "other".to_string(),
+            ..config.clone()
+        });
+        let (token, _) = other
+            .sign_token(claims(Utc::now()), Duration::minutes(1))
+            .unwrap();
+        assert!(matches!(
+            authority_.validate_token::<UserClaims>(&token),
+            Err(AuthError::WrongIssuer(iss)) if iss == "other"
+        ));
+
+        let other = authority(&Config {
+            audience: "other".to_string(),
+            ..config.clone()
+        });
+        let (token, _) = other
+            .sign_token(claims(Utc::now()), Duration::minutes(1))
+            .unwrap();
+        assert!(matches!(
+            authority_.validate_token::<UserClaims>(&token),
+            Err(AuthError::WrongAudience(aud)) if aud == "other"
+        ));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let (old_keypair, old_public_key) = AuthKeys::generate().unwrap();
+        let (new_keypair, _) = AuthKeys::generate().unwrap();
+        let key = |id: &str, keypair: Option<String>, public_key: Option<String>| KeyConfig {
+            id: id.to_string(),
+            keypair,
+            public_key,
+        };
+
+        let old = authority(&Config {
+            signing_key: Some("old".to_string()),
+            keys: vec![key("old", Some(old_keypair), None)],
+            ..config(default_lifetime())
+        });
+        let cookie = old.create_signed_cookie(claims(Utc::now())).unwrap();
+
+        // the old key is kept for validation only
+        let rotated_config = Config {
+            signing_key: Some("new".to_string()),
+            keys: vec![
+                key("new", Some(new_keypair), None),
+                key("old", None, Some(old_public_key)),
+            ],
+            ..config(default_lifetime())
+        };
+        let rotated = authority(&rotated_config);
+        assert!(rotated.validate_token::<UserClaims>(cookie.value()).is_ok());
+
+        let new_cookie = rotated.create_signed_cookie(claims(Utc::now())).unwrap();
+        let header = UntrustedToken::new(new_cookie.value())
+            .unwrap()
+            .header()
+            .clone();
+        assert_eq!(header.key_id.as_deref(), Some("new"));
+
+        // once the old key is removed, its tokens are rejected
+        let removed = authority(&Config {
+            keys: rotated_config.keys[..1].to_vec(),
+            ..rotated_config.clone()
+        });
+        assert!(removed
+            .validate_token::<UserClaims>(new_cookie.value())
+            .is_ok());
+        assert!(matches!(
+            removed.validate_token::<UserClaims>(cookie.value()),
+            Err(AuthError::UnknownKey(Some(kid))) if kid == "old"
+        ));
+    }
+
+    #[test]
+    fn test_require_scope() {
+        // session cookies are not limited by scopes
+        let claims_ = claims(Utc::now());
+        assert!(claims_.require_scope(Scope::MarksWrite).is_ok());
+
+        let claims_ = UserClaims {
+            scopes: Some(vec![Scope::SessionsRead]),
+            ..claims_
+        };
+        assert!(claims_.require_scope(Scope::SessionsRead).is_ok());
+        assert!(matches!(
+            claims_.require_scope(Scope::MarksWrite),
+            Err(AuthError::MissingScope(Scope::MarksWrite))
+        ));
+    }
 }
@@ -1,28 +1,52 @@
-use crate::api::models::{Challenge, ChallengeResult};
+use crate::api::auth::UserToken;
+use crate::api::client_ip::{self, ClientIp};
+use crate::api::geofence::{self, Verdict};
+use crate::api::models::{
+    Challenge, ChallengeFailure, ChallengeResult, Geofence, SessionState, User,
+};
 use crate::api::ApiResult;
-use crate::api::UserClaims;
 use crate::db;
-use crate::db::models::SessionId;
+use crate::db::models::{
+    self as db_models, ChallengeAlgorithm, GeofencePosition, NetworkPolicy, Role, SessionId,
+    StudentVisibility,
+};
 use crate::db::DbData;
 use actix_web::web::{Buf, ServiceConfig};
 use actix_web::{post, web};
 use anyhow::bail;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::TimeZone;
 use chrono::{DateTime, Utc};
 use hmac::Mac;
 use integer_encoding::VarIntReader;
+use ipnet::IpNet;
 use serde::Deserialize;
 use sha1::Sha1;
+use sha2::Sha256;
 use std::io::Read;
+use std::net::IpAddr;
 use std::time::Duration;
 use tracing::warn;
 
+/// Tag size of the legacy format
 const HMAC_SIZE: usize = 4;
+/// Versioned challenges end with a format byte with this bit set. Legacy ones end with a varint,
+///   whose last byte never has it, so the two can't be confused
+const FORMAT_MARKER: u8 = 0x80;
+/// Format byte of HMAC-SHA256 challenges, which have the tag after the varints:
+///   `[session_id: varint][challenge_index: varint][tag][FORMAT_HMAC_SHA256]`
+const FORMAT_HMAC_SHA256: u8 = FORMAT_MARKER | 1;
+pub const MIN_TAG_SIZE: usize = 4;
+pub const MAX_TAG_SIZE: usize = 32;
+/// Bounds of the timing overrides of the sessions
+const MIN_QR_INTERVAL_MS: u32 = 200;
+const MAX_QR_INTERVAL_MS: u32 = 5 * 60 * 1000;
+const MAX_JITTER_WINDOW_MS: u32 = 5 * 60 * 1000;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 struct ParsedChallenge {
-    hmac: [u8; HMAC_SIZE],
+    algorithm: ChallengeAlgorithm,
+    hmac: Vec<u8>,
     session_id: SessionId,
     challenge_index: u32,
 }
@@ -31,6 +55,9 @@ struct ParsedChallenge {
 struct ChallengeParams {
     seed: Vec<u8>,
     start_time: DateTime<Utc>,
+    algorithm: ChallengeAlgorithm,
+    tag_length: usize,
+    state: SessionState,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -39,9 +66,189 @@ pub struct Config {
     pub qr_interval: Duration,
     #[serde(with = "humantime_serde")]
     pub jitter_window: Duration,
+    /// Algorithm of the new sessions, unless chosen when creating them. The displays must support it
+    #[serde(default = "default_algorithm")]
+    pub default_algorithm: ChallengeAlgorithm,
+    /// Tag length of the new HMAC-SHA256 sessions, in bytes
+    #[serde(default = "default_tag_length")]
+    pub default_tag_length: usize,
+    /// A code redeemed by more students than this from one IP is flagged as shared.
+    ///   Should be higher for classrooms behind a single NAT address
+    #[serde(default = "default_shared_code_threshold")]
+    pub shared_code_threshold: usize,
+    /// Locations less precise than this are treated as missing by the geofences
+    #[serde(default = "default_max_location_accuracy_m")]
+    pub max_location_accuracy_m: f64,
+    /// Networks the challenges have to come from, like the NAT ranges of the campus Wi-Fi. Anywhere if empty
+    #[serde(default)]
+    pub allowed_networks: Vec<IpNet>,
+    /// What happens to the challenges from other networks
+    #[serde(default = "default_network_policy")]
+    pub network_policy: NetworkPolicy,
+    /// The teacher is warned when the clock of the display is off by this part of the jitter window
+    #[serde(default = "default_clock_skew_warning_ratio")]
+    pub clock_skew_warning_ratio: f64,
+    /// Who is told why a challenge failed, the others only get `Failed`. The reason always goes to the log
+    #[serde(default = "default_failure_details")]
+    pub failure_details: FailureDetails,
+    /// Networks of the clients trusted with the failure details, like the ones of the support staff
+    #[serde(default)]
+    pub trusted_networks: Vec<IpNet>,
+    /// What the students are told about the others after checking in, unless the session says otherwise
+    #[serde(default = "default_student_visibility")]
+    pub student_visibility: StudentVisibility,
+    /// How many of the latest other students are named
+    #[serde(default = "default_recent_students")]
+    pub recent_students: usize,
 }
 
-fn parse_challenge(data: &[u8]) -> Result<ParsedChallenge> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureDetails {
+    Everyone,
+    /// The staff (teaching assistants and up) and the clients from the trusted networks
+    Trusted,
+}
+
+impl Config {
+    /// The config with the overrides of the session applied
+    pub fn for_session(&self, session: &db_models::Session) -> Config {
+        let from_millis = |ms: i32| Duration::from_millis(ms as u64);
+        Config {
+            qr_interval: session
+                .qr_interval_ms
+                .map(from_millis)
+                .unwrap_or(self.qr_interval),
+            jitter_window: session
+                .jitter_window_ms
+                .map(from_millis)
+                .unwrap_or(self.jitter_window),
+            // checked when the session was created
+            allowed_networks: match &session.allowed_networks {
+                Some(networks) => networks.iter().filter_map(|n| n.parse().ok()).collect(),
+                None => self.allowed_networks.clone(),
+            },
+            network_policy: session.network_policy.unwrap_or(self.network_policy),
+            student_visibility: session
+                .student_visibility
+                .unwrap_or(self.student_visibility),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the failure details are shown to the user from this address
+    fn shows_failure_details(&self, role: Role, ip: Option<IpAddr>) -> bool {
+        match self.failure_details {
+            FailureDetails::Everyone => true,
+            FailureDetails::Trusted => {
+                role >= Role::TeachingAssistant
+                    || matches!(ip, Some(ip) if client_ip::in_networks(&self.trusted_networks, &ip))
+            }
+        }
+    }
+
+    /// Whether a challenge from this address is allowed by the network restrictions
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        if self.allowed_networks.is_empty() {
+            return true;
+        }
+        matches!(ip, Some(ip) if client_ip::in_networks(&self.allowed_networks, &ip))
+    }
+}
+
+/// Check the timing overrides requested for a new session
+pub fn check_timing_overrides(
+    qr_interval_ms: Option<u32>,
+    jitter_window_ms: Option<u32>,
+) -> Result<(), String> {
+    if let Some(qr_interval_ms) = qr_interval_ms {
+        if !(MIN_QR_INTERVAL_MS..=MAX_QR_INTERVAL_MS).contains(&qr_interval_ms) {
+            return Err(format!(
+                "The QR code interval must be between {} and {} ms",
+                MIN_QR_INTERVAL_MS, MAX_QR_INTERVAL_MS
+            ));
+        }
+    }
+    if let Some(jitter_window_ms) = jitter_window_ms {
+        if jitter_window_ms > MAX_JITTER_WINDOW_MS {
+            return Err(format!(
+                "The jitter window must be at most {} ms",
+                MAX_JITTER_WINDOW_MS
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn default_algorithm() -> ChallengeAlgorithm {
+    ChallengeAlgorithm::HmacSha1
+}
+fn default_tag_length() -> usize {
+    8
+}
+fn default_shared_code_threshold() -> usize {
+    5
+}
+fn default_max_location_accuracy_m() -> f64 {
+    250.0
+}
+fn default_network_policy() -> NetworkPolicy {
+    NetworkPolicy::Reject
+}
+fn default_clock_skew_warning_ratio() -> f64 {
+    0.5
+}
+fn default_failure_details() -> FailureDetails {
+    FailureDetails::Everyone
+}
+fn default_student_visibility() -> StudentVisibility {
+    StudentVisibility::Names
+}
+fn default_recent_students() -> usize {
+    10
+}
+
+/// What the student is told about the others marked in the session: the latest ones and the number of all of them
+fn visible_students(
+    visibility: StudentVisibility,
+    recent: Vec<db_models::User>,
+    total: i64,
+) -> (Vec<User>, Option<u64>) {
+    let recent = match visibility {
+        StudentVisibility::Names => recent.into_iter().map(|s| s.into()).collect(),
+        StudentVisibility::Hidden | StudentVisibility::Count => vec![],
+    };
+    let total = (visibility >= StudentVisibility::Count).then_some(total as u64);
+    (recent, total)
+}
+
+/// Pick the tag length of a new session, checking the requested one
+pub fn tag_length(
+    config: &Config,
+    algorithm: ChallengeAlgorithm,
+    requested: Option<usize>,
+) -> Result<usize, String> {
+    match (algorithm, requested) {
+        (ChallengeAlgorithm::HmacSha1, None | Some(HMAC_SIZE)) => Ok(HMAC_SIZE),
+        (ChallengeAlgorithm::HmacSha1, Some(_)) => Err(format!(
+            "The tag length of {} is always {}",
+            algorithm, HMAC_SIZE
+        )),
+        (ChallengeAlgorithm::HmacSha256, requested) => {
+            let length = requested.unwrap_or(config.default_tag_length);
+            if !(MIN_TAG_SIZE..=MAX_TAG_SIZE).contains(&length) {
+                return Err(format!(
+                    "The tag length must be between {} and {}",
+                    MIN_TAG_SIZE, MAX_TAG_SIZE
+                ));
+            }
+            Ok(length)
+        }
+    }
+}
+
+/// Parse the legacy challenge: `[hmac: 4 bytes][session_id: varint][challenge_index: varint]`
+fn parse_legacy_challenge(data: &[u8]) -> Result<ParsedChallenge> {
     let mut cursor = std::io::Cursor::new(data);
     let mut hmac = [0u8; HMAC_SIZE];
     cursor.read_exact(&mut hmac)?;
@@ -53,6 +260,33 @@ fn parse_challenge(data: &[u8]) -> Result<ParsedChallenge> {
     }
 
     Ok(ParsedChallenge {
+        algorithm: ChallengeAlgorithm::HmacSha1,
+        hmac: hmac.to_vec(),
+        session_id: SessionId(session_id as i32),
+        challenge_index,
+    })
+}
+
+fn parse_challenge(data: &[u8]) -> Result<ParsedChallenge> {
+    let (format, data) = match data.split_last() {
+        Some((&format, data)) if format & FORMAT_MARKER != 0 => (format, data),
+        _ => return parse_legacy_challenge(data),
+    };
+    let algorithm = match format {
+        FORMAT_HMAC_SHA256 => ChallengeAlgorithm::HmacSha256,
+        _ => bail!("Unknown challenge format {:#04x}", format),
+    };
+
+    let mut cursor = std::io::Cursor::new(data);
+    let session_id = cursor.read_varint::<u32>()?;
+    let challenge_index = cursor.read_varint::<u32>()?;
+    let hmac = cursor.chunk().to_vec();
+    if !(MIN_TAG_SIZE..=MAX_TAG_SIZE).contains(&hmac.len()) {
+        bail!("Invalid tag length {}", hmac.len());
+    }
+
+    Ok(ParsedChallenge {
+        algorithm,
         hmac,
         session_id: SessionId(session_id as i32),
         challenge_index,
@@ -76,6 +310,27 @@ fn calculate_hmac(seed: &[u8], index: u32) -> Result<[u8; HMAC_SIZE]> {
     Ok(result[..HMAC_SIZE].try_into()?)
 }
 
+/// The tag of the challenge with the index, truncated to `length` bytes
+fn calculate_tag(
+    algorithm: ChallengeAlgorithm,
+    seed: &[u8],
+    index: u32,
+    length: usize,
+) -> Result<Vec<u8>> {
+    let tag = match algorithm {
+        ChallengeAlgorithm::HmacSha1 => calculate_hmac(seed, index)?.to_vec(),
+        ChallengeAlgorithm::HmacSha256 => {
+            let mut hmac = hmac::Hmac::<Sha256>::new_from_slice(seed)?;
+            hmac.update(&index.to_le_bytes());
+            hmac.finalize().into_bytes().to_vec()
+        }
+    };
+    if length > tag.len() {
+        bail!("Tag length {} is too long for {}", length, algorithm);
+    }
+    Ok(tag[..length].to_vec())
+}
+
 /// Time subtraction with specified bounds
 fn saturating_sub(
     a: DateTime<Utc>,
@@ -93,15 +348,42 @@ fn saturating_sub(
     diff
 }
 
+/// Check the challenge, returning how long after its code was replaced on the screen it was submitted
 fn validate_challenge(
     challenge: ParsedChallenge,
     submission_time: DateTime<Utc>,
     params: ChallengeParams,
     config: &Config,
-) -> Result<()> {
-    let valid_hmac = calculate_hmac(&params.seed, challenge.challenge_index)?;
-    if valid_hmac != challenge.hmac {
-        bail!("Invalid hmac");
+) -> Result<chrono::Duration> {
+    // otherwise the legacy format would still work for the sessions that moved away from it
+    if challenge.algorithm != params.algorithm {
+        return Err(ChallengeFailure::WrongFormat).with_context(|| {
+            format!(
+                "Challenge uses {} instead of {}",
+                challenge.algorithm, params.algorithm
+            )
+        });
+    }
+    let valid_hmac = calculate_tag(
+        params.algorithm,
+        &params.seed,
+        challenge.challenge_index,
+        params.tag_length,
+    )?;
+    if valid_hmac.len() != challenge.hmac.len()
+        || !openssl::memcmp::eq(&valid_hmac, &challenge.hmac)
+    {
+        return Err(ChallengeFailure::BadSignature).context("Invalid hmac");
+    }
+    // only after the signature, so that just the holders of a code learn the state of the session
+    match params.state {
+        SessionState::Running => {}
+        SessionState::Paused => {
+            return Err(ChallengeFailure::SessionPaused).context("Session is paused")
+        }
+        SessionState::Closed => {
+            return Err(ChallengeFailure::SessionClosed).context("Session is closed")
+        }
     }
 
     let expected_start_time = params.start_time
@@ -120,27 +402,44 @@ fn validate_challenge(
         Some(chrono::Duration::zero()),
         None,
     );
-    let difference = difference_1.max(difference_2);
+    let jitter_window = chrono::Duration::from_std(config.jitter_window)?;
 
-    if difference > chrono::Duration::from_std(config.jitter_window)? {
-        bail!(
+    let failure = if difference_1 > jitter_window {
+        ChallengeFailure::TooEarly {
+            early_ms: difference_1.num_milliseconds(),
+        }
+    } else if difference_2 > jitter_window {
+        ChallengeFailure::Expired {
+            late_ms: difference_2.num_milliseconds(),
+        }
+    } else {
+        return Ok(difference_2);
+    };
+    Err(failure).with_context(|| {
+        format!(
             "Challenge out of time window ({} ms > jitter window, {} ms)",
-            difference.num_milliseconds(),
+            difference_1.max(difference_2).num_milliseconds(),
             config.jitter_window.as_millis(),
-        );
-    }
-
-    Ok(())
+        )
+    })
 }
 
 #[post("/challenge")]
 async fn submit_challenge(
-    user: UserClaims,
+    ClientIp(ip): ClientIp,
+    UserToken { claims: user, .. }: UserToken,
     db: DbData,
     challenge: web::Json<Challenge>,
     config: web::Data<Config>,
 ) -> ApiResult<web::Json<ChallengeResult>> {
     let submission_time = Utc::now();
+    let location = challenge.location.clone();
+    let show_details = config.shows_failure_details(user.role, ip);
+    let failed = |reason: ChallengeFailure| -> ApiResult<web::Json<ChallengeResult>> {
+        Ok(web::Json(ChallengeResult::Failed {
+            reason: Some(reason).filter(|_| show_details),
+        }))
+    };
 
     let challenge = match parse_encoded_challenge(&challenge.challenge) {
         Ok(c) => c,
@@ -166,40 +465,90 @@ async fn submit_challenge(
                 "User {:?} tried to submit challenge for unknown session {:?}",
                 user.user_id, challenge.session_id
             );
-            return Ok(web::Json(ChallengeResult::Failed));
+            return failed(ChallengeFailure::UnknownSession);
         }
     };
-
-    match validate_challenge(
-        challenge,
+    let session_config = config.for_session(&session);
+    let late = match validate_challenge(
+        challenge.clone(),
         submission_time,
         ChallengeParams {
             start_time: Utc.from_utc_datetime(&session.start_time),
-            seed: base64::decode(session.seed)?,
+            seed: base64::decode(&session.seed)?,
+            algorithm: session.challenge_algorithm,
+            tag_length: session.challenge_tag_length as usize,
+            state: SessionState::of(&session),
         },
-        config.get_ref(),
+        &session_config,
     ) {
-        Ok(()) => {}
+        Ok(late) => late,
         Err(e) => {
             warn!(
                 "User {:?} submitted invalid challenge {:?}: {:?}",
                 user.user_id, challenge, e
             );
-            return Ok(web::Json(ChallengeResult::Failed));
+            return match e.downcast::<ChallengeFailure>() {
+                Ok(reason) => failed(reason),
+                Err(e) => Err(e.into()),
+            };
         }
+    };
+
+    if !session_config.allows_ip(ip) && session_config.network_policy == NetworkPolicy::Reject {
+        warn!(
+            "User {:?} submitted challenge for session {:?} from a network that is not allowed: {:?}",
+            user.user_id, session.id, ip
+        );
+        return failed(ChallengeFailure::NetworkNotAllowed);
     }
 
-    let (_, other_students) = db
+    let (geofence_position, distance_m) = match Geofence::of_session(&session) {
+        Some(fence) => {
+            let (position, distance) =
+                geofence::locate(&fence, location.as_ref(), config.max_location_accuracy_m);
+            if geofence::evaluate(fence.policy, position) == Verdict::Reject {
+                warn!(
+                    "User {:?} submitted challenge for session {:?} from {:?}, {:?} m away",
+                    user.user_id, session.id, position, distance
+                );
+                return failed(match distance {
+                    Some(distance) if position == GeofencePosition::Outside => {
+                        ChallengeFailure::OutsideGeofence {
+                            distance_m: distance.round() as u32,
+                        }
+                    }
+                    _ => ChallengeFailure::LocationRequired,
+                });
+            }
+            (Some(position), distance.map(|d| d.round() as i32))
+        }
+        None => (None, None),
+    };
+
+    let (_, recent, total) = db
         .send(db::AddAutoAttendanceMark {
             span: tracing::Span::current(),
             session_id: session.id,
             student_id: user.user_id,
             mark_time: submission_time.naive_utc(),
+            challenge_index: challenge.challenge_index,
+            ip: ip.map(|ip| ip.to_string()),
+            late_ms: late.num_milliseconds() as i32,
+            geofence_position,
+            distance_m,
+            // no need to load the names nobody will see
+            recent_students: match session_config.student_visibility {
+                StudentVisibility::Names => session_config.recent_students as i64,
+                StudentVisibility::Hidden | StudentVisibility::Count => 0,
+            },
         })
         .await??;
 
+    let (other_students, other_students_count) =
+        visible_students(session_config.student_visibility, recent, total);
     Ok(web::Json(ChallengeResult::Success {
-        other_students: other_students.into_iter().map(|s| s.into()).collect(),
+        other_students,
+        other_students_count,
     }))
 }
 
@@ -211,7 +560,7 @@ pub fn configure(config: Config) -> impl Fn(&mut ServiceConfig) + Clone {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use chrono::{DateTime, Utc};
 
     use crate::db::models::SessionId;
@@ -238,6 +587,297 @@ mod test {
         assert_eq!(parsed.challenge_index, 4);
     }
 
+    /// What the displays do
+    fn encode_challenge(challenge: &ParsedChallenge) -> String {
+        use integer_encoding::VarIntWriter;
+
+        let mut data = Vec::new();
+        if challenge.algorithm == ChallengeAlgorithm::HmacSha1 {
+            data.extend_from_slice(&challenge.hmac);
+        }
+        data.write_varint(challenge.session_id.0 as u32).unwrap();
+        data.write_varint(challenge.challenge_index).unwrap();
+        if challenge.algorithm == ChallengeAlgorithm::HmacSha256 {
+            data.extend_from_slice(&challenge.hmac);
+            data.push(FORMAT_HMAC_SHA256);
+        }
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn test_parse_versioned_challenge() {
+        let mut data = vec![12, 4];
+        data.extend_from_slice(&[0xaa; 8]);
+        data.push(FORMAT_HMAC_SHA256);
+        let parsed = parse_challenge(&data).unwrap();
+        assert_eq!(parsed.algorithm, ChallengeAlgorithm::HmacSha256);
+        assert_eq!(parsed.hmac, [0xaa; 8]);
+        assert_eq!(parsed.session_id.0, 12);
+        assert_eq!(parsed.challenge_index, 4);
+
+        // the tag is too short or too long
+        assert!(parse_challenge(&[12, 4, 0xaa, 0xaa, 0xaa, FORMAT_HMAC_SHA256]).is_err());
+        let mut data = vec![12, 4];
+        data.extend_from_slice(&[0xaa; MAX_TAG_SIZE + 1]);
+        data.push(FORMAT_HMAC_SHA256);
+        assert!(parse_challenge(&data).is_err());
+        // unknown format
+        assert!(parse_challenge(&[12, 4, 0xaa, 0xaa, 0xaa, 0xaa, FORMAT_MARKER | 0x7f]).is_err());
+    }
+
+    #[test]
+    fn test_legacy_challenge_compatibility() {
+        // the last byte of a legacy challenge never looks like a format byte, whatever the tag is
+        for data in ["PQRETQwE", "AAAAAAEC", "_____wEC", "_____4CAAf__Aw"] {
+            let parsed = parse_encoded_challenge(data).unwrap();
+            assert_eq!(parsed.algorithm, ChallengeAlgorithm::HmacSha1, "{}", data);
+        }
+
+        let parsed = parse_encoded_challenge("_____4CAAf__Aw").unwrap();
+        assert_eq!(parsed.hmac, [0xff; 4]);
+        assert_eq!(parsed.session_id.0, 0x4000);
+        assert_eq!(parsed.challenge_index, 0xffff);
+    }
+
+    #[test]
+    fn test_challenge_round_trip() {
+        let seed = base64::decode("YNxExINfvxmC0q6g").unwrap();
+        let start_time = chrono::DateTime::<chrono::Utc>::from_utc(
+            chrono::NaiveDateTime::from_timestamp(1645671600, 0),
+            chrono::Utc,
+        );
+        let config = test_config();
+        let params = |algorithm, tag_length| ChallengeParams {
+            seed: seed.clone(),
+            start_time,
+            algorithm,
+            tag_length,
+            state: SessionState::Running,
+        };
+        let submission_time = start_time.add(chrono::Duration::milliseconds(4500));
+
+        for (algorithm, tag_length) in [
+            (ChallengeAlgorithm::HmacSha1, HMAC_SIZE),
+            (ChallengeAlgorithm::HmacSha256, MIN_TAG_SIZE),
+            (ChallengeAlgorithm::HmacSha256, 8),
+            (ChallengeAlgorithm::HmacSha256, MAX_TAG_SIZE),
+        ] {
+            let challenge = ParsedChallenge {
+                algorithm,
+                hmac: calculate_tag(algorithm, &seed, 4, tag_length).unwrap(),
+                session_id: SessionId(12),
+                challenge_index: 4,
+            };
+            let parsed = parse_encoded_challenge(&encode_challenge(&challenge)).unwrap();
+            assert_eq!(parsed.algorithm, algorithm);
+            assert_eq!(parsed.hmac, challenge.hmac);
+            assert_eq!(parsed.session_id, challenge.session_id);
+            assert_eq!(parsed.challenge_index, challenge.challenge_index);
+
+            validate_challenge(
+                parsed.clone(),
+                submission_time,
+                params(algorithm, tag_length),
+                &config,
+            )
+            .unwrap_or_else(|e| panic!("{} challenge should be accepted: {:?}", algorithm, e));
+        }
+
+        // the legacy challenges are the same as before
+        let legacy = ParsedChallenge {
+            algorithm: ChallengeAlgorithm::HmacSha1,
+            hmac: calculate_tag(ChallengeAlgorithm::HmacSha1, &seed, 4, HMAC_SIZE).unwrap(),
+            session_id: SessionId(12),
+            challenge_index: 4,
+        };
+        assert_eq!(legacy.hmac, calculate_hmac(&seed, 4).unwrap());
+        // but not accepted by the sessions that moved on
+        validate_challenge(
+            legacy,
+            submission_time,
+            params(ChallengeAlgorithm::HmacSha256, HMAC_SIZE),
+            &config,
+        )
+        .expect_err("Legacy challenge should not be accepted by an HMAC-SHA256 session");
+
+        // a truncated tag is not accepted when the session expects a longer one
+        let truncated = ParsedChallenge {
+            algorithm: ChallengeAlgorithm::HmacSha256,
+            hmac: calculate_tag(ChallengeAlgorithm::HmacSha256, &seed, 4, 4).unwrap(),
+            session_id: SessionId(12),
+            challenge_index: 4,
+        };
+        validate_challenge(
+            truncated,
+            submission_time,
+            params(ChallengeAlgorithm::HmacSha256, 8),
+            &config,
+        )
+        .expect_err("Challenge with a truncated tag should not be accepted");
+    }
+
+    #[test]
+    fn test_tag_length() {
+        let config = test_config();
+
+        assert_eq!(
+            tag_length(&config, ChallengeAlgorithm::HmacSha1, None),
+            Ok(4)
+        );
+        assert!(tag_length(&config, ChallengeAlgorithm::HmacSha1, Some(8)).is_err());
+        assert_eq!(
+            tag_length(&config, ChallengeAlgorithm::HmacSha256, None),
+            Ok(8)
+        );
+        assert_eq!(
+            tag_length(&config, ChallengeAlgorithm::HmacSha256, Some(16)),
+            Ok(16)
+        );
+        assert!(tag_length(&config, ChallengeAlgorithm::HmacSha256, Some(2)).is_err());
+        assert!(tag_length(&config, ChallengeAlgorithm::HmacSha256, Some(64)).is_err());
+    }
+
+    /// A session without any overrides
+    pub(crate) fn session(start_time: DateTime<Utc>, seed: &[u8]) -> db_models::Session {
+        db_models::Session {
+            id: SessionId(12),
+            title: None,
+            owner_id: db_models::UserId(1),
+            active: true,
+            start_time: start_time.naive_utc(),
+            seed: base64::encode(seed),
+            challenge_algorithm: ChallengeAlgorithm::HmacSha1,
+            challenge_tag_length: HMAC_SIZE as i32,
+            qr_interval_ms: None,
+            jitter_window_ms: None,
+            latitude: None,
+            longitude: None,
+            radius_m: None,
+            geofence_policy: None,
+            allowed_networks: None,
+            network_policy: None,
+            end_time: None,
+            student_visibility: None,
+        }
+    }
+
+    #[test]
+    fn test_session_timing_overrides() {
+        let (seed, parsed_challenge, start_time, config) = init_validate();
+        let mut session = session(start_time, &seed);
+        let params = || ChallengeParams {
+            seed: seed.clone(),
+            start_time,
+            algorithm: ChallengeAlgorithm::HmacSha1,
+            tag_length: HMAC_SIZE,
+            state: SessionState::Running,
+        };
+        // the fifth code of a session with 2 s codes
+        let submission_time = start_time.add(chrono::Duration::milliseconds(8500));
+
+        let session_config = config.for_session(&session);
+        assert_eq!(session_config.qr_interval, config.qr_interval);
+        assert_eq!(session_config.jitter_window, config.jitter_window);
+        validate_challenge(
+            parsed_challenge.clone(),
+            submission_time,
+            params(),
+            &session_config,
+        )
+        .expect_err("Challenge should be too late with the default interval");
+
+        session.qr_interval_ms = Some(2000);
+        let session_config = config.for_session(&session);
+        assert_eq!(session_config.qr_interval, Duration::from_secs(2));
+        validate_challenge(
+            parsed_challenge.clone(),
+            submission_time,
+            params(),
+            &session_config,
+        )
+        .expect("Challenge should be in time with the session interval");
+
+        // a wider jitter window lets the late challenge in with the default interval too
+        session.qr_interval_ms = None;
+        session.jitter_window_ms = Some(4000);
+        validate_challenge(
+            parsed_challenge,
+            submission_time,
+            params(),
+            &config.for_session(&session),
+        )
+        .expect("Challenge should be in the session jitter window");
+
+        assert!(check_timing_overrides(None, None).is_ok());
+        assert!(check_timing_overrides(Some(2000), Some(0)).is_ok());
+        assert!(check_timing_overrides(Some(0), None).is_err());
+        assert!(check_timing_overrides(None, Some(MAX_JITTER_WINDOW_MS + 1)).is_err());
+    }
+
+    #[test]
+    fn test_allowed_networks() {
+        let (seed, _, start_time, mut config) = init_validate();
+        let campus = "188.130.155.2".parse().ok();
+        let home = "85.26.164.3".parse().ok();
+
+        // anywhere by default
+        assert!(config.allows_ip(campus));
+        assert!(config.allows_ip(home));
+        assert!(config.allows_ip(None));
+
+        config.allowed_networks = vec!["188.130.155.0/24".parse().unwrap()];
+        let mut session = session(start_time, &seed);
+        let session_config = config.for_session(&session);
+        assert!(session_config.allows_ip(campus));
+        assert!(!session_config.allows_ip(home));
+        assert!(!session_config.allows_ip(None));
+        assert_eq!(session_config.network_policy, NetworkPolicy::Reject);
+
+        session.allowed_networks = Some(vec!["85.26.0.0/16".to_string()]);
+        session.network_policy = Some(NetworkPolicy::Flag);
+        let session_config = config.for_session(&session);
+        assert!(!session_config.allows_ip(campus));
+        assert!(session_config.allows_ip(home));
+        assert_eq!(session_config.network_policy, NetworkPolicy::Flag);
+
+        // an empty list lifts the restriction
+        session.allowed_networks = Some(vec![]);
+        assert!(config.for_session(&session).allows_ip(home));
+    }
+
+    #[test]
+    fn test_student_visibility() {
+        let (seed, _, start_time, config) = init_validate();
+        let mut session = session(start_time, &seed);
+        assert_eq!(
+            config.for_session(&session).student_visibility,
+            StudentVisibility::Names
+        );
+        session.student_visibility = Some(StudentVisibility::Hidden);
+        assert_eq!(
+            config.for_session(&session).student_visibility,
+            StudentVisibility::Hidden
+        );
+
+        let recent = || {
+            vec![db_models::User {
+                id: db_models::UserId(2),
+                username: "vy.sergeev@innopolis.university".to_string(),
+                name: Some("Vyacheslav Sergeev".to_string()),
+                role: Role::Student,
+            }]
+        };
+        let (names, total) = visible_students(StudentVisibility::Names, recent(), 23);
+        assert_eq!(names.len(), 1);
+        assert_eq!(total, Some(23));
+        let (names, total) = visible_students(StudentVisibility::Count, recent(), 23);
+        assert!(names.is_empty());
+        assert_eq!(total, Some(23));
+        let (names, total) = visible_students(StudentVisibility::Hidden, recent(), 23);
+        assert!(names.is_empty());
+        assert_eq!(total, None);
+    }
+
     #[test]
     fn test_calculate_hmac() {
         let seed = base64::decode("YNxExINfvxmC0q6g").unwrap();
@@ -247,6 +887,32 @@ mod test {
         assert_eq!(calculate_hmac(&seed, challenge_index).unwrap(), hmac);
     }
 
+    #[test]
+    fn test_parse_encoded_versioned_challenge() {
+        // same as in the frontend tests
+        let data = "DATpSYJFLaJYXoE";
+        let parsed = parse_encoded_challenge(data).unwrap();
+        assert_eq!(parsed.algorithm, ChallengeAlgorithm::HmacSha256);
+        assert_eq!(parsed.hmac, [233, 73, 130, 69, 45, 162, 88, 94]);
+        assert_eq!(parsed.session_id.0, 12);
+        assert_eq!(parsed.challenge_index, 4);
+    }
+
+    #[test]
+    fn test_calculate_tag() {
+        let seed = base64::decode("YNxExINfvxmC0q6g").unwrap();
+
+        assert_eq!(
+            calculate_tag(ChallengeAlgorithm::HmacSha1, &seed, 4, HMAC_SIZE).unwrap(),
+            [48, 137, 117, 180]
+        );
+        assert_eq!(
+            calculate_tag(ChallengeAlgorithm::HmacSha256, &seed, 4, 8).unwrap(),
+            [233, 73, 130, 69, 45, 162, 88, 94]
+        );
+        assert!(calculate_tag(ChallengeAlgorithm::HmacSha1, &seed, 4, 8).is_err());
+    }
+
     #[test]
     fn test_saturating_sub() {
         /// `m`-`s` (bounds: `min`, `max`) = `d`
@@ -348,10 +1014,30 @@ mod test {
         }
     }
 
+    /// The config the tests start from, each one changes only what it tests
+    pub(crate) fn test_config() -> Config {
+        Config {
+            qr_interval: Duration::from_secs(1),
+            jitter_window: Duration::from_millis(300),
+            default_algorithm: default_algorithm(),
+            default_tag_length: default_tag_length(),
+            shared_code_threshold: default_shared_code_threshold(),
+            max_location_accuracy_m: default_max_location_accuracy_m(),
+            allowed_networks: vec![],
+            network_policy: default_network_policy(),
+            clock_skew_warning_ratio: default_clock_skew_warning_ratio(),
+            failure_details: default_failure_details(),
+            trusted_networks: vec![],
+            student_visibility: default_student_visibility(),
+            recent_students: default_recent_students(),
+        }
+    }
+
     fn init_validate() -> (Vec<u8>, ParsedChallenge, DateTime<Utc>, Config) {
         let seed = base64::decode("YNxExINfvxmC0q6g").unwrap();
         let parsed_challenge = ParsedChallenge {
-            hmac: [48, 137, 117, 180],
+            algorithm: ChallengeAlgorithm::HmacSha1,
+            hmac: vec![48, 137, 117, 180],
             session_id: SessionId(12),
             challenge_index: 4,
         };
@@ -359,10 +1045,7 @@ mod test {
             chrono::NaiveDateTime::from_timestamp(1645671600, 0),
             chrono::Utc,
         );
-        let config = super::Config {
-            qr_interval: Duration::from_secs(1),
-            jitter_window: Duration::from_millis(300),
-        };
+        let config = test_config();
         (seed, parsed_challenge, start_time, config)
     }
 
@@ -384,11 +1067,14 @@ mod test {
         ];
         for submission_time in cases {
             validate_challenge(
-                parsed_challenge,
+                parsed_challenge.clone(),
                 submission_time,
                 super::ChallengeParams {
                     start_time,
                     seed: seed.clone(),
+                    algorithm: ChallengeAlgorithm::HmacSha1,
+                    tag_length: HMAC_SIZE,
+                    state: SessionState::Running,
                 },
                 &config,
             )
@@ -399,6 +1085,142 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_validate_challenge_late() {
+        let (seed, parsed_challenge, start_time, config) = init_validate();
+        let params = || ChallengeParams {
+            seed: seed.clone(),
+            start_time,
+            algorithm: ChallengeAlgorithm::HmacSha1,
+            tag_length: HMAC_SIZE,
+            state: SessionState::Running,
+        };
+
+        // before and while the code is on the screen
+        for submission_time in [
+            start_time.add(chrono::Duration::milliseconds(3900)),
+            start_time.add(chrono::Duration::milliseconds(4500)),
+            start_time.add(chrono::Duration::seconds(5)),
+        ] {
+            let late =
+                validate_challenge(parsed_challenge.clone(), submission_time, params(), &config)
+                    .unwrap();
+            assert_eq!(late, chrono::Duration::zero());
+        }
+
+        // after it was replaced
+        let late = validate_challenge(
+            parsed_challenge,
+            start_time.add(chrono::Duration::milliseconds(5200)),
+            params(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(late, chrono::Duration::milliseconds(200));
+    }
+
+    #[test]
+    fn test_validate_challenge_reasons() {
+        let (seed, parsed_challenge, start_time, config) = init_validate();
+        let params = |algorithm| ChallengeParams {
+            seed: seed.clone(),
+            start_time,
+            algorithm,
+            tag_length: HMAC_SIZE,
+            state: SessionState::Running,
+        };
+        let reason = |challenge: ParsedChallenge, submission_ms, algorithm| {
+            let e = validate_challenge(
+                challenge,
+                start_time.add(chrono::Duration::milliseconds(submission_ms)),
+                params(algorithm),
+                &config,
+            )
+            .expect_err("Challenge should fail");
+            e.downcast::<ChallengeFailure>()
+                .unwrap_or_else(|e| panic!("No reason in {:?}", e))
+        };
+
+        // the code is shown from 4 s to 5 s
+        assert_eq!(
+            reason(parsed_challenge.clone(), 3500, ChallengeAlgorithm::HmacSha1),
+            ChallengeFailure::TooEarly { early_ms: 500 }
+        );
+        assert_eq!(
+            reason(parsed_challenge.clone(), 5600, ChallengeAlgorithm::HmacSha1),
+            ChallengeFailure::Expired { late_ms: 600 }
+        );
+        assert_eq!(
+            reason(
+                parsed_challenge.clone(),
+                4500,
+                ChallengeAlgorithm::HmacSha256
+            ),
+            ChallengeFailure::WrongFormat
+        );
+        let mut forged = parsed_challenge;
+        forged.hmac[0] ^= 1;
+        assert_eq!(
+            reason(forged, 4500, ChallengeAlgorithm::HmacSha1),
+            ChallengeFailure::BadSignature
+        );
+    }
+
+    #[test]
+    fn test_session_state() {
+        let (seed, parsed_challenge, start_time, config) = init_validate();
+        let submission_time = start_time.add(chrono::Duration::milliseconds(4500));
+        let params = |state| ChallengeParams {
+            seed: seed.clone(),
+            start_time,
+            algorithm: ChallengeAlgorithm::HmacSha1,
+            tag_length: HMAC_SIZE,
+            state,
+        };
+        let validate = |state| {
+            validate_challenge(
+                parsed_challenge.clone(),
+                submission_time,
+                params(state),
+                &config,
+            )
+            .map_err(|e| e.downcast::<ChallengeFailure>().unwrap())
+        };
+
+        assert!(validate(SessionState::Running).is_ok());
+        assert_eq!(
+            validate(SessionState::Paused).unwrap_err(),
+            ChallengeFailure::SessionPaused
+        );
+        assert_eq!(
+            validate(SessionState::Closed).unwrap_err(),
+            ChallengeFailure::SessionClosed
+        );
+
+        let mut session = session(start_time, &seed);
+        assert_eq!(SessionState::of(&session), SessionState::Running);
+        session.active = false;
+        assert_eq!(SessionState::of(&session), SessionState::Paused);
+        session.end_time = Some(submission_time.naive_utc());
+        assert_eq!(SessionState::of(&session), SessionState::Closed);
+    }
+
+    #[test]
+    fn test_failure_details() {
+        let (_, _, _, mut config) = init_validate();
+        let support = "10.91.4.17".parse().ok();
+        let elsewhere = "188.130.155.2".parse().ok();
+
+        assert!(config.shows_failure_details(Role::Student, elsewhere));
+
+        config.failure_details = FailureDetails::Trusted;
+        config.trusted_networks = vec!["10.91.4.0/24".parse().unwrap()];
+        assert!(!config.shows_failure_details(Role::Student, elsewhere));
+        assert!(!config.shows_failure_details(Role::Student, None));
+        assert!(config.shows_failure_details(Role::Student, support));
+        assert!(config.shows_failure_details(Role::TeachingAssistant, elsewhere));
+    }
+
     #[test]
     fn test_validate_challenge_invalid() {
         let (seed, parsed_challenge, start_time, config) = init_validate();
@@ -419,11 +1241,14 @@ mod test {
         ];
         for submission_time in cases {
             validate_challenge(
-                parsed_challenge,
+                parsed_challenge.clone(),
                 submission_time,
                 super::ChallengeParams {
                     start_time,
                     seed: seed.clone(),
+                    algorithm: ChallengeAlgorithm::HmacSha1,
+                    tag_length: HMAC_SIZE,
+                    state: SessionState::Running,
                 },
                 &config,
             )
@@ -437,11 +1262,14 @@ mod test {
 
         // Test if this passes to make sure errors later are caused by the changes made
         validate_challenge(
-            parsed_challenge,
+            parsed_challenge.clone(),
             submission_time,
             super::ChallengeParams {
                 start_time,
                 seed: seed.clone(),
+                algorithm: ChallengeAlgorithm::HmacSha1,
+                tag_length: HMAC_SIZE,
+                state: SessionState::Running,
             },
             &config,
         )
@@ -452,15 +1280,18 @@ mod test {
 
         // Wrong HMAC
         let incorrect_challenge = ParsedChallenge {
-            hmac: [48, 137, 117, 181],
-            ..parsed_challenge
+            hmac: vec![48, 137, 117, 181],
+            ..parsed_challenge.clone()
         };
         validate_challenge(
-            incorrect_challenge,
+            incorrect_challenge.clone(),
             submission_time,
             super::ChallengeParams {
                 start_time,
                 seed: seed.clone(),
+                algorithm: ChallengeAlgorithm::HmacSha1,
+                tag_length: HMAC_SIZE,
+                state: SessionState::Running,
             },
             &config,
         )
@@ -472,14 +1303,17 @@ mod test {
         // Wrong index (or HMAC lol)
         let incorrect_challenge = ParsedChallenge {
             challenge_index: 3,
-            ..parsed_challenge
+            ..parsed_challenge.clone()
         };
         validate_challenge(
-            incorrect_challenge,
+            incorrect_challenge.clone(),
             submission_time,
             super::ChallengeParams {
                 start_time,
                 seed: seed.clone(),
+                algorithm: ChallengeAlgorithm::HmacSha1,
+                tag_length: HMAC_SIZE,
+                state: SessionState::Running,
             },
             &config,
         )
@@ -0,0 +1,149 @@
+//! Finding out the address of the client behind the reverse proxies
+//!
+//! Anyone can send an `X-Forwarded-For` header, so it is only read as far as the hops are trusted proxies:
+//!   the first address from the right that is not a trusted proxy is the client.
+
+use actix_web::http::header::HeaderName;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use ipnet::IpNet;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Networks of the reverse proxies in front of the server, like the ingress controller
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(pub Vec<IpNet>);
+
+impl TrustedProxies {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        in_networks(&self.0, ip)
+    }
+}
+
+pub fn in_networks(networks: &[IpNet], ip: &IpAddr) -> bool {
+    networks.iter().any(|net| net.contains(ip))
+}
+
+/// Address of the client, `None` if the connection has no peer address (like in the tests)
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+/// Parse a hop of `X-Forwarded-For`, some proxies add the port
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|a| a.ip()))
+        .ok()
+}
+
+/// Walk the hops from the peer towards the client while they are trusted
+fn client_ip<'a>(
+    peer: IpAddr,
+    forwarded_for: impl DoubleEndedIterator<Item = &'a str>,
+    trusted: &TrustedProxies,
+) -> IpAddr {
+    let mut client = peer;
+    for hop in forwarded_for.rev() {
+        if !trusted.contains(&client) {
+            break;
+        }
+        match parse_hop(hop) {
+            Some(ip) => client = ip,
+            // garbage from the client, the last trusted proxy is all we know
+            None => break,
+        }
+    }
+    client
+}
+
+impl ClientIp {
+    /// Nothing is trusted if the proxies are not registered
+    pub fn of(req: &HttpRequest) -> Self {
+        let nothing = TrustedProxies::default();
+        let trusted = req
+            .app_data::<web::Data<TrustedProxies>>()
+            .map(|t| t.get_ref())
+            .unwrap_or(&nothing);
+
+        let forwarded_for = req
+            .headers()
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect::<Vec<_>>();
+
+        ClientIp(
+            req.peer_addr()
+                .map(|peer| client_ip(peer.ip(), forwarded_for.into_iter(), trusted)),
+        )
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        ready(Ok(ClientIp::of(req)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted = TrustedProxies(vec!["10.42.0.0/16".parse().unwrap()]);
+        let hops = |s: &'static str| s.split(',');
+
+        // straight from the client, the header is made up
+        assert_eq!(
+            client_ip(ip("188.130.155.2"), hops("1.1.1.1"), &trusted),
+            ip("188.130.155.2")
+        );
+        // through the ingress
+        assert_eq!(
+            client_ip(ip("10.42.0.7"), hops("188.130.155.2"), &trusted),
+            ip("188.130.155.2")
+        );
+        // the client tried to prepend a fake address
+        assert_eq!(
+            client_ip(ip("10.42.0.7"), hops("1.1.1.1, 188.130.155.2"), &trusted),
+            ip("188.130.155.2")
+        );
+        // through two trusted proxies, one of them adding the port
+        assert_eq!(
+            client_ip(
+                ip("10.42.0.7"),
+                hops("188.130.155.2:51234, 10.42.1.3"),
+                &trusted
+            ),
+            ip("188.130.155.2")
+        );
+        // garbage after the ingress
+        assert_eq!(
+            client_ip(ip("10.42.0.7"), hops("unknown"), &trusted),
+            ip("10.42.0.7")
+        );
+        // no header at all
+        assert_eq!(
+            client_ip(ip("10.42.0.7"), std::iter::empty(), &trusted),
+            ip("10.42.0.7")
+        );
+        // nothing is trusted by default
+        assert_eq!(
+            client_ip(
+                ip("10.42.0.7"),
+                hops("188.130.155.2"),
+                &TrustedProxies::default()
+            ),
+            ip("10.42.0.7")
+        );
+    }
+}
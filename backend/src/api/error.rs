@@ -1,4 +1,12 @@
 use crate::api::auth::AuthError;
+use crate::api::ldap::LdapError;
+use crate::api::local_accounts::LocalAccountError;
+use crate::api::magic_link::MagicLinkError;
+use crate::api::saml::SamlError;
+use crate::api::sessions::SessionError;
+use crate::api::sso::SsoError;
+use crate::api::totp::TotpError;
+use crate::api::webauthn::PasskeyError;
 use crate::db::DbError;
 use crate::diagnostics::RequestIds;
 use actix_web::body::{BoxBody, EitherBody, MessageBody};
@@ -37,6 +45,14 @@ pub enum Error {
     AnyhowApiError,
     AuthError,
     DbError,
+    LdapError,
+    SamlError,
+    SsoError,
+    PasskeyError,
+    TotpError,
+    LocalAccountError,
+    MagicLinkError,
+    SessionError,
 }
 pub type ApiResult<T> = Result<T, Error>;
 
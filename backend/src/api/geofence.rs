@@ -0,0 +1,203 @@
+//! Checking that the students are at the place of the session
+//!
+//! The teacher can attach a location and a radius to a session. The device then sends its coordinates along with
+//!   the challenge, and the challenges from outside are either rejected or accepted with a flag, depending on the
+//!   policy of the session. The coordinates come from the device and can be faked, so this only raises the bar.
+
+use crate::api::models::{Geofence, Location};
+use crate::db::models::{GeofencePolicy, GeofencePosition};
+
+/// Mean radius of the Earth
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+/// Bounds of the radius of a session geofence
+const MIN_RADIUS_M: u32 = 10;
+const MAX_RADIUS_M: u32 = 100_000;
+
+/// What to do with a challenge, given where it came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    AcceptWithFlag,
+    Reject,
+}
+
+/// Great-circle distance between two points, in meters
+pub fn distance_m(a: &Location, b: &Geofence) -> f64 {
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.longitude - a.longitude).to_radians();
+
+    // haversine, stable for the small distances we care about
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().min(1.0).asin()
+}
+
+fn valid_coordinates(latitude: f64, longitude: f64) -> bool {
+    (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude)
+}
+
+/// Check the geofence requested for a new session
+pub fn check_geofence(geofence: &Geofence) -> Result<(), String> {
+    if !valid_coordinates(geofence.latitude, geofence.longitude) {
+        return Err("The geofence coordinates are out of range".to_string());
+    }
+    if !(MIN_RADIUS_M..=MAX_RADIUS_M).contains(&geofence.radius_m) {
+        return Err(format!(
+            "The geofence radius must be between {} and {} m",
+            MIN_RADIUS_M, MAX_RADIUS_M
+        ));
+    }
+    Ok(())
+}
+
+/// Where the device is relative to the geofence, along with the distance to its center if it is known.
+///   The device is given the benefit of the doubt: it is inside if any point within its accuracy is
+pub fn locate(
+    geofence: &Geofence,
+    location: Option<&Location>,
+    max_accuracy_m: f64,
+) -> (GeofencePosition, Option<f64>) {
+    let location = match location {
+        Some(l)
+            if valid_coordinates(l.latitude, l.longitude)
+                && (0.0..=max_accuracy_m).contains(&l.accuracy_m) =>
+        {
+            l
+        }
+        _ => return (GeofencePosition::Unknown, None),
+    };
+
+    let distance = distance_m(location, geofence);
+    let position = if distance - location.accuracy_m <= geofence.radius_m as f64 {
+        GeofencePosition::Inside
+    } else {
+        GeofencePosition::Outside
+    };
+    (position, Some(distance))
+}
+
+/// Apply the policy of the session to the position of the device
+pub fn evaluate(policy: GeofencePolicy, position: GeofencePosition) -> Verdict {
+    match (position, policy) {
+        (GeofencePosition::Inside, _) => Verdict::Accept,
+        (_, GeofencePolicy::Flag) => Verdict::AcceptWithFlag,
+        (_, GeofencePolicy::Reject) => Verdict::Reject,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Innopolis University
+    fn fence(policy: GeofencePolicy) -> Geofence {
+        Geofence {
+            latitude: 55.7536,
+            longitude: 48.7434,
+            radius_m: 150,
+            policy,
+        }
+    }
+
+    fn location(latitude: f64, longitude: f64, accuracy_m: f64) -> Location {
+        Location {
+            latitude,
+            longitude,
+            accuracy_m,
+        }
+    }
+
+    #[test]
+    fn test_distance() {
+        let fence = fence(GeofencePolicy::Reject);
+        assert!(distance_m(&location(55.7536, 48.7434, 0.0), &fence) < 1e-6);
+
+        // one minute of arc along a meridian is about a nautical mile
+        let d = distance_m(&location(55.7536 + 1.0 / 60.0, 48.7434, 0.0), &fence);
+        assert!((d - 1853.2).abs() < 1.0, "{}", d);
+
+        // Kazan Kremlin, about 23 km away
+        let d = distance_m(&location(55.7986, 49.1060, 0.0), &fence);
+        assert!((d - 23_135.0).abs() < 100.0, "{}", d);
+
+        // the antimeridian does not break it
+        let d = distance_m(
+            &location(0.0, 179.999, 0.0),
+            &Geofence {
+                latitude: 0.0,
+                longitude: -179.999,
+                ..fence
+            },
+        );
+        assert!((d - 222.4).abs() < 1.0, "{}", d);
+    }
+
+    #[test]
+    fn test_locate() {
+        let fence = fence(GeofencePolicy::Reject);
+
+        let (position, distance) = locate(&fence, Some(&location(55.7540, 48.7440, 10.0)), 100.0);
+        assert_eq!(position, GeofencePosition::Inside);
+        assert!(distance.unwrap() < 150.0);
+
+        // about 220 m away
+        let outside = location(55.7556, 48.7434, 10.0);
+        let (position, distance) = locate(&fence, Some(&outside), 100.0);
+        assert_eq!(position, GeofencePosition::Outside);
+        assert!((distance.unwrap() - 222.4).abs() < 1.0);
+
+        // could be inside with this accuracy
+        let imprecise = location(55.7556, 48.7434, 80.0);
+        assert_eq!(
+            locate(&fence, Some(&imprecise), 100.0).0,
+            GeofencePosition::Inside
+        );
+        // too imprecise to tell anything
+        assert_eq!(
+            locate(&fence, Some(&imprecise), 50.0),
+            (GeofencePosition::Unknown, None)
+        );
+
+        assert_eq!(
+            locate(&fence, None, 100.0),
+            (GeofencePosition::Unknown, None)
+        );
+        assert_eq!(
+            locate(&fence, Some(&location(91.0, 48.7434, 10.0)), 100.0),
+            (GeofencePosition::Unknown, None)
+        );
+        assert_eq!(
+            locate(&fence, Some(&location(55.7536, 48.7434, f64::NAN)), 100.0),
+            (GeofencePosition::Unknown, None)
+        );
+    }
+
+    #[test]
+    fn test_evaluate() {
+        use GeofencePolicy::*;
+        use GeofencePosition::*;
+
+        assert_eq!(evaluate(Reject, Inside), Verdict::Accept);
+        assert_eq!(evaluate(Reject, Outside), Verdict::Reject);
+        assert_eq!(evaluate(Reject, Unknown), Verdict::Reject);
+        assert_eq!(evaluate(Flag, Inside), Verdict::Accept);
+        assert_eq!(evaluate(Flag, Outside), Verdict::AcceptWithFlag);
+        assert_eq!(evaluate(Flag, Unknown), Verdict::AcceptWithFlag);
+    }
+
+    #[test]
+    fn test_check_geofence() {
+        assert!(check_geofence(&fence(GeofencePolicy::Flag)).is_ok());
+        let fence = fence(GeofencePolicy::Flag);
+        assert!(check_geofence(&Geofence {
+            radius_m: 5,
+            ..fence
+        })
+        .is_err());
+        assert!(check_geofence(&Geofence {
+            longitude: 200.0,
+            ..fence
+        })
+        .is_err());
+    }
+}
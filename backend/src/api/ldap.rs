@@ -0,0 +1,318 @@
+//! Login with username and password checked against an LDAP directory
+
+use crate::api::auth::Authority;
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::models::PasswordLoginRequest;
+use crate::db::DbData;
+use actix_web::http::StatusCode;
+use actix_web::web::ServiceConfig;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// LDAP result code returned when the bind DN or the password is wrong
+const INVALID_CREDENTIALS: u32 = 49;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Server to connect to, e.g. `ldaps://ldap.example.com` or `ldap://localhost:1389`
+    pub url: String,
+    /// Upgrade a plain `ldap://` connection with StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    /// DN to bind as, `{username}` is replaced with the (escaped) username,
+    /// e.g. `uid={username},ou=people,dc=example,dc=com`
+    pub bind_dn: String,
+    /// Where to look up the user entry after a successful bind
+    pub search_base: String,
+    /// Filter selecting the user entry, `{username}` is replaced with the (escaped) username
+    #[serde(default = "default_search_filter")]
+    pub search_filter: String,
+    #[serde(default)]
+    pub attributes: AttributeMapping,
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AttributeMapping {
+    /// Attribute used as `users.username`
+    #[serde(default = "default_username_attribute")]
+    pub username: String,
+    /// Attributes tried in order for `users.name`, the username is used if none is present
+    #[serde(default = "default_name_attributes")]
+    pub name: Vec<String>,
+}
+
+impl Default for AttributeMapping {
+    fn default() -> Self {
+        Self {
+            username: default_username_attribute(),
+            name: default_name_attributes(),
+        }
+    }
+}
+
+fn default_search_filter() -> String {
+    "(uid={username})".to_string()
+}
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+fn default_username_attribute() -> String {
+    "uid".to_string()
+}
+fn default_name_attributes() -> Vec<String> {
+    vec!["displayName".to_string(), "cn".to_string()]
+}
+
+#[derive(Debug)]
+pub enum LdapError {
+    InvalidCredentials,
+    UserNotFound,
+}
+
+impl ApiError for LdapError {
+    fn to_http(&self) -> (StatusCode, String) {
+        match self {
+            LdapError::InvalidCredentials => (
+                StatusCode::UNAUTHORIZED,
+                "Invalid username or password".to_string(),
+            ),
+            LdapError::UserNotFound => (
+                StatusCode::UNAUTHORIZED,
+                "User entry was not found in the directory".to_string(),
+            ),
+        }
+    }
+}
+
+/// User as found in the directory
+#[derive(Debug, PartialEq, Eq)]
+struct DirectoryUser {
+    username: String,
+    name: String,
+}
+
+impl Config {
+    fn bind_dn(&self, username: &str) -> String {
+        self.bind_dn.replace("{username}", &dn_escape(username))
+    }
+
+    fn search_filter(&self, username: &str) -> String {
+        self.search_filter
+            .replace("{username}", &ldap_escape(username))
+    }
+}
+
+/// Attribute names are case-insensitive, so the server might return them in a different case
+fn attribute<'a>(attrs: &'a HashMap<String, Vec<String>>, name: &str) -> Option<&'a str> {
+    attrs
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(|v| v.as_str())
+}
+
+fn directory_user(
+    mapping: &AttributeMapping,
+    username: &str,
+    attrs: &HashMap<String, Vec<String>>,
+) -> DirectoryUser {
+    let username = attribute(attrs, &mapping.username)
+        .unwrap_or(username)
+        .to_string();
+    let name = mapping
+        .name
+        .iter()
+        .find_map(|a| attribute(attrs, a))
+        .unwrap_or(&username)
+        .to_string();
+
+    DirectoryUser { username, name }
+}
+
+/// Bind as the user to check the password, then look up their entry
+async fn authenticate(config: &Config, username: &str, password: &str) -> ApiResult<DirectoryUser> {
+    // an empty password would make it an unauthenticated bind, which always succeeds
+    if username.is_empty() || password.is_empty() {
+        return Err(LdapError::InvalidCredentials.into());
+    }
+
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(config.timeout)
+        .set_starttls(config.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &config.url)
+        .await
+        .context("Connecting to the LDAP server")?;
+    ldap3::drive!(conn);
+
+    let result = ldap
+        .with_timeout(config.timeout)
+        .simple_bind(&config.bind_dn(username), password)
+        .await
+        .context("Binding to the LDAP server")?;
+    if result.rc == INVALID_CREDENTIALS {
+        return Err(LdapError::InvalidCredentials.into());
+    }
+    result.success().context("Binding to the LDAP server")?;
+
+    let mut attributes = vec![config.attributes.username.as_str()];
+    attributes.extend(config.attributes.name.iter().map(|a| a.as_str()));
+
+    let (entries, _) = ldap
+        .with_timeout(config.timeout)
+        .search(
+            &config.search_base,
+            Scope::Subtree,
+            &config.search_filter(username),
+            attributes,
+        )
+        .await
+        .context("Searching the LDAP directory")?
+        .success()
+        .context("Searching the LDAP directory")?;
+
+    if let Err(e) = ldap.unbind().await {
+        warn!("Failed to unbind from the LDAP server: {:?}", e);
+    }
+
+    let entry = match <[_; 1]>::try_from(entries) {
+        Ok([entry]) => SearchEntry::construct(entry),
+        Err(entries) => {
+            warn!(
+                "Expected exactly one LDAP entry for {:?}, found {}",
+                username,
+                entries.len()
+            );
+            return Err(LdapError::UserNotFound.into());
+        }
+    };
+
+    Ok(directory_user(&config.attributes, username, &entry.attrs))
+}
+
+#[post("/ldap/login")]
+async fn login(
+    req: HttpRequest,
+    db: DbData,
+    authority: web::Data<Authority>,
+    config: web::Data<Config>,
+    body: web::Json<PasswordLoginRequest>,
+) -> ApiResult<HttpResponse> {
+    let body = body.into_inner();
+
+    let user = authenticate(&config, &body.username, &body.password).await?;
+
+    info!("User {:?} logged in via LDAP", user.username);
+
+    let outcome =
+        super::create_session_cookie(&req, &db, &authority, user.username, user.name).await?;
+
+    Ok(outcome.into_response())
+}
+
+pub fn configure(config: Option<Config>) -> impl Fn(&mut ServiceConfig) + Clone {
+    move |cfg: &mut ServiceConfig| {
+        if let Some(config) = &config {
+            cfg.app_data(web::Data::new(config.clone())).service(login);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(url: &str) -> Config {
+        Config {
+            url: url.to_string(),
+            starttls: false,
+            bind_dn: "cn={username},ou=users,dc=example,dc=org".to_string(),
+            search_base: "ou=users,dc=example,dc=org".to_string(),
+            search_filter: "(&(objectClass=inetOrgPerson)(uid={username}))".to_string(),
+            attributes: AttributeMapping::default(),
+            timeout: default_timeout(),
+        }
+    }
+
+    #[test]
+    fn test_escaping() {
+        let config = config("ldap://localhost");
+
+        assert_eq!(
+            config.bind_dn("evil,ou=admins"),
+            "cn=evil\\2cou\\3dadmins,ou=users,dc=example,dc=org"
+        );
+        assert_eq!(
+            config.search_filter("*)(uid=*"),
+            "(&(objectClass=inetOrgPerson)(uid=\\2a\\29\\28uid=\\2a))"
+        );
+    }
+
+    #[test]
+    fn test_directory_user() {
+        let mapping = AttributeMapping::default();
+
+        let attrs = HashMap::from([
+            ("UID".to_string(), vec!["jdoe".to_string()]),
+            ("cn".to_string(), vec!["John Doe".to_string()]),
+            ("displayName".to_string(), vec!["Johnny".to_string()]),
+        ]);
+        assert_eq!(
+            directory_user(&mapping, "JDoe", &attrs),
+            DirectoryUser {
+                username: "jdoe".to_string(),
+                name: "Johnny".to_string()
+            }
+        );
+
+        let attrs = HashMap::from([("cn".to_string(), vec!["John Doe".to_string()])]);
+        assert_eq!(
+            directory_user(&mapping, "jdoe", &attrs),
+            DirectoryUser {
+                username: "jdoe".to_string(),
+                name: "John Doe".to_string()
+            }
+        );
+
+        assert_eq!(
+            directory_user(&mapping, "jdoe", &HashMap::new()),
+            DirectoryUser {
+                username: "jdoe".to_string(),
+                name: "jdoe".to_string()
+            }
+        );
+    }
+
+    /// Runs against the `ldap` service from docker-compose.yml:
+    /// `docker compose up -d ldap && cargo test -- --ignored test_local_directory`
+    #[actix_web::test]
+    #[ignore]
+    async fn test_local_directory() {
+        let url =
+            std::env::var("LDAP_TEST_URL").unwrap_or_else(|_| "ldap://localhost:1389".to_string());
+        let config = config(&url);
+
+        let user = authenticate(&config, "user01", "password1").await.unwrap();
+        assert_eq!(user.username, "user01");
+        assert_eq!(user.name, "user01");
+
+        assert!(matches!(
+            authenticate(&config, "user01", "wrong").await,
+            Err(crate::api::error::Error::LdapError(
+                LdapError::InvalidCredentials
+            ))
+        ));
+        assert!(matches!(
+            authenticate(&config, "user01", "").await,
+            Err(crate::api::error::Error::LdapError(
+                LdapError::InvalidCredentials
+            ))
+        ));
+    }
+}
@@ -0,0 +1,524 @@
+//! Local accounts with passwords, for deployments without an identity provider
+//!
+//! Admins invite users (or reset their passwords) by giving out single-use tokens, with which the
+//!   users set their passwords. The passwords are hashed with Argon2id.
+
+use crate::api::auth::{Authority, UserToken};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::models;
+use crate::db;
+use crate::db::models::{self as db_models, PasswordTokenPurpose, Role};
+use crate::db::DbData;
+use actix_web::http::StatusCode;
+use actix_web::web::ServiceConfig;
+use actix_web::{post, put, web, HttpRequest, HttpResponse};
+use anyhow::{anyhow, Context, Result};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chrono::Utc;
+use rand::rngs::OsRng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{info, warn, Span};
+
+/// Longer passwords are rejected, so that hashing them can't be used to load the server
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    #[serde(default = "default_min_password_length")]
+    pub min_password_length: usize,
+    /// The account is locked after this many wrong passwords in a row
+    #[serde(default = "default_max_failed_attempts")]
+    pub max_failed_attempts: i32,
+    #[serde(default = "default_lockout", with = "humantime_serde")]
+    pub lockout: Duration,
+    #[serde(default = "default_invite_lifetime", with = "humantime_serde")]
+    pub invite_lifetime: Duration,
+    #[serde(default = "default_reset_lifetime", with = "humantime_serde")]
+    pub reset_lifetime: Duration,
+}
+
+fn default_min_password_length() -> usize {
+    10
+}
+fn default_max_failed_attempts() -> i32 {
+    5
+}
+fn default_lockout() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+fn default_invite_lifetime() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+fn default_reset_lifetime() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+#[derive(Debug)]
+pub enum LocalAccountError {
+    InvalidCredentials,
+    LockedOut,
+    WeakPassword(usize),
+    InvalidToken,
+    AlreadyRegistered,
+    UserNotFound,
+    NoPassword,
+}
+
+impl ApiError for LocalAccountError {
+    fn to_http(&self) -> (StatusCode, String) {
+        match self {
+            LocalAccountError::InvalidCredentials => (
+                StatusCode::UNAUTHORIZED,
+                "Invalid username or password".to_string(),
+            ),
+            LocalAccountError::LockedOut => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many invalid passwords, please try again later".to_string(),
+            ),
+            LocalAccountError::WeakPassword(min_length) => (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Password must be between {} and {} characters long",
+                    min_length, MAX_PASSWORD_LENGTH
+                ),
+            ),
+            LocalAccountError::InvalidToken => (
+                StatusCode::BAD_REQUEST,
+                "The link is invalid, expired or was already used, please ask for a new one"
+                    .to_string(),
+            ),
+            LocalAccountError::AlreadyRegistered => (
+                StatusCode::CONFLICT,
+                "The user already has a password, reset it instead".to_string(),
+            ),
+            LocalAccountError::UserNotFound => {
+                (StatusCode::NOT_FOUND, "User not found".to_string())
+            }
+            LocalAccountError::NoPassword => (
+                StatusCode::BAD_REQUEST,
+                "You don't have a password, you log in some other way".to_string(),
+            ),
+        }
+    }
+}
+
+struct Data {
+    config: Config,
+    /// Checked for users without a password, so that they take as long as the ones with it
+    dummy_hash: String,
+}
+
+fn argon2() -> Argon2<'static> {
+    // the parameters recommended by OWASP
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(19 * 1024, 2, 1, None).expect("BUG: Invalid Argon2 parameters"),
+    )
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(argon2()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Hashing password: {}", e))?
+        .to_string())
+}
+
+fn verify_password(hash: &str, password: &str) -> Result<bool> {
+    let hash = PasswordHash::new(hash).map_err(|e| anyhow!("Parsing password hash: {}", e))?;
+    Ok(argon2().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// Hashing takes a while, so it is done off the async executor
+async fn hash_password_blocking(password: String) -> Result<String> {
+    web::block(move || hash_password(&password))
+        .await
+        .context("Hashing password")?
+}
+
+async fn verify_password_blocking(hash: String, password: String) -> Result<bool> {
+    web::block(move || verify_password(&hash, &password))
+        .await
+        .context("Verifying password")?
+}
+
+fn check_password_policy(config: &Config, password: &str) -> Result<(), LocalAccountError> {
+    let length = password.chars().count();
+    if length < config.min_password_length || length > MAX_PASSWORD_LENGTH {
+        return Err(LocalAccountError::WeakPassword(config.min_password_length));
+    }
+    Ok(())
+}
+
+fn generate_token() -> String {
+    use rand::RngCore;
+
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// The tokens are random, so a fast hash is enough
+fn hash_token(token: &str) -> Vec<u8> {
+    openssl::sha::sha256(token.as_bytes()).to_vec()
+}
+
+/// Check the password, counting the failures and locking the account after too many of them
+async fn authenticate(
+    db: &DbData,
+    data: &Data,
+    username: String,
+    password: String,
+) -> ApiResult<db_models::User> {
+    let (user, user_password) = match db
+        .send(db::GetUserPassword {
+            span: Span::current(),
+            username,
+        })
+        .await??
+    {
+        Some((user, Some(user_password))) => (user, user_password),
+        _ => {
+            verify_password_blocking(data.dummy_hash.clone(), password).await?;
+            return Err(LocalAccountError::InvalidCredentials.into());
+        }
+    };
+
+    let lockout = chrono::Duration::from_std(data.config.lockout)?;
+    // counted before the check, so that concurrent requests can't make more attempts
+    let attempt = db
+        .send(db::CountPasswordAttempt {
+            span: Span::current(),
+            user_id: user.id,
+            max_failed_attempts: data.config.max_failed_attempts,
+            lockout_until: (Utc::now() + lockout).naive_utc(),
+        })
+        .await??
+        .ok_or(LocalAccountError::LockedOut)?;
+    if attempt >= data.config.max_failed_attempts {
+        warn!(
+            "Too many invalid passwords for {:?}, locking",
+            user.username
+        );
+    }
+
+    if !verify_password_blocking(user_password.password_hash, password).await? {
+        return Err(LocalAccountError::InvalidCredentials.into());
+    }
+
+    db.send(db::UpdatePasswordAttempts {
+        span: Span::current(),
+        user_id: user.id,
+        failed_attempts: 0,
+        locked_until: None,
+    })
+    .await??;
+
+    Ok(user)
+}
+
+#[post("/local/login")]
+async fn login(
+    req: HttpRequest,
+    db: DbData,
+    data: web::Data<Data>,
+    authority: web::Data<Authority>,
+    body: web::Json<models::PasswordLoginRequest>,
+) -> ApiResult<HttpResponse> {
+    let body = body.into_inner();
+    let user = authenticate(&db, &data, body.username, body.password).await?;
+
+    info!("User {:?} logged in with a password", user.username);
+
+    let name = user.name.unwrap_or_else(|| user.username.clone());
+    let outcome = super::create_session_cookie(&req, &db, &authority, user.username, name).await?;
+
+    Ok(outcome.into_response())
+}
+
+/// Set the password with a token and log in
+async fn redeem_token(
+    req: HttpRequest,
+    db: DbData,
+    data: web::Data<Data>,
+    authority: web::Data<Authority>,
+    body: models::SetPasswordWithToken,
+    purpose: PasswordTokenPurpose,
+) -> ApiResult<HttpResponse> {
+    check_password_policy(&data.config, &body.password)?;
+
+    let user = db
+        .send(db::RedeemPasswordToken {
+            span: Span::current(),
+            token_hash: hash_token(&body.token),
+            purpose,
+            password_hash: hash_password_blocking(body.password).await?,
+        })
+        .await??
+        .ok_or(LocalAccountError::InvalidToken)?;
+
+    if purpose == PasswordTokenPurpose::Reset {
+        // whoever knew the old password should not stay logged in
+        db.send(db::RevokeLogins {
+            span: Span::current(),
+            user_id: user.id,
+            jti: None,
+        })
+        .await??;
+    }
+
+    info!("User {:?} set their password ({})", user.username, purpose);
+
+    let name = user.name.unwrap_or_else(|| user.username.clone());
+    let outcome = super::create_session_cookie(&req, &db, &authority, user.username, name).await?;
+
+    Ok(outcome.into_response())
+}
+
+#[post("/local/register")]
+async fn register(
+    req: HttpRequest,
+    db: DbData,
+    data: web::Data<Data>,
+    authority: web::Data<Authority>,
+    body: web::Json<models::SetPasswordWithToken>,
+) -> ApiResult<HttpResponse> {
+    redeem_token(
+        req,
+        db,
+        data,
+        authority,
+        body.into_inner(),
+        PasswordTokenPurpose::Invite,
+    )
+    .await
+}
+
+#[post("/local/reset")]
+async fn reset(
+    req: HttpRequest,
+    db: DbData,
+    data: web::Data<Data>,
+    authority: web::Data<Authority>,
+    body: web::Json<models::SetPasswordWithToken>,
+) -> ApiResult<HttpResponse> {
+    redeem_token(
+        req,
+        db,
+        data,
+        authority,
+        body.into_inner(),
+        PasswordTokenPurpose::Reset,
+    )
+    .await
+}
+
+#[put("/me/password")]
+async fn change_password(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    data: web::Data<Data>,
+    body: web::Json<models::ChangePassword>,
+) -> ApiResult<web::Json<HashMap<(), ()>>> {
+    let body = body.into_inner();
+    check_password_policy(&data.config, &body.new_password)?;
+
+    match db
+        .send(db::GetUserPassword {
+            span: Span::current(),
+            username: user.username.clone(),
+        })
+        .await??
+    {
+        Some((_, Some(_))) => {}
+        _ => return Err(LocalAccountError::NoPassword.into()),
+    }
+    authenticate(&db, &data, user.username.clone(), body.current_password).await?;
+
+    db.send(db::SetPassword {
+        span: Span::current(),
+        user_id: user.user_id,
+        password_hash: hash_password_blocking(body.new_password).await?,
+    })
+    .await??;
+
+    info!("User {:?} changed their password", user.username);
+
+    Ok(web::Json(HashMap::new()))
+}
+
+async fn create_token(
+    db: &DbData,
+    user: &db_models::User,
+    purpose: PasswordTokenPurpose,
+    lifetime: Duration,
+) -> ApiResult<models::PasswordToken> {
+    let token = generate_token();
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::from_std(lifetime)?;
+
+    db.send(db::CreatePasswordToken {
+        span: Span::current(),
+        token: db_models::NewPasswordToken {
+            token_hash: hash_token(&token),
+            user_id: user.id,
+            purpose,
+            created_at: now.naive_utc(),
+            expires_at: expires_at.naive_utc(),
+        },
+    })
+    .await??;
+
+    Ok(models::PasswordToken {
+        username: user.username.clone(),
+        token,
+        expires_at,
+    })
+}
+
+/// Create the user, if needed, and a token for them to set their password with
+#[post("/admin/invites")]
+async fn invite(
+    UserToken { claims: admin, .. }: UserToken,
+    db: DbData,
+    data: web::Data<Data>,
+    body: web::Json<models::NewInvite>,
+) -> ApiResult<web::Json<models::PasswordToken>> {
+    admin.require_role(Role::Admin)?;
+    let body = body.into_inner();
+
+    if let Some((_, Some(_))) = db
+        .send(db::GetUserPassword {
+            span: Span::current(),
+            username: body.username.clone(),
+        })
+        .await??
+    {
+        return Err(LocalAccountError::AlreadyRegistered.into());
+    }
+
+    let mut user = db
+        .send(db::GetOrCreateUser {
+            span: Span::current(),
+            username: body.username,
+            name: body.name,
+        })
+        .await??;
+    if let Some(role) = body.role.filter(|role| *role != user.role) {
+        user = db
+            .send(db::SetUserRole {
+                span: Span::current(),
+                username: user.username,
+                role,
+            })
+            .await??;
+    }
+
+    let token = create_token(
+        &db,
+        &user,
+        PasswordTokenPurpose::Invite,
+        data.config.invite_lifetime,
+    )
+    .await?;
+
+    info!(
+        "User {:?} invited {:?} as {}",
+        admin.username, user.username, user.role
+    );
+
+    Ok(web::Json(token))
+}
+
+#[post("/admin/users/{username}/password-reset")]
+async fn create_reset_token(
+    UserToken { claims: admin, .. }: UserToken,
+    db: DbData,
+    data: web::Data<Data>,
+    path: web::Path<models::UserRef>,
+) -> ApiResult<web::Json<models::PasswordToken>> {
+    admin.require_role(Role::Admin)?;
+
+    let (user, _) = db
+        .send(db::GetUserPassword {
+            span: Span::current(),
+            username: path.into_inner().username,
+        })
+        .await??
+        .ok_or(LocalAccountError::UserNotFound)?;
+
+    let token = create_token(
+        &db,
+        &user,
+        PasswordTokenPurpose::Reset,
+        data.config.reset_lifetime,
+    )
+    .await?;
+
+    info!(
+        "User {:?} created a password reset token for {:?}",
+        admin.username, user.username
+    );
+
+    Ok(web::Json(token))
+}
+
+pub fn configure(config: Option<Config>) -> Result<impl Fn(&mut ServiceConfig) + Clone> {
+    let data = config
+        .map(|config| -> Result<_> {
+            Ok(web::Data::new(Data {
+                config,
+                dummy_hash: hash_password("dummy password")?,
+            }))
+        })
+        .transpose()?;
+
+    Ok(move |cfg: &mut ServiceConfig| {
+        if let Some(data) = &data {
+            cfg.app_data(data.clone())
+                .service(login)
+                .service(register)
+                .service(reset)
+                .service(change_password)
+                .service(invite)
+                .service(create_reset_token);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_password_hash() {
+        let hash = hash_password("correct horse battery staple").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+
+        assert!(verify_password(&hash, "correct horse battery staple").unwrap());
+        assert!(!verify_password(&hash, "correct horse battery").unwrap());
+        // salted
+        assert_ne!(hash, hash_password("correct horse battery staple").unwrap());
+    }
+
+    #[test]
+    fn test_password_policy() {
+        let config = Config {
+            min_password_length: 10,
+            max_failed_attempts: default_max_failed_attempts(),
+            lockout: default_lockout(),
+            invite_lifetime: default_invite_lifetime(),
+            reset_lifetime: default_reset_lifetime(),
+        };
+
+        assert!(check_password_policy(&config, "short").is_err());
+        // characters, not bytes
+        assert!(check_password_policy(&config, "пароль").is_err());
+        assert!(check_password_policy(&config, "длинный пароль").is_ok());
+        assert!(check_password_policy(&config, &"a".repeat(MAX_PASSWORD_LENGTH + 1)).is_err());
+    }
+}
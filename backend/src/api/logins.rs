@@ -0,0 +1,74 @@
+use crate::api::auth::{Authority, UserToken};
+use crate::api::error::ApiResult;
+use crate::api::models;
+use crate::db;
+use crate::db::DbData;
+use actix_web::{delete, get, web, HttpResponse};
+use std::collections::HashMap;
+use tracing::{info, Span};
+
+#[get("/me/logins")]
+async fn get_logins(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+) -> ApiResult<web::Json<Vec<models::Login>>> {
+    let logins = db
+        .send(db::GetActiveLogins {
+            span: Span::current(),
+            user_id: user.user_id,
+        })
+        .await??;
+
+    Ok(web::Json(
+        logins
+            .into_iter()
+            .map(|l| models::Login::new(l, &user.jti))
+            .collect(),
+    ))
+}
+
+#[delete("/me/logins/{login_id}")]
+async fn revoke_login(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    authority: web::Data<Authority>,
+    req: web::Path<models::LoginRef>,
+) -> ApiResult<HttpResponse> {
+    let req = req.into_inner();
+    db.send(db::RevokeLogins {
+        span: Span::current(),
+        user_id: user.user_id,
+        jti: Some(req.login_id.clone()),
+    })
+    .await??;
+
+    info!("User {:?} revoked login {:?}", user.username, req.login_id);
+
+    let mut response = HttpResponse::Ok();
+    if req.login_id == user.jti {
+        response.cookie(authority.create_removal_cookie());
+    }
+    Ok(response.json(HashMap::<(), ()>::new()))
+}
+
+/// Log out everywhere, including the current login
+#[delete("/me/logins")]
+async fn revoke_all_logins(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    authority: web::Data<Authority>,
+) -> ApiResult<HttpResponse> {
+    let count = db
+        .send(db::RevokeLogins {
+            span: Span::current(),
+            user_id: user.user_id,
+            jti: None,
+        })
+        .await??;
+
+    info!("User {:?} revoked all {} logins", user.username, count);
+
+    Ok(HttpResponse::Ok()
+        .cookie(authority.create_removal_cookie())
+        .json(HashMap::<(), ()>::new()))
+}
@@ -0,0 +1,328 @@
+//! Passwordless login with single-use links sent by email, for guest lecturers and the like
+//!
+//! The link carries a token signed like the session cookies, and its id is remembered once it is used.
+//!   Opening the link only shows a page with a button that posts the token, as mail scanners open
+//!   the links they see and would use them up otherwise.
+
+use crate::api::auth::{Authority, TokenClaims};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::models;
+use crate::db;
+use crate::db::DbData;
+use crate::mail::{Email, Mailer};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::ServiceConfig;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use anyhow::{bail, Result};
+use chrono::Utc;
+use lettre::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{info, warn, Instrument, Span};
+use url::Url;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Should point to the `/api/magic-link/login` endpoint, the token is added as the `token` parameter
+    pub login_url: Url,
+    #[serde(default = "default_lifetime", with = "humantime_serde")]
+    pub lifetime: Duration,
+    /// Email domains that can log in, any if empty
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// Whether users are created on their first login. If not, only the existing users
+    ///   (like the ones given a role by an admin) get the links
+    #[serde(default)]
+    pub allow_new_users: bool,
+    /// Where to send the user after a successful login
+    #[serde(default = "default_post_login_redirect")]
+    pub post_login_redirect: String,
+}
+
+fn default_lifetime() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+fn default_post_login_redirect() -> String {
+    "/".to_string()
+}
+
+#[derive(Debug)]
+pub enum MagicLinkError {
+    InvalidEmail,
+    InvalidLink,
+}
+
+impl ApiError for MagicLinkError {
+    fn to_http(&self) -> (StatusCode, String) {
+        match self {
+            MagicLinkError::InvalidEmail => (
+                StatusCode::BAD_REQUEST,
+                "This email address can't be used to log in".to_string(),
+            ),
+            MagicLinkError::InvalidLink => (
+                StatusCode::BAD_REQUEST,
+                "The login link is invalid, expired or was already used, please ask for a new one"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// Claims of the token in the link
+#[derive(Serialize, Deserialize, Clone)]
+struct MagicLinkClaims {
+    email: String,
+    jti: String,
+}
+
+impl TokenClaims for MagicLinkClaims {
+    const PURPOSE: &'static str = "magic_link";
+}
+
+/// Lowercase the address and check that it is valid and in one of the allowed domains
+fn normalize_email(config: &Config, email: &str) -> Result<String, MagicLinkError> {
+    let email = email.trim().to_lowercase();
+    // a plain split at the last '@' would let `guest@evil.com@innopolis.university` through
+    let address: Address = email.parse().map_err(|_| MagicLinkError::InvalidEmail)?;
+    let domain = address.domain();
+    if !config.allowed_domains.is_empty()
+        && !config
+            .allowed_domains
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+    {
+        return Err(MagicLinkError::InvalidEmail);
+    }
+    Ok(email)
+}
+
+/// Send the login link, if the user can log in. The response is the same either way
+#[post("/magic-link")]
+async fn request_link(
+    db: DbData,
+    config: web::Data<Config>,
+    mailer: web::Data<Mailer>,
+    authority: web::Data<Authority>,
+    body: web::Json<models::MagicLinkRequest>,
+) -> ApiResult<web::Json<HashMap<(), ()>>> {
+    let email = normalize_email(&config, &body.email)?;
+
+    if !config.allow_new_users {
+        let user = db
+            .send(db::GetUser {
+                span: Span::current(),
+                username: email.clone(),
+            })
+            .await??;
+        if user.is_none() {
+            info!("Not sending a login link to unknown user {:?}", email);
+            return Ok(web::Json(HashMap::new()));
+        }
+    }
+
+    let (token, _) = authority.sign_token(
+        MagicLinkClaims {
+            email: email.clone(),
+            jti: super::auth::new_token_id(),
+        },
+        chrono::Duration::from_std(config.lifetime)?,
+    )?;
+    let mut url = config.login_url.clone();
+    url.query_pairs_mut().append_pair("token", &token);
+
+    let mail = Email {
+        to: email.clone(),
+        subject: "Log in to BAAM".to_string(),
+        body: format!(
+            "Hello,\n\n\
+            Follow this link to log in to BAAM:\n\n\
+            {}\n\n\
+            The link works once and expires in {} minutes. If you did not ask for it, just ignore this mail.\n",
+            url,
+            config.lifetime.as_secs() / 60
+        ),
+    };
+    // in the background, so that the response time does not tell which users exist
+    actix_web::rt::spawn(
+        async move {
+            match mailer.send(mail).await {
+                Ok(()) => info!("Sent a login link to {:?}", email),
+                Err(e) => warn!("Could not send a login link to {:?}: {:?}", email, e),
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(web::Json(HashMap::new()))
+}
+
+fn validate_link(
+    authority: &Authority,
+    token: &str,
+) -> Result<jwt_compact::Claims<MagicLinkClaims>, MagicLinkError> {
+    authority
+        .validate_token::<MagicLinkClaims>(token)
+        .map_err(|e| {
+            warn!("Could not validate the magic link: {:?}", e);
+            MagicLinkError::InvalidLink
+        })
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The page the link opens, the login happens only when the user presses the button
+fn confirmation_page(email: &str, token: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Log in to BAAM</title>
+</head>
+<body>
+<form method="post" action="login">
+<p>Log in to BAAM as {}?</p>
+<input type="hidden" name="token" value="{}">
+<button type="submit">Log in</button>
+</form>
+</body>
+</html>
+"#,
+        escape_html(email),
+        escape_html(token)
+    )
+}
+
+/// Show the confirmation page, without using up the link
+#[get("/magic-link/login")]
+async fn confirm_login(
+    authority: web::Data<Authority>,
+    query: web::Query<models::MagicLinkToken>,
+) -> ApiResult<HttpResponse> {
+    let claims = validate_link(&authority, &query.token)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(confirmation_page(&claims.custom.email, &query.token)))
+}
+
+#[post("/magic-link/login")]
+async fn login(
+    req: HttpRequest,
+    db: DbData,
+    config: web::Data<Config>,
+    authority: web::Data<Authority>,
+    form: web::Form<models::MagicLinkToken>,
+) -> ApiResult<HttpResponse> {
+    let claims = validate_link(&authority, &form.token)?;
+    let expires_at = claims.expiration.unwrap_or_else(Utc::now);
+    let MagicLinkClaims { email, jti } = claims.custom;
+
+    let first_use = db
+        .send(db::UseMagicLink {
+            span: Span::current(),
+            jti,
+            email: email.clone(),
+            expires_at: expires_at.naive_utc(),
+        })
+        .await??;
+    if !first_use {
+        warn!("Magic link of {:?} was used again", email);
+        return Err(MagicLinkError::InvalidLink.into());
+    }
+
+    let user = db
+        .send(db::GetUser {
+            span: Span::current(),
+            username: email.clone(),
+        })
+        .await??;
+    if user.is_none() && !config.allow_new_users {
+        // the user was removed after the link was sent
+        return Err(MagicLinkError::InvalidLink.into());
+    }
+    let name = user.and_then(|u| u.name).unwrap_or_else(|| email.clone());
+
+    info!("User {:?} logged in with a magic link", email);
+
+    let outcome = super::create_session_cookie(&req, &db, &authority, email, name).await?;
+
+    // 303 makes the browser follow the redirect with a GET
+    Ok(outcome
+        .into_redirect(StatusCode::SEE_OTHER, &config.post_login_redirect)
+        .finish())
+}
+
+pub fn configure(
+    config: Option<Config>,
+    mailer: Option<web::Data<Mailer>>,
+) -> Result<impl Fn(&mut ServiceConfig) + Clone> {
+    let data = match (config, mailer) {
+        (Some(config), Some(mailer)) => Some((web::Data::new(config), mailer)),
+        (Some(_), None) => bail!("The magic link login needs the `mail` section in the config"),
+        (None, _) => None,
+    };
+
+    Ok(move |cfg: &mut ServiceConfig| {
+        if let Some((config, mailer)) = &data {
+            cfg.app_data(config.clone())
+                .app_data(mailer.clone())
+                .service(request_link)
+                .service(confirm_login)
+                .service(login);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_email() {
+        let mut config = Config {
+            login_url: Url::parse("http://localhost:8080/api/magic-link/login").unwrap(),
+            lifetime: default_lifetime(),
+            allowed_domains: vec![],
+            allow_new_users: false,
+            post_login_redirect: default_post_login_redirect(),
+        };
+
+        assert_eq!(
+            normalize_email(&config, " Guest@Example.com ").unwrap(),
+            "guest@example.com"
+        );
+        assert!(normalize_email(&config, "guest").is_err());
+        assert!(normalize_email(&config, "@example.com").is_err());
+        assert!(normalize_email(&config, "guest lecturer@example.com").is_err());
+
+        config.allowed_domains = vec!["innopolis.university".to_string()];
+        assert!(normalize_email(&config, "guest@innopolis.university").is_ok());
+        assert!(normalize_email(&config, "guest@example.com").is_err());
+        assert!(normalize_email(&config, "guest@evil.com@innopolis.university").is_err());
+    }
+
+    #[test]
+    fn test_confirmation_page() {
+        let page = confirmation_page("guest@example.com", "a.b\"><script>");
+        assert!(page.contains(r#"<form method="post" action="login">"#));
+        assert!(page.contains(r#"value="a.b&quot;&gt;&lt;script&gt;""#));
+        assert!(!page.contains("<script>"));
+    }
+}
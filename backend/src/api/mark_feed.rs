@@ -0,0 +1,216 @@
+//! Live attendance feed of the teacher page, as Server-Sent Events
+//!
+//! The changes of the marks are recorded in the `mark_events` table in the transaction of the change, and the feed
+//!   polls them after its cursor, the id of the last event it sent. A new client gets a snapshot of the session
+//!   first. A client reconnecting with the cursor (`EventSource` sends it as `Last-Event-ID` by itself) gets the
+//!   events it missed instead, even across restarts of the server. The events may come twice around a snapshot,
+//!   so the clients apply them by username.
+
+use crate::api::auth::UserClaims;
+use crate::api::challenge;
+use crate::api::error::ApiResult;
+use crate::api::mark_flags::flag_marks;
+use crate::api::models::{
+    AttendanceMark, GetSession, MarkFeedQuery, MarkRemoved, SessionWithMarks,
+};
+use crate::db;
+use crate::db::models::{self as db_models, MarkEventId, MarkEventKind, Scope, SessionId, UserId};
+use crate::db::DbData;
+use actix_web::http::header::{self, HeaderName};
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+use tracing::{info, Span};
+
+const LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
+/// How often the new events are looked for
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// A comment is sent after this long without events, so that the proxies keep the connection open
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// One event in the SSE format, the JSON has no newlines in it
+fn sse_event(cursor: MarkEventId, name: &str, data: &impl Serialize) -> ApiResult<String> {
+    Ok(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        cursor.0,
+        name,
+        serde_json::to_string(data)?
+    ))
+}
+
+/// The events as sent to the client, along with the new cursor
+fn feed_events(
+    (session, events, marks): db_models::MarkEvents,
+    mut cursor: MarkEventId,
+    config: &challenge::Config,
+) -> ApiResult<(String, MarkEventId)> {
+    let config = config.for_session(&session);
+    let mut flags = flag_marks(
+        marks.values(),
+        config.shared_code_threshold,
+        &config.allowed_networks,
+    );
+
+    let mut data = String::new();
+    for (event, user) in events {
+        cursor = event.id;
+        match event.kind {
+            MarkEventKind::Added => {
+                // removed since then, the removal comes later
+                let mark = match marks.get(&event.mark_id) {
+                    Some(mark) => mark.clone(),
+                    None => continue,
+                };
+                let flags = flags.remove(&mark.id).unwrap_or_default();
+                let mark = AttendanceMark::new(mark, user.username, flags);
+                data += &sse_event(event.id, "mark_added", &mark)?;
+            }
+            MarkEventKind::Removed => {
+                let removed = MarkRemoved {
+                    username: user.username,
+                };
+                data += &sse_event(event.id, "mark_removed", &removed)?;
+            }
+        }
+    }
+    Ok((data, cursor))
+}
+
+struct Feed {
+    db: DbData,
+    config: web::Data<challenge::Config>,
+    user_id: UserId,
+    session_id: SessionId,
+    cursor: MarkEventId,
+    /// The snapshot or the missed events, sent before polling
+    first: Option<String>,
+    last_sent: Instant,
+}
+
+impl Feed {
+    /// The next chunk of the response, the feed ends when the session can no longer be seen
+    async fn next(mut self) -> Option<(Result<Bytes, Infallible>, Self)> {
+        if let Some(first) = self.first.take().filter(|first| !first.is_empty()) {
+            return Some((Ok(Bytes::from(first)), self));
+        }
+
+        loop {
+            actix_web::rt::time::sleep(POLL_INTERVAL).await;
+
+            let events: ApiResult<_> = async {
+                let events = self
+                    .db
+                    .send(db::GetMarkEvents {
+                        span: Span::current(),
+                        user_id: self.user_id,
+                        session_id: self.session_id,
+                        after: self.cursor,
+                    })
+                    .await??;
+                feed_events(events, self.cursor, &self.config)
+            }
+            .await;
+            let (data, cursor) = match events {
+                Ok(data) => data,
+                Err(e) => {
+                    info!(
+                        "Ending the attendance feed of session {:?} for user {:?}: {:?}",
+                        self.session_id, self.user_id, e
+                    );
+                    return None;
+                }
+            };
+            self.cursor = cursor;
+
+            if !data.is_empty() {
+                self.last_sent = Instant::now();
+                return Some((Ok(Bytes::from(data)), self));
+            }
+            if self.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+                self.last_sent = Instant::now();
+                return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), self));
+            }
+        }
+    }
+}
+
+#[get("/sessions/{session_id}/feed")]
+async fn mark_feed(
+    req: HttpRequest,
+    user: UserClaims,
+    db: DbData,
+    config: web::Data<challenge::Config>,
+    path: web::Path<GetSession>,
+    query: web::Query<MarkFeedQuery>,
+) -> ApiResult<HttpResponse> {
+    user.require_scope(Scope::SessionsRead)?;
+    let session_id = path.into_inner().session_id;
+    let cursor = req
+        .headers()
+        .get(LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .or(query.cursor)
+        .map(MarkEventId);
+
+    // the first request is made here, so that the errors are returned as usual
+    let (first, cursor) = match cursor {
+        Some(cursor) => {
+            let events = db
+                .send(db::GetMarkEvents {
+                    span: Span::current(),
+                    user_id: user.user_id,
+                    session_id,
+                    after: cursor,
+                })
+                .await??;
+            feed_events(events, cursor, &config)?
+        }
+        None => {
+            let (session, permission, cursor) = db
+                .send(db::GetMarkSnapshot {
+                    span: Span::current(),
+                    user_id: user.user_id,
+                    session_id,
+                })
+                .await??;
+            let snapshot = SessionWithMarks::new(session, permission, &config);
+            (sse_event(cursor, "snapshot", &snapshot)?, cursor)
+        }
+    };
+
+    let feed = Feed {
+        db,
+        config,
+        user_id: user.user_id,
+        session_id,
+        cursor,
+        first: Some(first),
+        last_sent: Instant::now(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // or the ingress holds the events back
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(futures::stream::unfold(feed, Feed::next)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sse_event() {
+        let removed = MarkRemoved {
+            username: "n.strygin@innopolis.university".to_string(),
+        };
+        assert_eq!(
+            sse_event(MarkEventId(42), "mark_removed", &removed).unwrap(),
+            "id: 42\nevent: mark_removed\ndata: {\"username\":\"n.strygin@innopolis.university\"}\n\n"
+        );
+    }
+}
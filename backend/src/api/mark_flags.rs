@@ -0,0 +1,208 @@
+//! Spotting QR codes forwarded to students who are not in the room
+//!
+//! A photo of the code can be messaged to a friend and redeemed within the jitter window. Such marks are not
+//!   rejected, but flagged for the teacher to review. The flags are worked out from all the marks of the session
+//!   every time, so that the earlier marks get flagged as well once the pattern shows up.
+
+use crate::api::client_ip::in_networks;
+use crate::db::models::{AttendanceMark, AttendanceMarkId, GeofencePosition};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkFlag {
+    /// The same code was redeemed by too many students from one IP
+    SharedCode,
+    /// The code was redeemed after it left the screen, from a network no one else in the session used
+    LateFromOtherNetwork,
+    /// Accepted from outside the allowed networks of the session
+    OutsideAllowedNetworks,
+    /// Accepted from outside the geofence of the session
+    OutsideGeofence,
+    /// Accepted without a precise enough location in a session with a geofence
+    UnknownLocation,
+}
+
+/// Older marks have the port in the address
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    ip.parse::<IpAddr>()
+        .or_else(|_| ip.parse::<SocketAddr>().map(|a| a.ip()))
+        .ok()
+}
+
+/// The network the address belongs to: the /24 for IPv4 and the /48 for IPv6
+fn network(ip: &str) -> Option<String> {
+    Some(match parse_ip(ip)? {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", a, b, c)
+        }
+    })
+}
+
+/// Flags of the suspicious marks of a session, the marks without flags are left out
+pub fn flag_marks<'a>(
+    marks: impl IntoIterator<Item = &'a AttendanceMark>,
+    shared_code_threshold: usize,
+    allowed_networks: &[IpNet],
+) -> HashMap<AttendanceMarkId, Vec<MarkFlag>> {
+    let marks = marks
+        .into_iter()
+        .filter(|m| !m.is_manual)
+        .collect::<Vec<_>>();
+
+    let mut by_code_and_ip = HashMap::<_, Vec<_>>::new();
+    let mut by_network = HashMap::<_, usize>::new();
+    for mark in &marks {
+        if let (Some(index), Some(ip)) = (mark.challenge_index, &mark.ip) {
+            by_code_and_ip.entry((index, ip)).or_default().push(mark.id);
+        }
+        if let Some(network) = mark.ip.as_deref().and_then(network) {
+            *by_network.entry(network).or_default() += 1;
+        }
+    }
+
+    let mut flags = HashMap::<_, Vec<_>>::new();
+    for ids in by_code_and_ip.values() {
+        if ids.len() > shared_code_threshold {
+            for id in ids {
+                flags.entry(*id).or_default().push(MarkFlag::SharedCode);
+            }
+        }
+    }
+    for mark in &marks {
+        let late = mark.late_ms.unwrap_or(0) > 0;
+        let alone = matches!(
+            mark.ip.as_deref().and_then(network),
+            Some(n) if by_network[&n] == 1
+        );
+        if late && alone {
+            flags
+                .entry(mark.id)
+                .or_default()
+                .push(MarkFlag::LateFromOtherNetwork);
+        }
+        let disallowed = !allowed_networks.is_empty()
+            && matches!(
+                mark.ip.as_deref().and_then(parse_ip),
+                Some(ip) if !in_networks(allowed_networks, &ip)
+            );
+        if disallowed {
+            flags
+                .entry(mark.id)
+                .or_default()
+                .push(MarkFlag::OutsideAllowedNetworks);
+        }
+        let geofence_flag = match mark.geofence_position {
+            Some(GeofencePosition::Outside) => Some(MarkFlag::OutsideGeofence),
+            Some(GeofencePosition::Unknown) => Some(MarkFlag::UnknownLocation),
+            Some(GeofencePosition::Inside) | None => None,
+        };
+        if let Some(flag) = geofence_flag {
+            flags.entry(mark.id).or_default().push(flag);
+        }
+    }
+
+    flags
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::{SessionId, UserId};
+    use chrono::NaiveDateTime;
+
+    fn mark(id: i32, index: i32, ip: &str, late_ms: i32) -> AttendanceMark {
+        AttendanceMark {
+            id: AttendanceMarkId(id),
+            user_id: UserId(id),
+            session_id: SessionId(1),
+            mark_time: NaiveDateTime::from_timestamp(1645671600, 0),
+            is_manual: false,
+            challenge_index: Some(index),
+            ip: Some(ip.to_string()),
+            late_ms: Some(late_ms),
+            geofence_position: None,
+            distance_m: None,
+            note: None,
+        }
+    }
+
+    #[test]
+    fn test_network() {
+        assert_eq!(network("10.91.4.17").unwrap(), "10.91.4.0/24");
+        assert_eq!(network("10.91.4.17:52100").unwrap(), "10.91.4.0/24");
+        assert_eq!(
+            network("2001:db8:85a3:8d3:1319:8a2e:370:7348").unwrap(),
+            "2001:db8:85a3::/48"
+        );
+        assert_eq!(network("unknown"), None);
+    }
+
+    #[test]
+    fn test_flag_marks() {
+        let mut marks = vec![
+            // the classroom
+            mark(1, 4, "10.91.4.17", 0),
+            mark(2, 4, "10.91.4.18", 0),
+            mark(3, 5, "10.91.4.19", 200),
+            // one phone redeeming the code for three students
+            mark(4, 6, "10.91.4.20", 0),
+            mark(5, 6, "10.91.4.20", 0),
+            mark(6, 6, "10.91.4.20", 0),
+            // a photo of the code at home
+            mark(7, 7, "188.130.155.2", 300),
+            // in time from a phone on mobile data
+            mark(8, 8, "85.26.164.3", 0),
+        ];
+        let mut manual = mark(9, 0, "", 0);
+        manual.is_manual = true;
+        manual.challenge_index = None;
+        manual.ip = None;
+        marks.push(manual);
+
+        let flags = flag_marks(&marks, 2, &[]);
+        let mut flagged = flags.iter().collect::<Vec<_>>();
+        flagged.sort_by_key(|(id, _)| id.0);
+        assert_eq!(
+            flagged,
+            [
+                (&AttendanceMarkId(4), &vec![MarkFlag::SharedCode]),
+                (&AttendanceMarkId(5), &vec![MarkFlag::SharedCode]),
+                (&AttendanceMarkId(6), &vec![MarkFlag::SharedCode]),
+                (&AttendanceMarkId(7), &vec![MarkFlag::LateFromOtherNetwork]),
+            ]
+        );
+
+        // below the threshold
+        assert!(!flag_marks(&marks, 3, &[]).contains_key(&AttendanceMarkId(4)));
+
+        // accepted by a session with a flagging geofence
+        marks[0].geofence_position = Some(GeofencePosition::Inside);
+        marks[1].geofence_position = Some(GeofencePosition::Outside);
+        marks[6].geofence_position = Some(GeofencePosition::Unknown);
+        let flags = flag_marks(&marks, 2, &[]);
+        assert!(!flags.contains_key(&AttendanceMarkId(1)));
+        assert_eq!(flags[&AttendanceMarkId(2)], [MarkFlag::OutsideGeofence]);
+        assert_eq!(
+            flags[&AttendanceMarkId(7)],
+            [MarkFlag::LateFromOtherNetwork, MarkFlag::UnknownLocation]
+        );
+
+        // only the campus network is allowed
+        let flags = flag_marks(&marks, 2, &["10.91.0.0/16".parse().unwrap()]);
+        assert!(!flags.contains_key(&AttendanceMarkId(1)));
+        assert_eq!(
+            flags[&AttendanceMarkId(8)],
+            [MarkFlag::OutsideAllowedNetworks]
+        );
+        assert!(!flags.contains_key(&AttendanceMarkId(9)));
+    }
+}
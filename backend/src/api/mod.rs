@@ -1,27 +1,53 @@
+mod admin;
 pub mod auth;
 mod challenge;
+mod client_ip;
 pub mod error;
+mod geofence;
+mod ldap;
+mod local_accounts;
+mod logins;
+mod magic_link;
+mod mark_feed;
+mod mark_flags;
 pub mod models;
+mod saml;
 pub mod sentry_tunnel;
 mod sessions;
 mod sso;
+mod time;
+mod tokens;
+mod totp;
+mod webauthn;
 
+use crate::api::client_ip::{ClientIp, TrustedProxies};
 use crate::api::models::LoginRequest;
 use crate::db::models as db_models;
 use crate::db::DbData;
 use actix_web::cookie::Cookie;
-use actix_web::http::StatusCode;
-use actix_web::{get, post, web, web::ServiceConfig, HttpResponse, Responder};
-use anyhow::{anyhow, Result};
+use actix_web::http::{header, StatusCode};
+use actix_web::{
+    get, post, web, web::ServiceConfig, HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
 use error::ApiResult;
 use std::collections::HashMap;
 use tracing::Span;
 
-use crate::api::auth::UserClaims;
+use crate::api::auth::{UserClaims, UserToken};
 use crate::config::Config;
 use crate::db;
-pub use auth::AuthKeys;
+use crate::mail::Mailer;
+pub use auth::{AuthKeys, Authority};
 pub use challenge::Config as ChallengeConfig;
+pub use ldap::Config as LdapConfig;
+pub use local_accounts::Config as LocalAccountsConfig;
+pub use magic_link::Config as MagicLinkConfig;
+pub use saml::Config as SamlConfig;
+pub use sso::Config as OidcConfig;
+pub use totp::Config as TotpConfig;
+pub use webauthn::Config as WebauthnConfig;
 
 #[get("/")]
 async fn hello() -> impl Responder {
@@ -43,61 +69,164 @@ async fn make_error() -> ApiResult<web::Bytes> {
     Err(anyhow!("Example error").into())
 }
 
-fn cookie_config(cookie: &mut Cookie) {
-    cookie.set_http_only(true);
-    cookie.set_same_site(actix_web::cookie::SameSite::Strict);
-    cookie.set_path("/");
+/// Result of the primary login
+enum LoginOutcome {
+    /// The user is logged in with the session cookie
+    LoggedIn(Cookie<'static>),
+    /// The cookie only lets the user verify their second factor at `/api/totp/verify`
+    SecondFactorRequired {
+        cookie: Cookie<'static>,
+        verify_page: String,
+    },
 }
 
-#[post("/login")]
-async fn login(
-    db: DbData,
-    authority: web::Data<auth::Authority>,
-    body: web::Json<LoginRequest>,
-) -> ApiResult<HttpResponse> {
-    let body = body.into_inner();
+impl LoginOutcome {
+    /// Answer to a login made with an API request, telling whether to ask for the second factor
+    fn into_response(self) -> HttpResponse {
+        let (cookie, second_factor_required) = match self {
+            LoginOutcome::LoggedIn(cookie) => (cookie, false),
+            LoginOutcome::SecondFactorRequired { cookie, .. } => (cookie, true),
+        };
+        HttpResponse::Ok().cookie(cookie).json(models::LoginResult {
+            second_factor_required,
+        })
+    }
+
+    /// Answer to a login the browser was sent through, redirecting to `post_login_redirect`
+    ///   or to the second factor page
+    fn into_redirect(self, status: StatusCode, post_login_redirect: &str) -> HttpResponseBuilder {
+        let (cookie, location) = match self {
+            LoginOutcome::LoggedIn(cookie) => (cookie, post_login_redirect.to_string()),
+            LoginOutcome::SecondFactorRequired {
+                cookie,
+                verify_page,
+            } => (cookie, verify_page),
+        };
+        let mut response = HttpResponse::build(status);
+        response
+            .cookie(cookie)
+            .insert_header((header::LOCATION, location));
+        response
+    }
+}
 
+/// Find or create the user with the supplied username, register a new login for them and produce a session cookie
+///
+/// If the user has a second factor, they are not logged in yet, see [`LoginOutcome::SecondFactorRequired`]
+async fn create_session_cookie(
+    req: &HttpRequest,
+    db: &DbData,
+    authority: &auth::Authority,
+    username: String,
+    name: String,
+) -> ApiResult<LoginOutcome> {
     let user: db_models::User = db
         .send(db::GetOrCreateUser {
             span: Span::current(),
-            username: body.username,
-            name: body.name,
+            username,
+            name,
+        })
+        .await??;
+
+    if let Some(totp) = req.app_data::<web::Data<totp::Data>>() {
+        if let Some(cookie) = totp::hold_login(db, authority, &user).await? {
+            return Ok(LoginOutcome::SecondFactorRequired {
+                cookie,
+                verify_page: totp.verify_page().to_string(),
+            });
+        }
+    }
+
+    Ok(LoginOutcome::LoggedIn(
+        issue_session_cookie(req, db, authority, user).await?,
+    ))
+}
+
+/// Register a new login for the user, who has passed all the factors, and produce a session cookie
+async fn issue_session_cookie(
+    req: &HttpRequest,
+    db: &DbData,
+    authority: &auth::Authority,
+    user: db_models::User,
+) -> ApiResult<Cookie<'static>> {
+    let now = Utc::now();
+    let ip = ClientIp::of(req).0.map(|ip| ip.to_string());
+    let login_ = db
+        .send(db::CreateLogin {
+            span: Span::current(),
+            login: db_models::NewLogin {
+                jti: auth::new_token_id(),
+                user_id: user.id,
+                user_agent: req
+                    .headers()
+                    .get(header::USER_AGENT)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string()),
+                ip,
+                created_at: now.naive_utc(),
+                expires_at: (now + authority.max_session_age()).naive_utc(),
+            },
         })
         .await??;
 
-    let mut cookie: Cookie = authority
-        .create_signed_cookie(user.into())
+    let cookie = authority
+        .create_signed_cookie(UserClaims::new(user, &login_))
         .map_err(|e| anyhow!("Cookie creation failed: {:?}", e))?;
 
-    cookie_config(&mut cookie);
+    Ok(cookie)
+}
 
-    Ok(HttpResponse::build(StatusCode::OK)
-        .cookie(cookie)
-        .json(HashMap::<(), ()>::new()))
+#[post("/login")]
+async fn login(
+    req: HttpRequest,
+    db: DbData,
+    authority: web::Data<auth::Authority>,
+    body: web::Json<LoginRequest>,
+) -> ApiResult<HttpResponse> {
+    let body = body.into_inner();
+
+    let outcome = create_session_cookie(&req, &db, &authority, body.username, body.name).await?;
+
+    Ok(outcome.into_response())
 }
 
 #[post("/logout")]
 async fn logout(
-    req: actix_web::HttpRequest,
+    req: HttpRequest,
+    db: DbData,
     authority: web::Data<auth::Authority>,
+    token: Option<UserToken>,
 ) -> ApiResult<HttpResponse> {
-    match req.cookie(authority.cookie_name) {
-        Some(mut cookie) => {
-            cookie_config(&mut cookie);
-            cookie.make_removal();
-            Ok(HttpResponse::build(StatusCode::OK)
-                .cookie(cookie)
-                .json(HashMap::<(), ()>::new()))
-        }
+    if let Some(UserToken { claims: user, .. }) = token {
+        db.send(db::RevokeLogins {
+            span: Span::current(),
+            user_id: user.user_id,
+            jti: Some(user.jti),
+        })
+        .await??;
+    }
+
+    match req.cookie(&authority.cookie_name) {
+        Some(_) => Ok(HttpResponse::build(StatusCode::OK)
+            .cookie(authority.create_removal_cookie())
+            .json(HashMap::<(), ()>::new())),
         None => Ok(HttpResponse::Ok().json(HashMap::<(), ()>::new())),
     }
 }
 
 #[get("/me")]
-async fn me(user: UserClaims) -> ApiResult<web::Json<models::User>> {
-    Ok(web::Json(models::User {
+async fn me(
+    token: UserToken,
+    authority: web::Data<auth::Authority>,
+) -> ApiResult<web::Json<models::Me>> {
+    let user = token.claims;
+    Ok(web::Json(models::Me {
+        login_expires_at: user.auth_time + authority.max_session_age(),
         username: user.username,
         name: Some(user.name),
+        role: user.role,
+        token_issued_at: token.issued_at,
+        token_expires_at: token.expires_at,
     }))
 }
 
@@ -105,32 +234,112 @@ async fn not_found() -> HttpResponse {
     HttpResponse::NotFound().body("Api route handler not found")
 }
 
-pub fn configure(config: Config, keys: AuthKeys) -> Result<impl Fn(&mut ServiceConfig) + Clone> {
-    let auth = auth::configure(keys)?;
+/// Names of the ways to log in enabled by the config
+pub fn auth_providers(config: &Config) -> Vec<&'static str> {
+    let mut providers = Vec::new();
+    if config.dev_mode {
+        providers.push("test login (dev mode)");
+    }
+    if config.oidc.is_some() {
+        providers.push("OpenID Connect");
+    }
+    if config.saml.is_some() {
+        providers.push("SAML");
+    }
+    if config.ldap.is_some() {
+        providers.push("LDAP");
+    }
+    if config.webauthn.is_some() {
+        providers.push("WebAuthn passkeys");
+    }
+    if config.local_accounts.is_some() {
+        providers.push("local accounts");
+    }
+    if config.magic_link.is_some() {
+        providers.push("magic links by email");
+    }
+    providers
+}
+
+pub fn configure(
+    config: Config,
+    authority: web::Data<Authority>,
+) -> Result<impl Fn(&mut ServiceConfig) + Clone> {
+    let auth = auth::configure(authority);
+    let saml = saml::configure(config.saml.clone())?;
+    let webauthn = webauthn::configure(config.webauthn.clone())?;
+    let totp = totp::configure(config.totp.clone())?;
+    let local_accounts = local_accounts::configure(config.local_accounts.clone())?;
+    let mailer = config
+        .mail
+        .clone()
+        .map(|mail| Mailer::new(mail).map(web::Data::new))
+        .transpose()
+        .context("Setting up the mailer")?;
+    let magic_link = magic_link::configure(config.magic_link.clone(), mailer)?;
+    let trusted_proxies = web::Data::new(TrustedProxies(config.server.trusted_proxies.clone()));
 
     Ok(move |cfg: &mut ServiceConfig| {
-        cfg
-            // testing
-            .service(hello)
-            .service(ping)
-            .service(echo)
-            .service(make_error)
+        if config.dev_mode {
+            cfg
+                // testing
+                .service(hello)
+                .service(echo)
+                .service(make_error)
+                // log in as anyone
+                .service(login);
+        }
+
+        cfg.app_data(trusted_proxies.clone());
+
+        cfg.service(ping)
             // sessions
             .service(sessions::get_sessions)
             .service(sessions::create_session)
             .service(sessions::get_session)
             .service(sessions::delete_session)
+            .service(sessions::pause_session)
+            .service(sessions::resume_session)
+            .service(sessions::close_session)
             .service(sessions::add_mark)
             .service(sessions::delete_mark)
+            .service(sessions::get_members)
+            .service(sessions::set_member)
+            .service(sessions::remove_member)
+            .service(mark_feed::mark_feed)
             // auth
-            .service(login)
             .service(logout)
             .service(me)
+            .service(logins::get_logins)
+            .service(logins::revoke_login)
+            .service(logins::revoke_all_logins)
+            .service(tokens::get_tokens)
+            .service(tokens::create_token)
+            .service(tokens::revoke_token)
+            // admin
+            .service(admin::get_staff)
+            .service(admin::set_role)
+            .service(admin::reset_role)
             // challenge
             .configure(challenge::configure(config.challenge.clone()))
+            .service(time::sync_time)
             // sentry tunnel
             .configure(sentry_tunnel::configure(config.sentry_tunnel.clone()))
             .configure(auth.clone())
+            // OpenID Connect
+            .configure(sso::configure(config.oidc.clone()))
+            // SAML
+            .configure(saml.clone())
+            // LDAP
+            .configure(ldap::configure(config.ldap.clone()))
+            // WebAuthn
+            .configure(webauthn.clone())
+            // second factor
+            .configure(totp.clone())
+            // passwords
+            .configure(local_accounts.clone())
+            // login links by email
+            .configure(magic_link.clone())
             .default_service(web::route().to(not_found));
     })
 }
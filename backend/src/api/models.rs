@@ -1,7 +1,14 @@
+use crate::api::mark_flags::{flag_marks, MarkFlag};
+use crate::api::ChallengeConfig;
 use crate::db::models as db_models;
-use crate::db::models::SessionId;
+use crate::db::models::{
+    ApiTokenId, ChallengeAlgorithm, GeofencePolicy, NetworkPolicy, Permission, Role, Scope,
+    SessionId, StudentVisibility, WebauthnCredentialId,
+};
 use chrono::{DateTime, TimeZone, Utc};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 
 /// Session as seen in the listing
 #[derive(Serialize, Deserialize)]
@@ -9,16 +16,42 @@ pub struct Session {
     pub id: SessionId,
     pub title: Option<String>,
     pub active: bool,
+    pub state: SessionState,
     pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
 }
 
 impl From<db_models::Session> for Session {
     fn from(db_session: db_models::Session) -> Self {
+        let state = SessionState::of(&db_session);
         Self {
             id: db_session.id,
             title: db_session.title,
             active: db_session.active,
+            state,
             start_time: Utc.from_utc_datetime(&db_session.start_time),
+            end_time: db_session
+                .end_time
+                .map(|end_time| Utc.from_utc_datetime(&end_time)),
+        }
+    }
+}
+
+/// Whether the session accepts check-ins. A closed session only takes manual marks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    Running,
+    Paused,
+    Closed,
+}
+
+impl SessionState {
+    pub fn of(session: &db_models::Session) -> Self {
+        match (session.end_time, session.active) {
+            (Some(_), _) => SessionState::Closed,
+            (None, true) => SessionState::Running,
+            (None, false) => SessionState::Paused,
         }
     }
 }
@@ -26,6 +59,40 @@ impl From<db_models::Session> for Session {
 #[derive(Serialize, Deserialize)]
 pub struct NewSession {
     pub title: Option<String>,
+    /// The default from the config if not set
+    pub challenge_algorithm: Option<ChallengeAlgorithm>,
+    /// In bytes, for HMAC-SHA256
+    pub challenge_tag_length: Option<usize>,
+    /// Overrides of the challenge timing from the config
+    pub qr_interval_ms: Option<u32>,
+    pub jitter_window_ms: Option<u32>,
+    /// Where the challenges have to come from, anywhere if not set
+    pub geofence: Option<Geofence>,
+    /// Overrides of the allowed client networks from the config, an empty list allows anywhere
+    pub allowed_networks: Option<Vec<IpNet>>,
+    pub network_policy: Option<NetworkPolicy>,
+    /// What the students are told about each other, the config decides if not set
+    pub student_visibility: Option<StudentVisibility>,
+}
+
+/// Place of a session
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Geofence {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_m: u32,
+    pub policy: GeofencePolicy,
+}
+
+impl Geofence {
+    pub fn of_session(session: &db_models::Session) -> Option<Self> {
+        Some(Self {
+            latitude: session.latitude?,
+            longitude: session.longitude?,
+            radius_m: session.radius_m? as u32,
+            policy: session.geofence_policy?,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -44,27 +111,70 @@ pub struct SessionWithMarks {
     pub id: SessionId,
     pub title: Option<String>,
     pub active: bool,
+    pub state: SessionState,
     pub start_time: DateTime<Utc>,
-    pub seed: String,
+    pub end_time: Option<DateTime<Utc>>,
+    /// Only for the members who can mark, as anyone with it can compute valid challenges
+    pub seed: Option<String>,
+    /// How the display should sign the challenges
+    pub challenge_algorithm: ChallengeAlgorithm,
+    pub challenge_tag_length: usize,
+    /// How often the display should change the challenge
+    pub qr_interval_ms: u64,
+    /// How late or early the challenges are still accepted
+    pub jitter_window_ms: u64,
+    pub geofence: Option<Geofence>,
+    /// Networks the challenges have to come from, anywhere if empty
+    pub allowed_networks: Vec<IpNet>,
+    pub network_policy: NetworkPolicy,
+    pub student_visibility: StudentVisibility,
     pub attendance_marks: Vec<AttendanceMark>,
+    /// Number of the marks with flags
+    pub flagged_marks: usize,
 }
 
-impl From<db_models::SessionWithMarks> for SessionWithMarks {
-    fn from((session, marks, users): db_models::SessionWithMarks) -> Self {
+impl SessionWithMarks {
+    pub fn new(
+        (session, marks, users): db_models::SessionWithMarks,
+        permission: Permission,
+        config: &ChallengeConfig,
+    ) -> Self {
+        let config = config.for_session(&session);
+        let geofence = Geofence::of_session(&session);
+        let state = SessionState::of(&session);
+        let mut flags = flag_marks(
+            marks.values(),
+            config.shared_code_threshold,
+            &config.allowed_networks,
+        );
+        let flagged_marks = flags.len();
         Self {
             id: session.id,
             title: session.title,
             active: session.active,
+            state,
             start_time: Utc.from_utc_datetime(&session.start_time),
-            seed: session.seed,
+            end_time: session
+                .end_time
+                .map(|end_time| Utc.from_utc_datetime(&end_time)),
+            seed: (permission >= Permission::Marker).then_some(session.seed),
+            challenge_algorithm: session.challenge_algorithm,
+            challenge_tag_length: session.challenge_tag_length as usize,
+            qr_interval_ms: config.qr_interval.as_millis() as u64,
+            jitter_window_ms: config.jitter_window.as_millis() as u64,
+            geofence,
+            allowed_networks: config.allowed_networks,
+            network_policy: config.network_policy,
+            student_visibility: config.student_visibility,
             attendance_marks: marks
                 .into_iter()
-                .map(|(_, mark)| AttendanceMark {
-                    username: users.get(&mark.user_id).unwrap().username.clone(),
-                    mark_time: Utc.from_utc_datetime(&mark.mark_time),
-                    is_manual: mark.is_manual,
+                .map(|(_, mark)| {
+                    let username = users.get(&mark.user_id).unwrap().username.clone();
+                    let flags = flags.remove(&mark.id).unwrap_or_default();
+                    AttendanceMark::new(mark, username, flags)
                 })
                 .collect(),
+            flagged_marks,
         }
     }
 }
@@ -74,6 +184,34 @@ pub struct AttendanceMark {
     pub username: String,
     pub mark_time: DateTime<Utc>,
     pub is_manual: bool,
+    /// Who added the mark after the session was closed
+    pub note: Option<String>,
+    /// Why the mark looks suspicious, for the teacher to review
+    pub flags: Vec<MarkFlag>,
+}
+
+impl AttendanceMark {
+    pub fn new(mark: db_models::AttendanceMark, username: String, flags: Vec<MarkFlag>) -> Self {
+        Self {
+            username,
+            mark_time: Utc.from_utc_datetime(&mark.mark_time),
+            is_manual: mark.is_manual,
+            note: mark.note,
+            flags,
+        }
+    }
+}
+
+/// Where to resume the attendance feed from, the `Last-Event-ID` header works too
+#[derive(Serialize, Deserialize)]
+pub struct MarkFeedQuery {
+    pub cursor: Option<i64>,
+}
+
+/// The mark of the student was deleted
+#[derive(Serialize, Deserialize)]
+pub struct MarkRemoved {
+    pub username: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -82,14 +220,48 @@ pub struct AttendanceMarkRef {
     pub username: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SessionMember {
+    pub username: String,
+    pub name: Option<String>,
+    pub permission: Permission,
+}
+
+impl From<(db_models::SessionMember, db_models::User)> for SessionMember {
+    fn from((member, user): (db_models::SessionMember, db_models::User)) -> Self {
+        Self {
+            username: user.username,
+            name: user.name,
+            permission: member.permission,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionMemberRef {
+    pub session_id: SessionId,
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetPermission {
+    pub permission: Permission,
+}
+
 /// This is a login request used only for testing
-/// It should not be available in production
+/// It is only available with `dev_mode` enabled
 #[derive(Serialize, Deserialize)]
 pub struct LoginRequest {
     pub username: String,
     pub name: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct PasswordLoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct User {
     pub username: String,
@@ -105,16 +277,332 @@ impl From<db_models::User> for User {
     }
 }
 
+/// User as seen by the admins
+#[derive(Serialize, Deserialize)]
+pub struct UserWithRole {
+    pub username: String,
+    pub name: Option<String>,
+    pub role: Role,
+}
+
+impl From<db_models::User> for UserWithRole {
+    fn from(db_user: db_models::User) -> Self {
+        Self {
+            username: db_user.username,
+            name: db_user.name,
+            role: db_user.role,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserRef {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetRole {
+    pub role: Role,
+}
+
+/// The logged in user, with the lifetime of their session
+#[derive(Serialize, Deserialize)]
+pub struct Me {
+    pub username: String,
+    pub name: Option<String>,
+    pub role: Role,
+    /// Lifetime of the current session token, it is renewed while the user is active
+    pub token_issued_at: DateTime<Utc>,
+    pub token_expires_at: DateTime<Utc>,
+    /// When the user will have to log in again regardless of their activity
+    pub login_expires_at: DateTime<Utc>,
+}
+
+/// One of the places the user is logged in from
+#[derive(Serialize, Deserialize)]
+pub struct Login {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub issued_at: DateTime<Utc>,
+    /// Whether this is the login making the request
+    pub current: bool,
+}
+
+impl Login {
+    pub fn new(db_login: db_models::Login, current_jti: &str) -> Self {
+        Self {
+            current: db_login.jti == current_jti,
+            id: db_login.jti,
+            user_agent: db_login.user_agent,
+            ip: db_login.ip,
+            issued_at: Utc.from_utc_datetime(&db_login.created_at),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginRef {
+    pub login_id: String,
+}
+
+/// A WebAuthn credential of the user
+#[derive(Serialize, Deserialize)]
+pub struct Passkey {
+    pub id: WebauthnCredentialId,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<db_models::WebauthnCredential> for Passkey {
+    fn from(db_credential: db_models::WebauthnCredential) -> Self {
+        Self {
+            id: db_credential.id,
+            name: db_credential.name,
+            created_at: Utc.from_utc_datetime(&db_credential.created_at),
+            last_used_at: db_credential
+                .last_used_at
+                .map(|t| Utc.from_utc_datetime(&t)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasskeyRef {
+    pub passkey_id: WebauthnCredentialId,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: ApiTokenId,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<db_models::ApiToken> for ApiToken {
+    fn from(db_token: db_models::ApiToken) -> Self {
+        Self {
+            id: db_token.id,
+            name: db_token.name,
+            scopes: db_token.scopes,
+            created_at: Utc.from_utc_datetime(&db_token.created_at),
+            expires_at: db_token.expires_at.map(|t| Utc.from_utc_datetime(&t)),
+            last_used_at: db_token.last_used_at.map(|t| Utc.from_utc_datetime(&t)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// The token never expires if not set
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A freshly created API token. This is the only time the token itself is shown
+#[derive(Serialize, Deserialize)]
+pub struct CreatedApiToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiToken,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiTokenRef {
+    pub token_id: ApiTokenId,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    /// Base32-encoded, for entering manually
+    pub secret: String,
+    /// `otpauth://` URI, for showing as a QR code
+    pub uri: String,
+}
+
+/// Answer to a login request
+#[derive(Serialize, Deserialize)]
+pub struct LoginResult {
+    /// The user has to post a code to `/api/totp/verify` before they are logged in
+    pub second_factor_required: bool,
+}
+
+/// A code from the authenticator app or a recovery code
+#[derive(Serialize, Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+/// Single-use codes for when the authenticator app is lost. This is the only time they are shown
+#[derive(Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// An invite for a new local account, or an existing user without a password
+#[derive(Serialize, Deserialize)]
+pub struct NewInvite {
+    pub username: String,
+    pub name: String,
+    /// Students by default
+    pub role: Option<Role>,
+}
+
+/// A single-use token for setting the password. This is the only time it is shown
+#[derive(Serialize, Deserialize)]
+pub struct PasswordToken {
+    pub username: String,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetPasswordWithToken {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLinkToken {
+    pub token: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Challenge {
     /// Base-64 encoded challenge
     pub challenge: String,
+    /// Position of the device, needed for the sessions with a geofence
+    #[serde(default)]
+    pub location: Option<Location>,
+}
+
+/// Position of the device, as reported by the browser
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Radius of the uncertainty
+    pub accuracy_m: f64,
+}
+
+/// Clock synchronization request of a QR code display
+#[derive(Serialize, Deserialize)]
+pub struct TimeRequest {
+    /// Time of the display when sending the request, in ms since the epoch
+    pub client_time: i64,
+    /// The session shown, for its jitter window
+    pub session_id: Option<SessionId>,
+    /// What the display made of the previous exchange
+    pub last_estimate: Option<ClockEstimate>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ClockEstimate {
+    /// How far the display clock is ahead of the server one
+    pub offset_ms: i64,
+    pub rtt_ms: i64,
+}
+
+/// The times are in ms since the epoch. The display, receiving this at its time `t`, gets
+///   `offset = ((client_time - receive_time) + (t - transmit_time)) / 2` and
+///   `rtt = (t - client_time) - (transmit_time - receive_time)`
+#[derive(Serialize, Deserialize)]
+pub struct TimeResponse {
+    pub client_time: i64,
+    pub receive_time: i64,
+    pub transmit_time: i64,
+    /// Of the session if given, the codes are accepted this much early or late
+    pub jitter_window_ms: u64,
+    /// The last estimate is too close to the jitter window
+    pub skew_warning: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "result")]
 pub enum ChallengeResult {
-    Success { other_students: Vec<User> },
+    Success {
+        /// The latest other students marked in the session, if the session shows the names
+        other_students: Vec<User>,
+        /// All of them, unless the session hides even that
+        other_students_count: Option<u64>,
+    },
+    /// The challenge could not be parsed
     Invalid,
-    Failed,
+    /// The reason is left out for the untrusted clients if the config says so
+    Failed {
+        #[serde(flatten)]
+        reason: Option<ChallengeFailure>,
+    },
+}
+
+/// Why a challenge was rejected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum ChallengeFailure {
+    UnknownSession,
+    /// The teacher paused the check-in, it may be resumed later
+    SessionPaused,
+    SessionClosed,
+    /// The session uses another challenge format
+    WrongFormat,
+    BadSignature,
+    /// The code was submitted this long before it was shown
+    TooEarly {
+        early_ms: i64,
+    },
+    /// The code was submitted this long after it left the screen
+    Expired {
+        late_ms: i64,
+    },
+    /// The session only allows some networks, and this one is not one of them
+    NetworkNotAllowed,
+    /// The session has a geofence, but the location was missing or too imprecise
+    LocationRequired,
+    OutsideGeofence {
+        distance_m: u32,
+    },
+}
+
+impl Display for ChallengeFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ChallengeFailure {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::challenge::test::{session, test_config};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_seed_only_for_markers() {
+        let config = test_config();
+        let with_marks = || (session(Utc::now(), b"seed"), HashMap::new(), HashMap::new());
+
+        let viewed = SessionWithMarks::new(with_marks(), Permission::Viewer, &config);
+        assert_eq!(viewed.seed, None);
+        let marked = SessionWithMarks::new(with_marks(), Permission::Marker, &config);
+        assert_eq!(marked.seed, Some(base64::encode(b"seed")));
+        let owned = SessionWithMarks::new(with_marks(), Permission::Owner, &config);
+        assert!(owned.seed.is_some());
+    }
 }
@@ -0,0 +1,703 @@
+//! SAML 2.0 service provider login (SP-initiated, HTTP-Redirect binding for the requests,
+//! HTTP-POST binding for the responses)
+
+mod xml;
+
+use crate::api::auth::{Authority, TokenClaims};
+use crate::api::error::{ApiError, ApiResult};
+use crate::db::DbData;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::web::ServiceConfig;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use openssl::pkey::{PKey, Public};
+use openssl::x509::X509;
+use quick_xml::escape::escape;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+use tracing::{info, warn};
+use url::Url;
+use xml::Element;
+
+const FLOW_COOKIE_NAME: &str = "saml_flow";
+/// How long the user has to complete the login at the identity provider
+const FLOW_DURATION_MINUTES: i64 = 10;
+
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const METADATA_NS: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BINDING_HTTP_POST: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+const SUBJECT_CONFIRMATION_BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Our entity ID, usually the URL of the metadata endpoint (`/api/saml/metadata`)
+    pub entity_id: String,
+    /// Should point to the `/api/saml/acs` endpoint
+    pub acs_url: Url,
+    pub idp_entity_id: String,
+    /// Single sign-on endpoint of the identity provider (HTTP-Redirect binding)
+    pub idp_sso_url: Url,
+    /// PEM-encoded signing certificates of the identity provider
+    ///
+    /// More than one can be specified to handle certificate rollover.
+    pub idp_certificates: Vec<String>,
+    #[serde(default)]
+    pub attributes: AttributeMapping,
+    /// How much the identity provider clock is allowed to differ from ours
+    #[serde(with = "humantime_serde", default = "default_clock_skew")]
+    pub clock_skew: Duration,
+    /// Where to send the user after a successful login
+    #[serde(default = "default_post_login_redirect")]
+    pub post_login_redirect: String,
+}
+
+/// Which assertion attributes are used to fill in the user
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AttributeMapping {
+    /// Attribute used as `users.username`, subject `NameID` is used when not set
+    pub username: Option<String>,
+    /// Attribute used as `users.name`, the username is used when not set or not supplied
+    pub name: Option<String>,
+}
+
+fn default_clock_skew() -> Duration {
+    Duration::from_secs(60)
+}
+fn default_post_login_redirect() -> String {
+    "/".to_string()
+}
+
+#[derive(Debug)]
+pub enum SamlError {
+    NoFlowCookie,
+    InvalidFlow,
+    InvalidResponse(String),
+}
+
+impl ApiError for SamlError {
+    fn to_http(&self) -> (StatusCode, String) {
+        match self {
+            SamlError::NoFlowCookie | SamlError::InvalidFlow => (
+                StatusCode::BAD_REQUEST,
+                "Login flow was not started or has expired, please try again".to_string(),
+            ),
+            SamlError::InvalidResponse(e) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Identity provider response is not valid: {}", e),
+            ),
+        }
+    }
+}
+
+/// Login flow state, stored in a signed cookie between the redirect to the provider and the response
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FlowState {
+    request_id: String,
+}
+
+impl TokenClaims for FlowState {
+    const PURPOSE: &'static str = "saml_flow";
+}
+
+#[derive(Deserialize, Debug)]
+struct AcsForm {
+    #[serde(rename = "SAMLResponse")]
+    saml_response: String,
+}
+
+/// Information extracted from a valid assertion
+#[derive(Debug)]
+struct AuthenticatedSubject {
+    name_id: String,
+    attributes: HashMap<String, Vec<String>>,
+}
+
+impl AuthenticatedSubject {
+    fn attribute(&self, name: &str) -> Option<String> {
+        self.attributes
+            .get(name)
+            .and_then(|values| values.first())
+            .cloned()
+    }
+}
+
+struct Data {
+    config: Config,
+    keys: Vec<PKey<Public>>,
+}
+
+fn load_keys(config: &Config) -> Result<Vec<PKey<Public>>> {
+    if config.idp_certificates.is_empty() {
+        bail!("No identity provider certificates configured");
+    }
+    config
+        .idp_certificates
+        .iter()
+        .map(|pem| {
+            Ok(X509::from_pem(pem.as_bytes())
+                .context("Parsing identity provider certificate")?
+                .public_key()?)
+        })
+        .collect()
+}
+
+fn new_request_id() -> String {
+    use rand::{rngs::OsRng, RngCore};
+
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    // xsd:ID must not start with a digit
+    format!(
+        "_{}",
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    )
+}
+
+fn format_instant(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn parse_instant(time: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(time)
+        .with_context(|| format!("Invalid time {:?}", time))?
+        .with_timezone(&Utc))
+}
+
+fn metadata_xml(config: &Config) -> String {
+    format!(
+        concat!(
+            r#"<md:EntityDescriptor xmlns:md="{md}" entityID="{entity_id}">"#,
+            r#"<md:SPSSODescriptor AuthnRequestsSigned="false" WantAssertionsSigned="true" "#,
+            r#"protocolSupportEnumeration="{protocol}">"#,
+            r#"<md:NameIDFormat>urn:oasis:names:tc:SAML:1.1:nameid-format:unspecified</md:NameIDFormat>"#,
+            r#"<md:AssertionConsumerService Binding="{binding}" Location="{acs_url}" index="0" isDefault="true"/>"#,
+            r#"</md:SPSSODescriptor>"#,
+            r#"</md:EntityDescriptor>"#,
+        ),
+        md = METADATA_NS,
+        entity_id = escape(&config.entity_id),
+        protocol = PROTOCOL_NS,
+        binding = BINDING_HTTP_POST,
+        acs_url = escape(config.acs_url.as_str()),
+    )
+}
+
+fn authn_request_xml(config: &Config, request_id: &str, now: DateTime<Utc>) -> String {
+    format!(
+        concat!(
+            r#"<samlp:AuthnRequest xmlns:samlp="{samlp}" xmlns:saml="{saml}" ID="{id}" Version="2.0" "#,
+            r#"IssueInstant="{instant}" Destination="{destination}" "#,
+            r#"AssertionConsumerServiceURL="{acs_url}" ProtocolBinding="{binding}">"#,
+            r#"<saml:Issuer>{entity_id}</saml:Issuer>"#,
+            r#"<samlp:NameIDPolicy AllowCreate="true"/>"#,
+            r#"</samlp:AuthnRequest>"#,
+        ),
+        samlp = PROTOCOL_NS,
+        saml = ASSERTION_NS,
+        id = request_id,
+        instant = format_instant(now),
+        destination = escape(config.idp_sso_url.as_str()),
+        acs_url = escape(config.acs_url.as_str()),
+        binding = BINDING_HTTP_POST,
+        entity_id = escape(&config.entity_id),
+    )
+}
+
+/// Encode the request for the HTTP-Redirect binding and build the URL to send the user to
+fn authn_request_url(config: &Config, request_id: &str, now: DateTime<Utc>) -> Result<Url> {
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(authn_request_xml(config, request_id, now).as_bytes())?;
+    let request = base64::encode(encoder.finish()?);
+
+    let mut url = config.idp_sso_url.clone();
+    url.query_pairs_mut().append_pair("SAMLRequest", &request);
+    Ok(url)
+}
+
+/// Check the `NotBefore`/`NotOnOrAfter` pair of attributes, as found on several SAML elements
+fn check_validity_window(
+    element: &Element,
+    now: DateTime<Utc>,
+    clock_skew: chrono::Duration,
+) -> Result<()> {
+    if let Some(not_before) = element.attribute("NotBefore") {
+        if now + clock_skew < parse_instant(not_before)? {
+            bail!("{} is not valid yet", element.name);
+        }
+    }
+    if let Some(not_on_or_after) = element.attribute("NotOnOrAfter") {
+        if now - clock_skew >= parse_instant(not_on_or_after)? {
+            bail!("{} has expired", element.name);
+        }
+    }
+    Ok(())
+}
+
+/// Validate the SAML response and the assertion in it, returning the authenticated subject
+///
+/// Everything is read only from the elements covered by a valid signature, so that
+///   unsigned data injected into the response can't be used.
+fn validate_response(
+    response_xml: &str,
+    config: &Config,
+    keys: &[PKey<Public>],
+    request_id: &str,
+    now: DateTime<Utc>,
+) -> Result<AuthenticatedSubject> {
+    let clock_skew = chrono::Duration::from_std(config.clock_skew)?;
+    let document = Element::parse(response_xml)?;
+    let response = &document;
+    if !response.is(PROTOCOL_NS, "Response") {
+        bail!("Expected a Response, got {}", response.name);
+    }
+
+    let status_code = response
+        .single_child(PROTOCOL_NS, "Status")?
+        .single_child(PROTOCOL_NS, "StatusCode")?
+        .attribute("Value");
+    if status_code != Some(STATUS_SUCCESS) {
+        bail!("Authentication failed with status {:?}", status_code);
+    }
+
+    if response.attribute("InResponseTo") != Some(request_id) {
+        bail!("Response is not for our request");
+    }
+    if let Some(destination) = response.attribute("Destination") {
+        if destination != config.acs_url.as_str() {
+            bail!("Response is intended for {:?}", destination);
+        }
+    }
+    if response.child(ASSERTION_NS, "EncryptedAssertion").is_some() {
+        bail!("Encrypted assertions are not supported");
+    }
+
+    let assertion = response.single_child(ASSERTION_NS, "Assertion")?;
+
+    // either the whole response or the assertion itself must be signed
+    let response_signature = response
+        .child(xml::DSIG_NS, "Signature")
+        .map(|_| response.verify_signature(&document, keys))
+        .transpose()
+        .context("Checking response signature")?;
+    let assertion_signature = assertion
+        .child(xml::DSIG_NS, "Signature")
+        .map(|_| assertion.verify_signature(&document, keys))
+        .transpose()
+        .context("Checking assertion signature")?;
+    if response_signature.is_none() && assertion_signature.is_none() {
+        bail!("Neither the response nor the assertion are signed");
+    }
+
+    let issuer = assertion.single_child(ASSERTION_NS, "Issuer")?.text();
+    if issuer.trim() != config.idp_entity_id {
+        bail!(
+            "Assertion is issued by an unknown identity provider {:?}",
+            issuer
+        );
+    }
+
+    let conditions = assertion.single_child(ASSERTION_NS, "Conditions")?;
+    check_validity_window(conditions, now, clock_skew)?;
+    for restriction in conditions.children(ASSERTION_NS, "AudienceRestriction") {
+        if !restriction
+            .children(ASSERTION_NS, "Audience")
+            .any(|audience| audience.text().trim() == config.entity_id)
+        {
+            bail!("Assertion is intended for a different audience");
+        }
+    }
+
+    let subject = assertion.single_child(ASSERTION_NS, "Subject")?;
+    let name_id = subject.single_child(ASSERTION_NS, "NameID")?.text();
+    let confirmed = subject
+        .children(ASSERTION_NS, "SubjectConfirmation")
+        .filter(|c| c.attribute("Method") == Some(SUBJECT_CONFIRMATION_BEARER))
+        .filter_map(|c| c.child(ASSERTION_NS, "SubjectConfirmationData"))
+        .any(|data| {
+            data.attribute("Recipient") == Some(config.acs_url.as_str())
+                && data.attribute("InResponseTo") == Some(request_id)
+                && data.attribute("NotOnOrAfter").is_some()
+                && check_validity_window(data, now, clock_skew).is_ok()
+        });
+    if !confirmed {
+        bail!("Subject confirmation failed");
+    }
+
+    let mut attributes = HashMap::<String, Vec<String>>::new();
+    for statement in assertion.children(ASSERTION_NS, "AttributeStatement") {
+        for attribute in statement.children(ASSERTION_NS, "Attribute") {
+            if let Some(name) = attribute.attribute("Name") {
+                attributes.entry(name.to_string()).or_default().extend(
+                    attribute
+                        .children(ASSERTION_NS, "AttributeValue")
+                        .map(|v| v.text().trim().to_string()),
+                );
+            }
+        }
+    }
+
+    Ok(AuthenticatedSubject {
+        name_id: name_id.trim().to_string(),
+        attributes,
+    })
+}
+
+fn flow_cookie(authority: &Authority, value: String) -> Cookie<'static> {
+    Cookie::build(FLOW_COOKIE_NAME, value)
+        .path("/api/saml")
+        .secure(authority.cookie_secure())
+        .http_only(true)
+        // the response is a cross-site POST from the identity provider, so Lax would not work.
+        //   The browsers drop the cookies with None that are not secure, so only a local provider works over HTTP
+        .same_site(match authority.cookie_secure() {
+            true => SameSite::None,
+            false => SameSite::Lax,
+        })
+        .finish()
+}
+
+#[get("/saml/metadata")]
+async fn metadata(data: web::Data<Data>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/samlmetadata+xml")
+        .body(metadata_xml(&data.config))
+}
+
+#[get("/saml/login")]
+async fn login(data: web::Data<Data>, authority: web::Data<Authority>) -> ApiResult<HttpResponse> {
+    let request_id = new_request_id();
+    let url = authn_request_url(&data.config, &request_id, Utc::now())?;
+
+    let (flow_token, _) = authority.sign_token(
+        FlowState { request_id },
+        chrono::Duration::minutes(FLOW_DURATION_MINUTES),
+    )?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url.as_str()))
+        .cookie(flow_cookie(&authority, flow_token))
+        .finish())
+}
+
+/// Assertion consumer service
+#[post("/saml/acs")]
+async fn acs(
+    req: HttpRequest,
+    db: DbData,
+    data: web::Data<Data>,
+    authority: web::Data<Authority>,
+    form: web::Form<AcsForm>,
+) -> ApiResult<HttpResponse> {
+    let flow = req
+        .cookie(FLOW_COOKIE_NAME)
+        .ok_or(SamlError::NoFlowCookie)?;
+    let flow = authority
+        .validate_token::<FlowState>(flow.value())
+        .map_err(|e| {
+            warn!("Could not validate the login flow cookie: {:?}", e);
+            SamlError::InvalidFlow
+        })?
+        .custom;
+
+    let response = base64::decode(
+        form.saml_response
+            .chars()
+            .filter(|c| !c.is_ascii_whitespace())
+            .collect::<String>(),
+    )
+    .map_err(|e| anyhow!("Could not decode SAMLResponse: {}", e))?;
+    let response = String::from_utf8(response).context("SAMLResponse is not valid UTF-8")?;
+
+    let subject = validate_response(
+        &response,
+        &data.config,
+        &data.keys,
+        &flow.request_id,
+        Utc::now(),
+    )
+    .map_err(|e| {
+        warn!("Rejected SAML response: {:?}", e);
+        SamlError::InvalidResponse(e.to_string())
+    })?;
+
+    let mapping = &data.config.attributes;
+    let username = match &mapping.username {
+        Some(attribute) => subject.attribute(attribute).ok_or_else(|| {
+            SamlError::InvalidResponse(format!("missing the {:?} attribute", attribute))
+        })?,
+        None => subject.name_id.clone(),
+    };
+    let name = mapping
+        .name
+        .as_ref()
+        .and_then(|attribute| subject.attribute(attribute))
+        .unwrap_or_else(|| username.clone());
+
+    info!("User {:?} logged in via SAML", username);
+
+    let outcome = super::create_session_cookie(&req, &db, &authority, username, name).await?;
+
+    let mut flow_removal = flow_cookie(&authority, String::new());
+    flow_removal.make_removal();
+
+    // 303 makes the browser follow the redirect with a GET
+    Ok(outcome
+        .into_redirect(StatusCode::SEE_OTHER, &data.config.post_login_redirect)
+        .cookie(flow_removal)
+        .finish())
+}
+
+pub fn configure(config: Option<Config>) -> Result<impl Fn(&mut ServiceConfig) + Clone> {
+    let keys = config.as_ref().map(load_keys).transpose()?;
+
+    Ok(move |cfg: &mut ServiceConfig| {
+        if let (Some(config), Some(keys)) = (&config, &keys) {
+            cfg.app_data(web::Data::new(Data {
+                config: config.clone(),
+                keys: keys.clone(),
+            }))
+            .service(metadata)
+            .service(login)
+            .service(acs);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+
+    const REQUEST_ID: &str = "_0123456789abcdef";
+
+    fn config() -> Config {
+        Config {
+            entity_id: "https://baam.test/api/saml/metadata".to_string(),
+            acs_url: Url::parse("https://baam.test/api/saml/acs").unwrap(),
+            idp_entity_id: "https://idp.test/".to_string(),
+            idp_sso_url: Url::parse("https://idp.test/sso").unwrap(),
+            idp_certificates: vec![],
+            attributes: AttributeMapping {
+                username: Some("uid".to_string()),
+                name: Some("displayName".to_string()),
+            },
+            clock_skew: default_clock_skew(),
+            post_login_redirect: default_post_login_redirect(),
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        parse_instant("2022-11-20T12:00:00Z").unwrap()
+    }
+
+    fn generate_key() -> (PKey<Private>, PKey<Public>) {
+        let private = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public = PKey::public_key_from_pem(&private.public_key_to_pem().unwrap()).unwrap();
+        (private, public)
+    }
+
+    fn assertion(name_id: &str, audience: &str) -> String {
+        format!(
+            concat!(
+                r#"<saml:Assertion xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" "#,
+                r#"xmlns:xs="http://www.w3.org/2001/XMLSchema" "#,
+                r#"xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" ID="_assertion" "#,
+                r#"Version="2.0" IssueInstant="2022-11-20T11:59:59Z">"#,
+                r#"<saml:Issuer>https://idp.test/</saml:Issuer>"#,
+                r#"<saml:Subject><saml:NameID>{name_id}</saml:NameID>"#,
+                r#"<saml:SubjectConfirmation Method="urn:oasis:names:tc:SAML:2.0:cm:bearer">"#,
+                r#"<saml:SubjectConfirmationData InResponseTo="{request_id}" "#,
+                r#"NotOnOrAfter="2022-11-20T12:05:00Z" Recipient="https://baam.test/api/saml/acs"/>"#,
+                r#"</saml:SubjectConfirmation></saml:Subject>"#,
+                r#"<saml:Conditions NotBefore="2022-11-20T11:59:00Z" NotOnOrAfter="2022-11-20T12:05:00Z">"#,
+                r#"<saml:AudienceRestriction><saml:Audience>{audience}</saml:Audience>"#,
+                r#"</saml:AudienceRestriction></saml:Conditions>"#,
+                "<saml:AttributeStatement>\n",
+                r#"  <saml:Attribute Name="uid"><saml:AttributeValue xsi:type="xs:string">{name_id}</saml:AttributeValue></saml:Attribute>"#,
+                "\n",
+                r#"  <saml:Attribute Name="displayName"><saml:AttributeValue xsi:type="xs:string">Student &amp; Co</saml:AttributeValue></saml:Attribute>"#,
+                "\n</saml:AttributeStatement>",
+                r#"</saml:Assertion>"#,
+            ),
+            name_id = name_id,
+            audience = audience,
+            request_id = REQUEST_ID,
+        )
+    }
+
+    fn response(assertion: &str) -> String {
+        format!(
+            concat!(
+                r#"<samlp:Response xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" ID="_response" "#,
+                r#"Version="2.0" IssueInstant="2022-11-20T11:59:59Z" "#,
+                r#"Destination="https://baam.test/api/saml/acs" InResponseTo="{request_id}">"#,
+                r#"<samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"/></samlp:Status>"#,
+                "{assertion}",
+                r#"</samlp:Response>"#,
+            ),
+            request_id = REQUEST_ID,
+            assertion = assertion,
+        )
+    }
+
+    /// Sign the assertion like an identity provider would, putting the signature after the issuer
+    fn sign_assertion(assertion: &str, key: &PKey<Private>) -> String {
+        // the assertion is canonicalized in the context of the response, as it would be when validating
+        let document = Element::parse(&response(assertion)).unwrap();
+        let element = document.child(ASSERTION_NS, "Assertion").unwrap();
+        let prefixes = vec!["xs".to_string()];
+        let digest = openssl::hash::hash(
+            MessageDigest::sha256(),
+            element.canonicalize(&prefixes, None).as_bytes(),
+        )
+        .unwrap();
+
+        let signed_info = format!(
+            concat!(
+                r#"<ds:SignedInfo>"#,
+                r#"<ds:CanonicalizationMethod Algorithm="http://www.w3.org/2001/10/xml-exc-c14n#"/>"#,
+                r#"<ds:SignatureMethod Algorithm="http://www.w3.org/2001/04/xmldsig-more#rsa-sha256"/>"#,
+                r##"<ds:Reference URI="#_assertion"><ds:Transforms>"##,
+                r#"<ds:Transform Algorithm="http://www.w3.org/2000/09/xmldsig#enveloped-signature"/>"#,
+                r#"<ds:Transform Algorithm="http://www.w3.org/2001/10/xml-exc-c14n#">"#,
+                r#"<ec:InclusiveNamespaces xmlns:ec="http://www.w3.org/2001/10/xml-exc-c14n#" PrefixList="xs"/>"#,
+                r#"</ds:Transform></ds:Transforms>"#,
+                r#"<ds:DigestMethod Algorithm="http://www.w3.org/2001/04/xmlenc#sha256"/>"#,
+                r#"<ds:DigestValue>{digest}</ds:DigestValue>"#,
+                r#"</ds:Reference></ds:SignedInfo>"#,
+            ),
+            digest = base64::encode(digest),
+        );
+        let signature = format!(
+            r#"<ds:Signature xmlns:ds="http://www.w3.org/2000/09/xmldsig#">{}</ds:Signature>"#,
+            signed_info
+        );
+        let canonical_signed_info = Element::parse(&signature)
+            .unwrap()
+            .child(xml::DSIG_NS, "SignedInfo")
+            .unwrap()
+            .canonicalize(&[], None);
+
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(canonical_signed_info.as_bytes()).unwrap();
+        let signature_value = base64::encode(signer.sign_to_vec().unwrap());
+
+        let signature = format!(
+            r#"<ds:Signature xmlns:ds="http://www.w3.org/2000/09/xmldsig#">{}<ds:SignatureValue>{}</ds:SignatureValue></ds:Signature>"#,
+            signed_info, signature_value
+        );
+        assertion.replacen("</saml:Issuer>", &format!("</saml:Issuer>{}", signature), 1)
+    }
+
+    #[test]
+    fn test_validate_response() {
+        let config = config();
+        let (private, public) = generate_key();
+        let keys = vec![public];
+
+        let signed = sign_assertion(&assertion("student", &config.entity_id), &private);
+        let subject =
+            validate_response(&response(&signed), &config, &keys, REQUEST_ID, now()).unwrap();
+        assert_eq!(subject.name_id, "student");
+        assert_eq!(subject.attribute("uid").as_deref(), Some("student"));
+        assert_eq!(
+            subject.attribute("displayName").as_deref(),
+            Some("Student & Co")
+        );
+
+        // same response, but a different time
+        validate_response(
+            &response(&signed),
+            &config,
+            &keys,
+            REQUEST_ID,
+            now() + chrono::Duration::minutes(10),
+        )
+        .expect_err("Expired assertion should not be accepted");
+
+        validate_response(&response(&signed), &config, &keys, "_other", now())
+            .expect_err("Response to a different request should not be accepted");
+
+        let tampered = signed.replace(">student<", ">teacher<");
+        validate_response(&response(&tampered), &config, &keys, REQUEST_ID, now())
+            .expect_err("Tampered assertion should not be accepted");
+
+        let unsigned = assertion("student", &config.entity_id);
+        validate_response(&response(&unsigned), &config, &keys, REQUEST_ID, now())
+            .expect_err("Unsigned assertion should not be accepted");
+
+        let (other_private, _) = generate_key();
+        let other_signed = sign_assertion(&assertion("student", &config.entity_id), &other_private);
+        validate_response(&response(&other_signed), &config, &keys, REQUEST_ID, now())
+            .expect_err("Assertion signed by an unknown key should not be accepted");
+
+        let other_audience = sign_assertion(&assertion("student", "https://other.test/"), &private);
+        validate_response(
+            &response(&other_audience),
+            &config,
+            &keys,
+            REQUEST_ID,
+            now(),
+        )
+        .expect_err("Assertion for a different audience should not be accepted");
+
+        // signature wrapping: the signed assertion is hidden away and an unsigned one is used instead
+        let wrapped = response(&format!(
+            "<samlp:Extensions>{}</samlp:Extensions>{}",
+            signed,
+            assertion("teacher", &config.entity_id).replace("_assertion", "_evil")
+        ));
+        validate_response(&wrapped, &config, &keys, REQUEST_ID, now())
+            .expect_err("Wrapped assertion should not be accepted");
+    }
+
+    #[test]
+    fn test_authn_request_url() {
+        use flate2::read::DeflateDecoder;
+        use std::io::Read;
+
+        let config = config();
+        let url = authn_request_url(&config, REQUEST_ID, now()).unwrap();
+        assert_eq!(url.host_str(), Some("idp.test"));
+
+        let (_, request) = url
+            .query_pairs()
+            .find(|(name, _)| name == "SAMLRequest")
+            .unwrap();
+        let mut xml = String::new();
+        DeflateDecoder::new(base64::decode(request.as_ref()).unwrap().as_slice())
+            .read_to_string(&mut xml)
+            .unwrap();
+
+        let request = Element::parse(&xml).unwrap();
+        assert!(request.is(PROTOCOL_NS, "AuthnRequest"));
+        assert_eq!(request.attribute("ID"), Some(REQUEST_ID));
+        assert_eq!(
+            request.attribute("AssertionConsumerServiceURL"),
+            Some(config.acs_url.as_str())
+        );
+        assert_eq!(
+            request.attribute("IssueInstant"),
+            Some("2022-11-20T12:00:00Z")
+        );
+        assert_eq!(
+            request.single_child(ASSERTION_NS, "Issuer").unwrap().text(),
+            config.entity_id
+        );
+    }
+}
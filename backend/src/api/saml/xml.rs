@@ -0,0 +1,503 @@
+//! Just enough XML to check signed SAML responses: a small DOM that keeps the namespace prefixes
+//! (they are needed for canonicalization), exclusive XML canonicalization and enveloped
+//! XML signature verification.
+
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::sign::Verifier;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+
+/// Namespaces in scope, `None` is the default namespace
+type Scope = BTreeMap<Option<String>, String>;
+
+#[derive(Debug, Clone)]
+pub struct Element {
+    pub prefix: Option<String>,
+    pub name: String,
+    pub namespace: Option<String>,
+    scope: Scope,
+    pub attributes: Vec<Attribute>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    pub prefix: Option<String>,
+    pub name: String,
+    pub namespace: Option<String>,
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+fn split_qname(qname: &[u8]) -> Result<(Option<String>, String)> {
+    let qname = std::str::from_utf8(qname)?;
+    Ok(match qname.split_once(':') {
+        Some((prefix, name)) => (Some(prefix.to_string()), name.to_string()),
+        None => (None, qname.to_string()),
+    })
+}
+
+/// Line ending normalization, as done by conforming XML parsers
+fn normalize_newlines(raw: &[u8]) -> Result<String> {
+    Ok(std::str::from_utf8(raw)?
+        .replace("\r\n", "\n")
+        .replace('\r', "\n"))
+}
+
+fn resolve(scope: &Scope, prefix: Option<&str>) -> Result<Option<String>> {
+    match prefix {
+        Some("xml") => Ok(Some(XML_NS.to_string())),
+        Some(prefix) => scope
+            .get(&Some(prefix.to_string()))
+            .filter(|ns| !ns.is_empty())
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| anyhow!("Unbound namespace prefix {:?}", prefix)),
+        None => Ok(scope.get(&None).filter(|ns| !ns.is_empty()).cloned()),
+    }
+}
+
+fn parse_start(start: &BytesStart, parent_scope: &Scope) -> Result<Element> {
+    let mut scope = parent_scope.clone();
+    let mut raw_attributes = Vec::new();
+    for attribute in start.attributes() {
+        let attribute = attribute?;
+        let (prefix, name) = split_qname(attribute.key.as_ref())?;
+        // attribute value normalization for CDATA attributes: literal whitespace becomes a space
+        let value = normalize_newlines(&attribute.value)?.replace(['\t', '\n'], " ");
+        let value = quick_xml::escape::unescape(&value)?.into_owned();
+        match (prefix.as_deref(), name.as_str()) {
+            (None, "xmlns") => {
+                scope.insert(None, value);
+            }
+            (Some("xmlns"), _) => {
+                scope.insert(Some(name), value);
+            }
+            _ => raw_attributes.push((prefix, name, value)),
+        }
+    }
+
+    let attributes = raw_attributes
+        .into_iter()
+        .map(|(prefix, name, value)| {
+            Ok(Attribute {
+                // unprefixed attributes are not in the default namespace
+                namespace: match &prefix {
+                    Some(_) => resolve(&scope, prefix.as_deref())?,
+                    None => None,
+                },
+                prefix,
+                name,
+                value,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let (prefix, name) = split_qname(start.name().as_ref())?;
+    Ok(Element {
+        namespace: resolve(&scope, prefix.as_deref())?,
+        prefix,
+        name,
+        scope,
+        attributes,
+        children: Vec::new(),
+    })
+}
+
+impl Element {
+    pub fn parse(xml: &str) -> Result<Element> {
+        let mut reader = Reader::from_str(xml);
+        let mut stack: Vec<Element> = Vec::new();
+        let empty_scope = Scope::new();
+
+        let finish = |stack: &mut Vec<Element>, element: Element| match stack.last_mut() {
+            Some(parent) => {
+                parent.children.push(Node::Element(element));
+                None
+            }
+            None => Some(element),
+        };
+
+        loop {
+            match reader.read_event().context("Parsing XML")? {
+                Event::Start(start) => {
+                    let scope = stack.last().map(|e| &e.scope).unwrap_or(&empty_scope);
+                    let element = parse_start(&start, scope)?;
+                    stack.push(element);
+                }
+                Event::Empty(start) => {
+                    let scope = stack.last().map(|e| &e.scope).unwrap_or(&empty_scope);
+                    let element = parse_start(&start, scope)?;
+                    if let Some(root) = finish(&mut stack, element) {
+                        return Ok(root);
+                    }
+                }
+                Event::End(_) => {
+                    let element = stack.pop().ok_or_else(|| anyhow!("Unexpected end tag"))?;
+                    if let Some(root) = finish(&mut stack, element) {
+                        return Ok(root);
+                    }
+                }
+                Event::Text(text) => {
+                    let text = normalize_newlines(&text.into_inner())?;
+                    let text = quick_xml::escape::unescape(&text)?.into_owned();
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(Node::Text(text)),
+                        None if text.trim().is_empty() => {}
+                        None => bail!("Text outside of the root element"),
+                    }
+                }
+                Event::CData(data) => {
+                    let text = normalize_newlines(&data.into_inner())?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(Node::Text(text)),
+                        None => bail!("CDATA outside of the root element"),
+                    }
+                }
+                Event::DocType(_) => bail!("Document type declarations are not allowed"),
+                Event::Decl(_) | Event::Comment(_) | Event::PI(_) => {}
+                Event::Eof => bail!("Unexpected end of the document"),
+            }
+        }
+    }
+
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace.as_deref() == Some(namespace) && self.name == name
+    }
+
+    /// Get the value of an unqualified attribute
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|a| a.namespace.is_none() && a.name == name)
+            .map(|a| a.value.as_str())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|c| match c {
+            Node::Element(e) => Some(e),
+            Node::Text(_) => None,
+        })
+    }
+
+    pub fn children<'a>(
+        &'a self,
+        namespace: &'a str,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Element> + 'a {
+        self.elements().filter(move |e| e.is(namespace, name))
+    }
+
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&Element> {
+        self.elements().find(|e| e.is(namespace, name))
+    }
+
+    /// Get the only child with the specified name, failing if there are none or more than one
+    pub fn single_child(&self, namespace: &str, name: &str) -> Result<&Element> {
+        let mut children = self.elements().filter(|e| e.is(namespace, name));
+        match (children.next(), children.next()) {
+            (Some(child), None) => Ok(child),
+            (None, _) => bail!("No {} element in {}", name, self.name),
+            (Some(_), Some(_)) => bail!("More than one {} element in {}", name, self.name),
+        }
+    }
+
+    /// Concatenated text content of the element (not including the nested elements)
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|c| match c {
+                Node::Text(t) => Some(t.as_str()),
+                Node::Element(_) => None,
+            })
+            .collect()
+    }
+
+    fn count_ids(&self, id: &str) -> usize {
+        let own = usize::from(self.attribute("ID") == Some(id));
+        own + self.elements().map(|e| e.count_ids(id)).sum::<usize>()
+    }
+
+    /// Exclusive XML canonicalization (without comments) of the element subtree
+    ///
+    /// `skip` element is omitted from the output, this implements the enveloped signature transform.
+    pub fn canonicalize(&self, inclusive_prefixes: &[String], skip: Option<&Element>) -> String {
+        let mut output = String::new();
+        self.write_canonical(&Scope::new(), inclusive_prefixes, skip, &mut output);
+        output
+    }
+
+    fn write_canonical(
+        &self,
+        rendered: &Scope,
+        inclusive_prefixes: &[String],
+        skip: Option<&Element>,
+        output: &mut String,
+    ) {
+        let mut rendered = rendered.clone();
+
+        // the namespaces that are "visibly utilized" by the element, and the inclusive ones
+        let mut prefixes = BTreeSet::new();
+        prefixes.insert(self.prefix.clone());
+        for attribute in &self.attributes {
+            if attribute.prefix.is_some() {
+                prefixes.insert(attribute.prefix.clone());
+            }
+        }
+        for prefix in inclusive_prefixes {
+            let prefix = (prefix != "#default").then(|| prefix.clone());
+            if self.scope.contains_key(&prefix) {
+                prefixes.insert(prefix);
+            }
+        }
+
+        let qname = |prefix: &Option<String>, name: &str| match prefix {
+            Some(prefix) => format!("{}:{}", prefix, name),
+            None => name.to_string(),
+        };
+
+        let element_name = qname(&self.prefix, &self.name);
+        output.push('<');
+        output.push_str(&element_name);
+
+        // `None` (the default namespace) is sorted first, as required
+        for prefix in prefixes {
+            if prefix.as_deref() == Some("xml") {
+                continue;
+            }
+            let namespace = self.scope.get(&prefix).cloned().unwrap_or_default();
+            if rendered.get(&prefix).map(String::as_str).unwrap_or("") == namespace {
+                continue;
+            }
+            match &prefix {
+                Some(prefix) => output.push_str(&format!(" xmlns:{}=\"", prefix)),
+                None => output.push_str(" xmlns=\""),
+            }
+            escape_attribute(&namespace, output);
+            output.push('"');
+            rendered.insert(prefix, namespace);
+        }
+
+        let mut attributes = self.attributes.iter().collect::<Vec<_>>();
+        attributes.sort_by(|a, b| {
+            (a.namespace.as_deref().unwrap_or(""), &a.name)
+                .cmp(&(b.namespace.as_deref().unwrap_or(""), &b.name))
+        });
+        for attribute in attributes {
+            output.push(' ');
+            output.push_str(&qname(&attribute.prefix, &attribute.name));
+            output.push_str("=\"");
+            escape_attribute(&attribute.value, output);
+            output.push('"');
+        }
+        output.push('>');
+
+        for child in &self.children {
+            match child {
+                Node::Element(e) if matches!(skip, Some(skip) if std::ptr::eq(e, skip)) => {}
+                Node::Element(e) => e.write_canonical(&rendered, inclusive_prefixes, skip, output),
+                Node::Text(t) => escape_text(t, output),
+            }
+        }
+
+        output.push_str("</");
+        output.push_str(&element_name);
+        output.push('>');
+    }
+
+    /// Check the enveloped XML signature on the element against the trusted keys
+    ///
+    /// `document` is the root of the document, it is used to make sure the signature reference
+    ///   can't be redirected to a different element with the same ID.
+    pub fn verify_signature(&self, document: &Element, keys: &[PKey<Public>]) -> Result<()> {
+        let signature = self.single_child(DSIG_NS, "Signature")?;
+        let signed_info = signature.single_child(DSIG_NS, "SignedInfo")?;
+
+        let canonicalization = signed_info.single_child(DSIG_NS, "CanonicalizationMethod")?;
+        if canonicalization.attribute("Algorithm") != Some(EXC_C14N) {
+            bail!(
+                "Unsupported canonicalization method {:?}",
+                canonicalization.attribute("Algorithm")
+            );
+        }
+        let signed_info_prefixes = inclusive_prefixes(canonicalization);
+
+        let signature_digest = match signed_info
+            .single_child(DSIG_NS, "SignatureMethod")?
+            .attribute("Algorithm")
+        {
+            Some("http://www.w3.org/2001/04/xmldsig-more#rsa-sha256") => MessageDigest::sha256(),
+            Some("http://www.w3.org/2000/09/xmldsig#rsa-sha1") => MessageDigest::sha1(),
+            algorithm => bail!("Unsupported signature method {:?}", algorithm),
+        };
+
+        let reference = signed_info.single_child(DSIG_NS, "Reference")?;
+        let id = self
+            .attribute("ID")
+            .ok_or_else(|| anyhow!("Signed element does not have an ID"))?;
+        if reference.attribute("URI") != Some(&format!("#{}", id)) {
+            bail!("Signature does not reference the signed element");
+        }
+        if document.count_ids(id) != 1 {
+            bail!("Signed element ID is not unique");
+        }
+
+        let mut enveloped = false;
+        let mut reference_prefixes = None;
+        for transform in reference
+            .single_child(DSIG_NS, "Transforms")?
+            .children(DSIG_NS, "Transform")
+        {
+            match transform.attribute("Algorithm") {
+                Some(ENVELOPED_SIGNATURE) => enveloped = true,
+                Some(EXC_C14N) => reference_prefixes = Some(inclusive_prefixes(transform)),
+                algorithm => bail!("Unsupported transform {:?}", algorithm),
+            }
+        }
+        let reference_prefixes = match (enveloped, reference_prefixes) {
+            (true, Some(prefixes)) => prefixes,
+            _ => bail!("Signature must use enveloped signature and exclusive c14n transforms"),
+        };
+
+        let reference_digest = match reference
+            .single_child(DSIG_NS, "DigestMethod")?
+            .attribute("Algorithm")
+        {
+            Some("http://www.w3.org/2001/04/xmlenc#sha256") => MessageDigest::sha256(),
+            Some("http://www.w3.org/2000/09/xmldsig#sha1") => MessageDigest::sha1(),
+            algorithm => bail!("Unsupported digest method {:?}", algorithm),
+        };
+        let expected_digest =
+            decode_base64(&reference.single_child(DSIG_NS, "DigestValue")?.text())?;
+        let digest = openssl::hash::hash(
+            reference_digest,
+            self.canonicalize(&reference_prefixes, Some(signature))
+                .as_bytes(),
+        )?;
+        if *digest != *expected_digest {
+            bail!("Digest of the signed element does not match");
+        }
+
+        let signature_value =
+            decode_base64(&signature.single_child(DSIG_NS, "SignatureValue")?.text())?;
+        let signed_info = signed_info.canonicalize(&signed_info_prefixes, None);
+        for key in keys {
+            let mut verifier = Verifier::new(signature_digest, key)?;
+            verifier.update(signed_info.as_bytes())?;
+            if verifier.verify(&signature_value)? {
+                return Ok(());
+            }
+        }
+
+        bail!("Signature is not made by any of the trusted keys")
+    }
+}
+
+fn inclusive_prefixes(transform: &Element) -> Vec<String> {
+    transform
+        .child(EXC_C14N, "InclusiveNamespaces")
+        .and_then(|e| e.attribute("PrefixList"))
+        .map(|list| list.split_whitespace().map(|p| p.to_string()).collect())
+        .unwrap_or_default()
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode(
+        data.chars()
+            .filter(|c| !c.is_ascii_whitespace())
+            .collect::<String>(),
+    )?)
+}
+
+fn escape_text(text: &str, output: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '\r' => output.push_str("&#xD;"),
+            c => output.push(c),
+        }
+    }
+}
+
+fn escape_attribute(value: &str, output: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '"' => output.push_str("&quot;"),
+            '\t' => output.push_str("&#x9;"),
+            '\n' => output.push_str("&#xA;"),
+            '\r' => output.push_str("&#xD;"),
+            c => output.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_canonicalize() {
+        // expected output is produced by `xmllint --exc-c14n`
+        let xml = concat!(
+            "<?xml version=\"1.0\"?>\n",
+            "<a:root xmlns:a=\"urn:a\" xmlns=\"urn:default\" xmlns:b=\"urn:b\" xmlns:unused=\"urn:unused\" ",
+            "b:z=\"1\" y=\"&lt;&quot;&#9;&#xA;\">\r\n",
+            "  <child   attr='x' a:attr=\"y\">text &amp; &gt; <![CDATA[<cdata & stuff>]]>&#xD;</child>\n",
+            "  <b:el xmlns=\"\"><inner xmlns=\"urn:default\"/><noNs/></b:el>\n",
+            "  <a:empty/>\n",
+            "  <x:y xmlns:x=\"urn:a\"><!-- comment --><z a:q=\"1\" b:q=\"2\" q=\"0\"/></x:y>\n",
+            "</a:root>\n",
+        );
+        let expected = concat!(
+            "<a:root xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" y=\"&lt;&quot;&#x9;&#xA;\" b:z=\"1\">\n",
+            "  <child xmlns=\"urn:default\" attr=\"x\" a:attr=\"y\">text &amp; &gt; &lt;cdata &amp; stuff&gt;&#xD;</child>\n",
+            "  <b:el><inner xmlns=\"urn:default\"></inner><noNs></noNs></b:el>\n",
+            "  <a:empty></a:empty>\n",
+            "  <x:y xmlns:x=\"urn:a\"><z xmlns=\"urn:default\" q=\"0\" a:q=\"1\" b:q=\"2\"></z></x:y>\n",
+            "</a:root>",
+        );
+
+        let element = Element::parse(xml).unwrap();
+        assert_eq!(element.canonicalize(&[], None), expected);
+    }
+
+    #[test]
+    fn test_canonicalize_subtree() {
+        let xml = concat!(
+            "<p:outer xmlns:p=\"urn:p\" xmlns:xs=\"urn:xs\" xmlns:xsi=\"urn:xsi\">",
+            "<p:inner ID=\"_1\"><p:value xsi:type=\"xs:string\">v</p:value><p:skipped/></p:inner>",
+            "</p:outer>",
+        );
+        let outer = Element::parse(xml).unwrap();
+        let inner = outer.child("urn:p", "inner").unwrap();
+        let skipped = inner.child("urn:p", "skipped").unwrap();
+
+        // namespaces declared outside of the subtree are rendered where they are used
+        assert_eq!(
+            inner.canonicalize(&[], Some(skipped)),
+            "<p:inner xmlns:p=\"urn:p\" ID=\"_1\"><p:value xmlns:xsi=\"urn:xsi\" xsi:type=\"xs:string\">v</p:value></p:inner>"
+        );
+        // unless they are listed as inclusive
+        assert_eq!(
+            inner.canonicalize(&["xs".to_string()], Some(skipped)),
+            "<p:inner xmlns:p=\"urn:p\" xmlns:xs=\"urn:xs\" ID=\"_1\"><p:value xmlns:xsi=\"urn:xsi\" xsi:type=\"xs:string\">v</p:value></p:inner>"
+        );
+    }
+}
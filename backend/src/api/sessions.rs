@@ -1,15 +1,33 @@
 use crate::api::auth::UserClaims;
-use crate::api::error::ApiResult;
+use crate::api::challenge;
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::geofence;
 use crate::api::models;
 use crate::db;
+use crate::db::models::{Role, Scope};
 use crate::db::DbData;
+use actix_web::http::StatusCode;
 use actix_web::{delete, get, post, put, web};
-use chrono::TimeZone;
 use chrono::Utc;
-use tracing::Span;
+use std::collections::HashMap;
+use tracing::{info, Span};
+
+#[derive(Debug)]
+pub enum SessionError {
+    InvalidParams(String),
+}
+
+impl ApiError for SessionError {
+    fn to_http(&self) -> (StatusCode, String) {
+        match self {
+            SessionError::InvalidParams(e) => (StatusCode::BAD_REQUEST, e.clone()),
+        }
+    }
+}
 
 #[get("/sessions")]
 async fn get_sessions(user: UserClaims, db: DbData) -> ApiResult<web::Json<Vec<models::Session>>> {
+    user.require_scope(Scope::SessionsRead)?;
     let sessions = db
         .send(db::GetSessions {
             span: Span::current(),
@@ -24,10 +42,23 @@ async fn get_sessions(user: UserClaims, db: DbData) -> ApiResult<web::Json<Vec<m
 async fn create_session(
     user: UserClaims,
     db: DbData,
+    config: web::Data<challenge::Config>,
     req: web::Json<models::NewSession>,
 ) -> ApiResult<web::Json<models::Session>> {
+    user.require_scope(Scope::SessionsWrite)?;
+    user.require_role(Role::Teacher)?;
     let req = req.into_inner();
 
+    let challenge_algorithm = req.challenge_algorithm.unwrap_or(config.default_algorithm);
+    let challenge_tag_length =
+        challenge::tag_length(&config, challenge_algorithm, req.challenge_tag_length)
+            .map_err(SessionError::InvalidParams)?;
+    challenge::check_timing_overrides(req.qr_interval_ms, req.jitter_window_ms)
+        .map_err(SessionError::InvalidParams)?;
+    if let Some(fence) = &req.geofence {
+        geofence::check_geofence(fence).map_err(SessionError::InvalidParams)?;
+    }
+
     use rand::{rngs::OsRng, RngCore};
 
     let mut seed = [0u8; 12];
@@ -41,6 +72,21 @@ async fn create_session(
             title: req.title,
             start_time: Utc::now().naive_utc(),
             seed,
+            challenge_algorithm,
+            challenge_tag_length: challenge_tag_length as i32,
+            qr_interval_ms: req.qr_interval_ms.map(|ms| ms as i32),
+            jitter_window_ms: req.jitter_window_ms.map(|ms| ms as i32),
+            allowed_networks: req
+                .allowed_networks
+                .map(|networks| networks.iter().map(|n| n.to_string()).collect()),
+            network_policy: req.network_policy,
+            student_visibility: req.student_visibility,
+            geofence: req.geofence.map(|g| db::SessionGeofence {
+                latitude: g.latitude,
+                longitude: g.longitude,
+                radius_m: g.radius_m as i32,
+                policy: g.policy,
+            }),
         })
         .await??;
 
@@ -51,18 +97,22 @@ async fn create_session(
 async fn get_session(
     user: UserClaims,
     db: DbData,
+    config: web::Data<challenge::Config>,
     req: web::Path<models::GetSession>,
 ) -> ApiResult<web::Json<models::SessionWithMarks>> {
+    user.require_scope(Scope::SessionsRead)?;
     let req = req.into_inner();
-    let session = db
+    let (session, permission) = db
         .send(db::GetSession {
             span: Span::current(),
-            owner_id: user.user_id,
+            user_id: user.user_id,
             session_id: req.session_id,
         })
         .await??;
 
-    Ok(web::Json(session.into()))
+    Ok(web::Json(models::SessionWithMarks::new(
+        session, permission, &config,
+    )))
 }
 
 #[delete("/sessions/{session_id}")]
@@ -71,42 +121,100 @@ async fn delete_session(
     db: DbData,
     req: web::Path<models::DeleteSession>,
 ) -> ApiResult<web::Json<models::Session>> {
+    user.require_scope(Scope::SessionsWrite)?;
     let req = req.into_inner();
     let session = db
         .send(db::DeleteSession {
             span: Span::current(),
-            owner_id: user.user_id,
+            user_id: user.user_id,
+            session_id: req.session_id,
+        })
+        .await??;
+
+    Ok(web::Json(session.into()))
+}
+
+async fn change_state(
+    user: UserClaims,
+    db: DbData,
+    req: models::GetSession,
+    change: db::SessionStateChange,
+) -> ApiResult<web::Json<models::Session>> {
+    user.require_scope(Scope::SessionsWrite)?;
+    let session = db
+        .send(db::ChangeSessionState {
+            span: Span::current(),
             session_id: req.session_id,
+            user_id: user.user_id,
+            change,
+            time: Utc::now().naive_utc(),
         })
         .await??;
 
+    info!(
+        "User {:?} changed the state of session {:?}: {:?}",
+        user.username, session.id, change
+    );
+
     Ok(web::Json(session.into()))
 }
 
+/// Stop accepting check-ins for a while, the displays keep running
+#[post("/sessions/{session_id}/pause")]
+async fn pause_session(
+    user: UserClaims,
+    db: DbData,
+    req: web::Path<models::GetSession>,
+) -> ApiResult<web::Json<models::Session>> {
+    change_state(user, db, req.into_inner(), db::SessionStateChange::Pause).await
+}
+
+#[post("/sessions/{session_id}/resume")]
+async fn resume_session(
+    user: UserClaims,
+    db: DbData,
+    req: web::Path<models::GetSession>,
+) -> ApiResult<web::Json<models::Session>> {
+    change_state(user, db, req.into_inner(), db::SessionStateChange::Resume).await
+}
+
+/// End the session for good, recording the end time
+#[post("/sessions/{session_id}/close")]
+async fn close_session(
+    user: UserClaims,
+    db: DbData,
+    req: web::Path<models::GetSession>,
+) -> ApiResult<web::Json<models::Session>> {
+    change_state(user, db, req.into_inner(), db::SessionStateChange::Close).await
+}
+
 #[put("/sessions/{session_id}/marks/{username}")]
 async fn add_mark(
     user: UserClaims,
     db: DbData,
     req: web::Path<models::AttendanceMarkRef>,
 ) -> ApiResult<web::Json<models::AttendanceMark>> {
+    user.require_scope(Scope::MarksWrite)?;
+    user.require_role(Role::TeachingAssistant)?;
     let time = Utc::now();
 
     let req = req.into_inner();
     let mark: db::models::AttendanceMark = db
         .send(db::AddManualAttendanceMark {
             span: Span::current(),
-            owner_id: user.user_id,
+            user_id: user.user_id,
+            username: user.username.clone(),
             session_id: req.session_id,
             student_username: req.username.clone(),
             mark_time: time.naive_utc(),
         })
         .await??;
 
-    Ok(web::Json(models::AttendanceMark {
-        username: req.username,
-        mark_time: Utc.from_utc_datetime(&mark.mark_time),
-        is_manual: mark.is_manual,
-    }))
+    Ok(web::Json(models::AttendanceMark::new(
+        mark,
+        req.username,
+        vec![],
+    )))
 }
 
 #[delete("/sessions/{session_id}/marks/{username}")]
@@ -115,19 +223,81 @@ async fn delete_mark(
     db: DbData,
     req: web::Path<models::AttendanceMarkRef>,
 ) -> ApiResult<web::Json<models::AttendanceMark>> {
+    user.require_scope(Scope::MarksWrite)?;
+    user.require_role(Role::TeachingAssistant)?;
     let req = req.into_inner();
     let mark: db::models::AttendanceMark = db
         .send(db::DeleteAttendanceMark {
             span: Span::current(),
-            owner_id: user.user_id,
+            user_id: user.user_id,
             session_id: req.session_id,
             student_username: req.username.clone(),
         })
         .await??;
 
-    Ok(web::Json(models::AttendanceMark {
-        username: req.username,
-        mark_time: Utc.from_utc_datetime(&mark.mark_time),
-        is_manual: mark.is_manual,
-    }))
+    Ok(web::Json(models::AttendanceMark::new(
+        mark,
+        req.username,
+        vec![],
+    )))
+}
+
+#[get("/sessions/{session_id}/members")]
+async fn get_members(
+    user: UserClaims,
+    db: DbData,
+    req: web::Path<models::GetSession>,
+) -> ApiResult<web::Json<Vec<models::SessionMember>>> {
+    user.require_scope(Scope::SessionsRead)?;
+    let req = req.into_inner();
+    let members = db
+        .send(db::GetSessionMembers {
+            span: Span::current(),
+            session_id: req.session_id,
+            user_id: user.user_id,
+        })
+        .await??;
+
+    Ok(web::Json(members.into_iter().map(|m| m.into()).collect()))
+}
+
+#[put("/sessions/{session_id}/members/{username}")]
+async fn set_member(
+    user: UserClaims,
+    db: DbData,
+    req: web::Path<models::SessionMemberRef>,
+    body: web::Json<models::SetPermission>,
+) -> ApiResult<web::Json<models::SessionMember>> {
+    user.require_scope(Scope::SessionsWrite)?;
+    let req = req.into_inner();
+    let member = db
+        .send(db::SetSessionMember {
+            span: Span::current(),
+            session_id: req.session_id,
+            user_id: user.user_id,
+            member_username: req.username,
+            permission: body.into_inner().permission,
+        })
+        .await??;
+
+    Ok(web::Json(member.into()))
+}
+
+#[delete("/sessions/{session_id}/members/{username}")]
+async fn remove_member(
+    user: UserClaims,
+    db: DbData,
+    req: web::Path<models::SessionMemberRef>,
+) -> ApiResult<web::Json<HashMap<(), ()>>> {
+    user.require_scope(Scope::SessionsWrite)?;
+    let req = req.into_inner();
+    db.send(db::RemoveSessionMember {
+        span: Span::current(),
+        session_id: req.session_id,
+        user_id: user.user_id,
+        member_username: req.username,
+    })
+    .await??;
+
+    Ok(web::Json(HashMap::new()))
 }
@@ -1 +1,751 @@
-// TODO: code regarding the uni SSO would go here
+//! OpenID Connect login using the authorization code flow with PKCE
+
+use crate::api::auth::{Authority, TokenClaims};
+use crate::api::error::{ApiError, ApiResult};
+use crate::db::DbData;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::web::ServiceConfig;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Duration, Utc};
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{info, warn};
+use url::Url;
+
+const FLOW_COOKIE_NAME: &str = "sso_flow";
+/// How long the user has to complete the login at the identity provider
+const FLOW_DURATION_MINUTES: i64 = 10;
+/// How long to use the provider metadata before fetching it again
+const METADATA_TTL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Issuer identifier, the metadata is discovered at `{issuer}/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Should point to the `/api/sso/callback` endpoint, as registered at the identity provider
+    pub redirect_url: Url,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// ID token claim used as `users.username`
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    /// ID token claim used as `users.name`
+    #[serde(default = "default_name_claim")]
+    pub name_claim: String,
+    /// Where to send the user after a successful login
+    #[serde(default = "default_post_login_redirect")]
+    pub post_login_redirect: String,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "profile".to_string()]
+}
+fn default_username_claim() -> String {
+    "preferred_username".to_string()
+}
+fn default_name_claim() -> String {
+    "name".to_string()
+}
+fn default_post_login_redirect() -> String {
+    "/".to_string()
+}
+
+#[derive(Debug)]
+pub enum SsoError {
+    NoFlowCookie,
+    InvalidFlow,
+    StateMismatch,
+    ProviderError(String),
+    MissingClaim(String),
+}
+
+impl ApiError for SsoError {
+    fn to_http(&self) -> (StatusCode, String) {
+        match self {
+            SsoError::NoFlowCookie | SsoError::InvalidFlow => (
+                StatusCode::BAD_REQUEST,
+                "Login flow was not started or has expired, please try again".to_string(),
+            ),
+            SsoError::StateMismatch => (
+                StatusCode::BAD_REQUEST,
+                "Login flow state does not match".to_string(),
+            ),
+            SsoError::ProviderError(e) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Identity provider returned an error: {}", e),
+            ),
+            SsoError::MissingClaim(claim) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Identity provider did not supply the {:?} claim", claim),
+            ),
+        }
+    }
+}
+
+/// The subset of provider metadata we use
+#[derive(Deserialize, Debug, Clone)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    jwks_uri: Url,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct JsonWebKeySet {
+    keys: Vec<JsonWebKey>,
+}
+
+impl JsonWebKeySet {
+    /// Whether an ID token with this key id can be checked with the set
+    fn has_key(&self, kid: Option<&str>) -> bool {
+        self.keys
+            .iter()
+            .filter(|k| k.kty == "RSA")
+            .any(|k| kid.is_none() || k.kid.as_deref() == kid)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct JsonWebKey {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct IdTokenHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, aud: &str) -> bool {
+        match self {
+            Audience::Single(a) => a == aud,
+            Audience::Multiple(a) => a.iter().any(|a| a == aud),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct IdTokenClaims {
+    iss: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+/// Login flow state, stored in a signed cookie between the redirect to the provider and the callback
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FlowState {
+    state: String,
+    nonce: String,
+    code_verifier: String,
+}
+
+impl TokenClaims for FlowState {
+    const PURPOSE: &'static str = "oidc_flow";
+}
+
+#[derive(Deserialize, Debug)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+struct Data {
+    config: Config,
+    client: awc::Client,
+    metadata: Mutex<Option<(Instant, ProviderMetadata)>>,
+    jwks: Mutex<Option<JsonWebKeySet>>,
+}
+
+impl Data {
+    async fn metadata(&self) -> Result<ProviderMetadata> {
+        if let Some((fetched_at, metadata)) = self.metadata.lock().unwrap().as_ref() {
+            if fetched_at.elapsed() < METADATA_TTL {
+                return Ok(metadata.clone());
+            }
+        }
+
+        let metadata = discover(&self.client, &self.config.issuer).await?;
+        *self.metadata.lock().unwrap() = Some((Instant::now(), metadata.clone()));
+        Ok(metadata)
+    }
+
+    /// Keys of the provider, fetched again only when the ID token is signed with a key that is not among them,
+    ///   e.g. after the provider has rotated its keys
+    async fn jwks(&self, metadata: &ProviderMetadata, kid: Option<&str>) -> Result<JsonWebKeySet> {
+        if let Some(jwks) = self.jwks.lock().unwrap().as_ref() {
+            if jwks.has_key(kid) {
+                return Ok(jwks.clone());
+            }
+        }
+
+        let jwks = fetch_jwks(&self.client, metadata).await?;
+        *self.jwks.lock().unwrap() = Some(jwks.clone());
+        Ok(jwks)
+    }
+}
+
+fn random_string() -> String {
+    use rand::{rngs::OsRng, RngCore};
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Compute the S256 PKCE code challenge (RFC 7636)
+fn pkce_challenge(code_verifier: &str) -> String {
+    base64::encode_config(
+        openssl::sha::sha256(code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+async fn discover(client: &awc::Client, issuer: &str) -> Result<ProviderMetadata> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let metadata: ProviderMetadata = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to fetch provider metadata: {}", e))?
+        .json()
+        .await
+        .map_err(|e| anyhow!("Failed to parse provider metadata: {}", e))?;
+
+    if metadata.issuer != issuer {
+        bail!(
+            "Provider metadata has issuer {:?}, expected {:?}",
+            metadata.issuer,
+            issuer
+        );
+    }
+
+    Ok(metadata)
+}
+
+async fn fetch_jwks(client: &awc::Client, metadata: &ProviderMetadata) -> Result<JsonWebKeySet> {
+    client
+        .get(metadata.jwks_uri.as_str())
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to fetch provider keys: {}", e))?
+        .json()
+        .await
+        .map_err(|e| anyhow!("Failed to parse provider keys: {}", e))
+}
+
+async fn exchange_code(
+    client: &awc::Client,
+    metadata: &ProviderMetadata,
+    config: &Config,
+    code: &str,
+    code_verifier: &str,
+) -> Result<TokenResponse> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", config.redirect_url.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+
+    let mut response = client
+        .post(metadata.token_endpoint.as_str())
+        .send_form(&form)
+        .await
+        .map_err(|e| anyhow!("Failed to send the token request: {}", e))?;
+
+    if !response.status().is_success() {
+        let body = response.body().await.unwrap_or_default();
+        bail!(
+            "Token endpoint returned {}: {}",
+            response.status(),
+            String::from_utf8_lossy(&body)
+        );
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|e| anyhow!("Failed to parse the token response: {}", e))
+}
+
+fn decode_base64url(data: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode_config(data, base64::URL_SAFE_NO_PAD)?)
+}
+
+/// Verify the ID token signature and standard claims, returning its claims
+fn parse_id_token_header(header: &str) -> Result<IdTokenHeader> {
+    serde_json::from_slice(&decode_base64url(header)?).context("Failed to parse ID token header")
+}
+
+/// Key id of the key the ID token is signed with, to pick the provider keys before the token is validated
+fn id_token_kid(id_token: &str) -> Result<Option<String>> {
+    let (header, _) = id_token
+        .split_once('.')
+        .ok_or_else(|| anyhow!("Malformed ID token"))?;
+    Ok(parse_id_token_header(header)?.kid)
+}
+
+fn validate_id_token(
+    id_token: &str,
+    jwks: &JsonWebKeySet,
+    config: &Config,
+    nonce: &str,
+) -> Result<IdTokenClaims> {
+    let (signing_input, signature) = id_token
+        .rsplit_once('.')
+        .ok_or_else(|| anyhow!("Malformed ID token"))?;
+    let (header, claims) = signing_input
+        .split_once('.')
+        .ok_or_else(|| anyhow!("Malformed ID token"))?;
+
+    let header = parse_id_token_header(header)?;
+    if header.alg != "RS256" {
+        bail!("Unsupported ID token algorithm {:?}", header.alg);
+    }
+
+    let key = jwks
+        .keys
+        .iter()
+        .filter(|k| k.kty == "RSA")
+        .find(|k| header.kid.is_none() || k.kid == header.kid)
+        .ok_or_else(|| anyhow!("No provider key found for kid {:?}", header.kid))?;
+    let (n, e) = key
+        .n
+        .as_ref()
+        .zip(key.e.as_ref())
+        .ok_or_else(|| anyhow!("Provider key {:?} is missing RSA parameters", key.kid))?;
+    let key = PKey::from_rsa(Rsa::from_public_components(
+        BigNum::from_slice(&decode_base64url(n)?)?,
+        BigNum::from_slice(&decode_base64url(e)?)?,
+    )?)?;
+
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    verifier.update(signing_input.as_bytes())?;
+    if !verifier.verify(&decode_base64url(signature)?)? {
+        bail!("Invalid ID token signature");
+    }
+
+    let claims: IdTokenClaims = serde_json::from_slice(&decode_base64url(claims)?)
+        .context("Failed to parse ID token claims")?;
+
+    if claims.iss != config.issuer {
+        bail!("ID token issued by {:?}", claims.iss);
+    }
+    if !claims.aud.contains(&config.client_id) {
+        bail!("ID token is not intended for us (aud = {:?})", claims.aud);
+    }
+    if claims.exp < Utc::now().timestamp() {
+        bail!("ID token has expired");
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        bail!("ID token nonce does not match");
+    }
+
+    Ok(claims)
+}
+
+fn claim_string(claims: &IdTokenClaims, claim: &str) -> Option<String> {
+    claims
+        .other
+        .get(claim)
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+}
+
+fn flow_cookie(authority: &Authority, value: String) -> Cookie<'static> {
+    Cookie::build(FLOW_COOKIE_NAME, value)
+        .path("/api/sso")
+        .secure(authority.cookie_secure())
+        .http_only(true)
+        // the callback is a cross-site redirect from the provider, so Strict would not work
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+#[get("/sso/login")]
+async fn login(data: web::Data<Data>, authority: web::Data<Authority>) -> ApiResult<HttpResponse> {
+    let metadata = data.metadata().await?;
+
+    let flow = FlowState {
+        state: random_string(),
+        nonce: random_string(),
+        code_verifier: random_string(),
+    };
+
+    let mut url = metadata.authorization_endpoint.clone();
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &data.config.client_id)
+        .append_pair("redirect_uri", data.config.redirect_url.as_str())
+        .append_pair("scope", &data.config.scopes.join(" "))
+        .append_pair("state", &flow.state)
+        .append_pair("nonce", &flow.nonce)
+        .append_pair("code_challenge", &pkce_challenge(&flow.code_verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    let (flow_token, _) = authority.sign_token(flow, Duration::minutes(FLOW_DURATION_MINUTES))?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url.as_str()))
+        .cookie(flow_cookie(&authority, flow_token))
+        .finish())
+}
+
+#[get("/sso/callback")]
+async fn callback(
+    req: HttpRequest,
+    db: DbData,
+    data: web::Data<Data>,
+    authority: web::Data<Authority>,
+    query: web::Query<CallbackQuery>,
+) -> ApiResult<HttpResponse> {
+    let query = query.into_inner();
+
+    let flow = req.cookie(FLOW_COOKIE_NAME).ok_or(SsoError::NoFlowCookie)?;
+    let flow = authority
+        .validate_token::<FlowState>(flow.value())
+        .map_err(|e| {
+            warn!("Could not validate the login flow cookie: {:?}", e);
+            SsoError::InvalidFlow
+        })?
+        .custom;
+
+    if query.state.as_deref() != Some(flow.state.as_str()) {
+        return Err(SsoError::StateMismatch.into());
+    }
+    if let Some(error) = query.error {
+        return Err(SsoError::ProviderError(match query.error_description {
+            Some(description) => format!("{}: {}", error, description),
+            None => error,
+        })
+        .into());
+    }
+    let code = query
+        .code
+        .ok_or_else(|| SsoError::ProviderError("no authorization code returned".to_string()))?;
+
+    let metadata = data.metadata().await?;
+    let tokens = exchange_code(
+        &data.client,
+        &metadata,
+        &data.config,
+        &code,
+        &flow.code_verifier,
+    )
+    .await?;
+    let jwks = data
+        .jwks(&metadata, id_token_kid(&tokens.id_token)?.as_deref())
+        .await?;
+    let claims = validate_id_token(&tokens.id_token, &jwks, &data.config, &flow.nonce)
+        .context("Validating the ID token")?;
+
+    let username = claim_string(&claims, &data.config.username_claim)
+        .ok_or_else(|| SsoError::MissingClaim(data.config.username_claim.clone()))?;
+    let name = claim_string(&claims, &data.config.name_claim).unwrap_or_else(|| username.clone());
+
+    info!("User {:?} logged in via OpenID Connect", username);
+
+    let outcome = super::create_session_cookie(&req, &db, &authority, username, name).await?;
+
+    let mut flow_removal = flow_cookie(&authority, String::new());
+    flow_removal.make_removal();
+
+    Ok(outcome
+        .into_redirect(StatusCode::FOUND, &data.config.post_login_redirect)
+        .cookie(flow_removal)
+        .finish())
+}
+
+pub fn configure(config: Option<Config>) -> impl Fn(&mut ServiceConfig) + Clone {
+    move |cfg: &mut ServiceConfig| {
+        if let Some(config) = &config {
+            cfg.app_data(web::Data::new(Data {
+                config: config.clone(),
+                client: awc::Client::default(),
+                metadata: Mutex::new(None),
+                jwks: Mutex::new(None),
+            }))
+            .service(login)
+            .service(callback);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{App, HttpServer};
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const CLIENT_ID: &str = "baam";
+    const KEY_ID: &str = "mock-key";
+
+    /// A minimal identity provider, issuing ID tokens for a fixed user
+    #[derive(Clone)]
+    struct MockIdp {
+        issuer: String,
+        key: PKey<Private>,
+    }
+
+    impl MockIdp {
+        fn sign(&self, claims: serde_json::Value) -> String {
+            let header = serde_json::json!({ "alg": "RS256", "kid": KEY_ID });
+            let signing_input = format!(
+                "{}.{}",
+                base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD),
+                base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+            );
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+            signer.update(signing_input.as_bytes()).unwrap();
+            let signature = signer.sign_to_vec().unwrap();
+            format!(
+                "{}.{}",
+                signing_input,
+                base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+            )
+        }
+
+        fn id_token(&self, nonce: &str) -> String {
+            self.sign(serde_json::json!({
+                "iss": self.issuer,
+                "aud": CLIENT_ID,
+                "exp": Utc::now().timestamp() + 60,
+                "nonce": nonce,
+                "preferred_username": "student",
+                "name": "Student Studentovich",
+            }))
+        }
+
+        fn jwks(&self) -> serde_json::Value {
+            let rsa = self.key.rsa().unwrap();
+            serde_json::json!({
+                "keys": [{
+                    "kty": "RSA",
+                    "kid": KEY_ID,
+                    "n": base64::encode_config(rsa.n().to_vec(), base64::URL_SAFE_NO_PAD),
+                    "e": base64::encode_config(rsa.e().to_vec(), base64::URL_SAFE_NO_PAD),
+                }]
+            })
+        }
+    }
+
+    fn mock_idp(issuer: String) -> MockIdp {
+        MockIdp {
+            issuer,
+            key: PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+        }
+    }
+
+    fn config(issuer: &str) -> Config {
+        Config {
+            issuer: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            redirect_url: Url::parse("http://localhost:8080/api/sso/callback").unwrap(),
+            scopes: default_scopes(),
+            username_claim: default_username_claim(),
+            name_claim: default_name_claim(),
+            post_login_redirect: default_post_login_redirect(),
+        }
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // example from RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_validate_id_token() {
+        let idp = mock_idp("http://idp.test".to_string());
+        let config = config(&idp.issuer);
+        let jwks: JsonWebKeySet = serde_json::from_value(idp.jwks()).unwrap();
+
+        let claims = validate_id_token(&idp.id_token("nonce"), &jwks, &config, "nonce").unwrap();
+        assert_eq!(
+            claim_string(&claims, "preferred_username").as_deref(),
+            Some("student")
+        );
+
+        validate_id_token(&idp.id_token("nonce"), &jwks, &config, "other nonce")
+            .expect_err("Token with a wrong nonce should not be accepted");
+
+        let other_issuer = Config {
+            issuer: "http://other-idp.test".to_string(),
+            ..config.clone()
+        };
+        validate_id_token(&idp.id_token("nonce"), &jwks, &other_issuer, "nonce")
+            .expect_err("Token from a wrong issuer should not be accepted");
+
+        let other_client = Config {
+            client_id: "other".to_string(),
+            ..config.clone()
+        };
+        validate_id_token(&idp.id_token("nonce"), &jwks, &other_client, "nonce")
+            .expect_err("Token for a different client should not be accepted");
+
+        let expired = idp.sign(serde_json::json!({
+            "iss": idp.issuer,
+            "aud": CLIENT_ID,
+            "exp": Utc::now().timestamp() - 60,
+            "nonce": "nonce",
+        }));
+        validate_id_token(&expired, &jwks, &config, "nonce")
+            .expect_err("Expired token should not be accepted");
+
+        let other_idp = mock_idp(idp.issuer.clone());
+        validate_id_token(&other_idp.id_token("nonce"), &jwks, &config, "nonce")
+            .expect_err("Token signed with an unknown key should not be accepted");
+    }
+
+    #[actix_web::test]
+    async fn test_mock_idp_flow() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let idp = mock_idp(issuer.clone());
+
+        let server_idp = idp.clone();
+        let jwks_fetches = Arc::new(AtomicUsize::new(0));
+        let server_jwks_fetches = jwks_fetches.clone();
+        let server = HttpServer::new(move || {
+            let idp = server_idp.clone();
+            let jwks_fetches = server_jwks_fetches.clone();
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to({
+                        let issuer = idp.issuer.clone();
+                        move || {
+                            let issuer = issuer.clone();
+                            async move {
+                                HttpResponse::Ok().json(serde_json::json!({
+                                    "issuer": issuer,
+                                    "authorization_endpoint": format!("{}/authorize", issuer),
+                                    "token_endpoint": format!("{}/token", issuer),
+                                    "jwks_uri": format!("{}/jwks", issuer),
+                                }))
+                            }
+                        }
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to({
+                        let jwks = idp.jwks();
+                        move || {
+                            jwks_fetches.fetch_add(1, Ordering::SeqCst);
+                            let jwks = jwks.clone();
+                            async move { HttpResponse::Ok().json(jwks) }
+                        }
+                    }),
+                )
+                .route(
+                    "/token",
+                    web::post().to(move |form: web::Form<HashMap<String, String>>| {
+                        let idp = idp.clone();
+                        async move {
+                            // the mock encodes the nonce in the authorization code
+                            assert_eq!(form["grant_type"], "authorization_code");
+                            assert_eq!(form["client_id"], CLIENT_ID);
+                            assert!(form.contains_key("code_verifier"));
+                            HttpResponse::Ok().json(serde_json::json!({
+                                "access_token": "unused",
+                                "token_type": "Bearer",
+                                "id_token": idp.id_token(&form["code"]),
+                            }))
+                        }
+                    }),
+                )
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let client = awc::Client::default();
+        let config = config(&issuer);
+
+        let metadata = discover(&client, &issuer).await.unwrap();
+        assert_eq!(metadata.issuer, issuer);
+
+        let code_verifier = random_string();
+        let tokens = exchange_code(&client, &metadata, &config, "nonce", &code_verifier)
+            .await
+            .unwrap();
+        let data = Data {
+            config: config.clone(),
+            client: client.clone(),
+            metadata: Mutex::new(None),
+            jwks: Mutex::new(None),
+        };
+        let kid = id_token_kid(&tokens.id_token).unwrap();
+        assert_eq!(kid.as_deref(), Some(KEY_ID));
+        let jwks = data.jwks(&metadata, kid.as_deref()).await.unwrap();
+        let claims = validate_id_token(&tokens.id_token, &jwks, &config, "nonce").unwrap();
+
+        // the keys are fetched again only for a key the provider did not have before
+        data.jwks(&metadata, kid.as_deref()).await.unwrap();
+        assert_eq!(jwks_fetches.load(Ordering::SeqCst), 1);
+        data.jwks(&metadata, Some("rotated")).await.unwrap();
+        assert_eq!(jwks_fetches.load(Ordering::SeqCst), 2);
+
+        assert_eq!(
+            claim_string(&claims, &config.username_claim).as_deref(),
+            Some("student")
+        );
+        assert_eq!(
+            claim_string(&claims, &config.name_claim).as_deref(),
+            Some("Student Studentovich")
+        );
+
+        handle.stop(true).await;
+    }
+}
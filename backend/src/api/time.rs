@@ -0,0 +1,109 @@
+//! Clock synchronization of the QR code displays
+//!
+//! The displays compute the challenge index from the start time of the session and their own clock, so a display
+//!   with a skewed clock shows codes that are rejected as too early or too late. The exchange is the one of NTP:
+//!   the display sends its time, the server answers with the times it received and sent the response, and the
+//!   display works out its offset and the round trip time. The display reports the estimate with the next request,
+//!   so that the server can log it and tell whether the skew eats too much of the jitter window.
+
+use crate::api::auth::UserClaims;
+use crate::api::challenge;
+use crate::api::error::ApiResult;
+use crate::api::models::{TimeRequest, TimeResponse};
+use crate::db;
+use crate::db::models::Scope;
+use crate::db::DbData;
+use actix_web::{post, web};
+use chrono::Utc;
+use std::time::Duration;
+use tracing::{info, warn, Span};
+
+/// Whether the skew reported by a display is close enough to the jitter window to worry the teacher.
+///   Half of the round trip is added, as the offset is only known up to it
+fn skew_too_large(offset_ms: i64, rtt_ms: i64, jitter_window: Duration, ratio: f64) -> bool {
+    let uncertain_skew = offset_ms.unsigned_abs() + rtt_ms.max(0) as u64 / 2;
+    uncertain_skew as f64 >= jitter_window.as_millis() as f64 * ratio
+}
+
+#[post("/time")]
+async fn sync_time(
+    user: UserClaims,
+    db: DbData,
+    config: web::Data<challenge::Config>,
+    req: web::Json<TimeRequest>,
+) -> ApiResult<web::Json<TimeResponse>> {
+    let receive_time = Utc::now().timestamp_millis();
+    user.require_scope(Scope::SessionsRead)?;
+    let req = req.into_inner();
+
+    // only the members see the settings of the session
+    let session = match req.session_id {
+        Some(session_id) => {
+            let ((session, _, _), _) = db
+                .send(db::GetSession {
+                    span: Span::current(),
+                    user_id: user.user_id,
+                    session_id,
+                })
+                .await??;
+            Some(session)
+        }
+        None => None,
+    };
+    let jitter_window = match &session {
+        Some(session) => config.for_session(session).jitter_window,
+        None => config.jitter_window,
+    };
+
+    let skew_warning = match req.last_estimate {
+        Some(estimate) => {
+            let too_large = skew_too_large(
+                estimate.offset_ms,
+                estimate.rtt_ms,
+                jitter_window,
+                config.clock_skew_warning_ratio,
+            );
+            if too_large {
+                warn!(
+                    "Display of user {:?} for session {:?} is {} ms off (rtt {} ms), the jitter window is {:?}",
+                    user.user_id, req.session_id, estimate.offset_ms, estimate.rtt_ms, jitter_window
+                );
+            } else {
+                info!(
+                    "Display of user {:?} for session {:?} is {} ms off (rtt {} ms)",
+                    user.user_id, req.session_id, estimate.offset_ms, estimate.rtt_ms
+                );
+            }
+            too_large
+        }
+        None => false,
+    };
+
+    Ok(web::Json(TimeResponse {
+        client_time: req.client_time,
+        receive_time,
+        transmit_time: Utc::now().timestamp_millis(),
+        jitter_window_ms: jitter_window.as_millis() as u64,
+        skew_warning,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_skew_too_large() {
+        let jitter_window = Duration::from_millis(500);
+
+        assert!(!skew_too_large(0, 40, jitter_window, 0.5));
+        assert!(!skew_too_large(-200, 40, jitter_window, 0.5));
+        assert!(skew_too_large(250, 0, jitter_window, 0.5));
+        assert!(skew_too_large(-250, 0, jitter_window, 0.5));
+        // a slow network makes a small offset uncertain
+        assert!(skew_too_large(100, 400, jitter_window, 0.5));
+        // the whole window
+        assert!(!skew_too_large(400, 0, jitter_window, 1.0));
+        assert!(skew_too_large(3000, 0, jitter_window, 1.0));
+    }
+}
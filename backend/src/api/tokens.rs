@@ -0,0 +1,118 @@
+//! Personal API tokens, for scripting against the API
+//!
+//! Tokens are sent in the `Authorization: Bearer` header and are limited by their scopes.
+//! Managing the tokens themselves requires a session cookie.
+
+use crate::api::auth::UserToken;
+use crate::api::error::ApiResult;
+use crate::api::models;
+use crate::db;
+use crate::db::models as db_models;
+use crate::db::DbData;
+use actix_web::{delete, get, post, web};
+use chrono::Utc;
+use std::collections::HashMap;
+use tracing::{info, Span};
+
+/// Makes the tokens easy to recognize, e.g. by secret scanners
+const TOKEN_PREFIX: &str = "baam_";
+
+fn generate_token() -> String {
+    use rand::{rngs::OsRng, RngCore};
+
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Only the hash of a token is stored. The tokens are random, so a fast hash is enough
+pub fn hash_token(token: &str) -> Vec<u8> {
+    openssl::sha::sha256(token.as_bytes()).to_vec()
+}
+
+#[get("/me/tokens")]
+async fn get_tokens(user: UserToken, db: DbData) -> ApiResult<web::Json<Vec<models::ApiToken>>> {
+    let tokens = db
+        .send(db::GetApiTokens {
+            span: Span::current(),
+            user_id: user.claims.user_id,
+        })
+        .await??;
+
+    Ok(web::Json(tokens.into_iter().map(|t| t.into()).collect()))
+}
+
+#[post("/me/tokens")]
+async fn create_token(
+    user: UserToken,
+    db: DbData,
+    req: web::Json<models::NewApiToken>,
+) -> ApiResult<web::Json<models::CreatedApiToken>> {
+    let req = req.into_inner();
+    let token = generate_token();
+
+    let info = db
+        .send(db::CreateApiToken {
+            span: Span::current(),
+            token: db_models::NewApiToken {
+                user_id: user.claims.user_id,
+                name: req.name,
+                token_hash: hash_token(&token),
+                scopes: req.scopes,
+                created_at: Utc::now().naive_utc(),
+                expires_at: req.expires_at.map(|t| t.naive_utc()),
+            },
+        })
+        .await??;
+
+    info!(
+        "User {:?} created API token {:?} with scopes {:?}",
+        user.claims.username, info.id, info.scopes
+    );
+
+    Ok(web::Json(models::CreatedApiToken {
+        token,
+        info: info.into(),
+    }))
+}
+
+#[delete("/me/tokens/{token_id}")]
+async fn revoke_token(
+    user: UserToken,
+    db: DbData,
+    req: web::Path<models::ApiTokenRef>,
+) -> ApiResult<web::Json<HashMap<(), ()>>> {
+    let req = req.into_inner();
+    db.send(db::RevokeApiToken {
+        span: Span::current(),
+        user_id: user.claims.user_id,
+        token_id: req.token_id,
+    })
+    .await??;
+
+    info!(
+        "User {:?} revoked API token {:?}",
+        user.claims.username, req.token_id
+    );
+
+    Ok(web::Json(HashMap::new()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generate_token() {
+        let token = generate_token();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_ne!(token, generate_token());
+
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_eq!(hash_token(&token).len(), 32);
+    }
+}
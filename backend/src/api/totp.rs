@@ -0,0 +1,612 @@
+//! TOTP (RFC 6238) second factor for teachers and admins
+//!
+//! After the primary login such users only get a short-lived cookie, which is exchanged for the
+//!   session cookie at `/api/totp/verify` with a code from their authenticator app or a recovery code.
+
+use crate::api::auth::{Authority, TokenClaims, UserToken};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::models;
+use crate::db;
+use crate::db::models::{self as db_models, Role, UserId};
+use crate::db::DbData;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::StatusCode;
+use actix_web::web::ServiceConfig;
+use actix_web::{delete, post, web, HttpRequest, HttpResponse};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use hmac::Mac;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::collections::HashMap;
+use tracing::{info, warn, Span};
+use url::Url;
+
+pub const PENDING_COOKIE_NAME: &str = "second_factor";
+/// How long the user has to enter the code after the primary login
+const PENDING_DURATION_MINUTES: i64 = 5;
+/// Users with this role or a higher one can enroll, students are exempt
+const REQUIRED_ROLE: Role = Role::Teacher;
+
+const SECRET_SIZE: usize = 20;
+const PERIOD_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from this many periods before or after the current one are accepted, to allow for clock skew
+const ALLOWED_SKEW_STEPS: i64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_SIZE: usize = 10;
+
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+const LOCKOUT_MINUTES: i64 = 15;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Base64-encoded 32 byte key the secrets are encrypted with, e.g. from `openssl rand -base64 32`
+    pub encryption_key: String,
+    /// Shown next to the account in the authenticator app
+    #[serde(default = "default_issuer")]
+    pub issuer: String,
+    /// Where the browser is sent by the logins through a provider when the user still has to verify their
+    ///   second factor. The page should post the code to `/api/totp/verify`
+    #[serde(default = "default_verify_page")]
+    pub verify_page: String,
+}
+
+fn default_issuer() -> String {
+    "BAAM".to_string()
+}
+fn default_verify_page() -> String {
+    "/totp".to_string()
+}
+
+#[derive(Debug)]
+pub enum TotpError {
+    NotEnrolled,
+    AlreadyEnrolled,
+    InvalidCode,
+    LockedOut,
+    NoPendingLogin,
+}
+
+impl ApiError for TotpError {
+    fn to_http(&self) -> (StatusCode, String) {
+        match self {
+            TotpError::NotEnrolled => (
+                StatusCode::BAD_REQUEST,
+                "Second factor is not set up".to_string(),
+            ),
+            TotpError::AlreadyEnrolled => (
+                StatusCode::BAD_REQUEST,
+                "Second factor is already set up, remove it first".to_string(),
+            ),
+            TotpError::InvalidCode => (StatusCode::UNAUTHORIZED, "Invalid code".to_string()),
+            TotpError::LockedOut => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many invalid codes, please try again later".to_string(),
+            ),
+            TotpError::NoPendingLogin => (
+                StatusCode::BAD_REQUEST,
+                "Login was not started or has expired, please log in again".to_string(),
+            ),
+        }
+    }
+}
+
+/// The user who has passed the primary login, stored in a signed cookie until they verify the second factor
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(super) struct PendingLogin {
+    username: String,
+    name: Option<String>,
+}
+
+impl TokenClaims for PendingLogin {
+    const PURPOSE: &'static str = "second_factor";
+}
+
+pub struct Data {
+    config: Config,
+    key: Vec<u8>,
+}
+
+impl Data {
+    fn new(config: Config) -> Result<Self> {
+        let key = base64::decode(&config.encryption_key).context("Decoding TOTP encryption key")?;
+        if key.len() != Cipher::aes_256_gcm().key_len() {
+            bail!("TOTP encryption key must be 32 bytes long");
+        }
+        Ok(Self { config, key })
+    }
+
+    pub(super) fn verify_page(&self) -> &str {
+        &self.config.verify_page
+    }
+
+    /// Encrypt the secret with AES-256-GCM, bound to the user so that it can't be moved to another one
+    fn encrypt(&self, user_id: UserId, secret: &[u8]) -> Result<Vec<u8>> {
+        use rand::{rngs::OsRng, RngCore};
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut tag = [0u8; TAG_SIZE];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &user_id.0.to_be_bytes(),
+            secret,
+            &mut tag,
+        )
+        .context("Encrypting TOTP secret")?;
+
+        Ok([&nonce[..], &ciphertext, &tag].concat())
+    }
+
+    fn decrypt(&self, user_id: UserId, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < NONCE_SIZE + TAG_SIZE {
+            bail!("Encrypted TOTP secret is too short");
+        }
+        let (nonce, rest) = data.split_at(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            &user_id.0.to_be_bytes(),
+            ciphertext,
+            tag,
+        )
+        .context("Decrypting TOTP secret, was the encryption key changed?")
+    }
+}
+
+/// HOTP value (RFC 4226) of the counter
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut hmac = hmac::SimpleHmac::<Sha1>::new_from_slice(secret)
+        .expect("BUG: HMAC accepts keys of any size");
+    hmac.update(&counter.to_be_bytes());
+    let hash = hmac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+fn current_step(now: DateTime<Utc>) -> i64 {
+    now.timestamp() / PERIOD_SECONDS
+}
+
+/// Find the time step the code was generated for, ignoring the steps that were already used
+fn verify_code(secret: &[u8], code: u32, step: i64, last_used_step: Option<i64>) -> Option<i64> {
+    (step - ALLOWED_SKEW_STEPS..=step + ALLOWED_SKEW_STEPS)
+        .filter(|s| !matches!(last_used_step, Some(last) if *s <= last))
+        .find(|s| hotp(secret, *s as u64) == code)
+}
+
+/// Parse a code from the authenticator app, `None` if it looks like a recovery code instead
+fn parse_code(code: &str) -> Option<u32> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        code.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Base32 (RFC 4648) without padding, as expected by the authenticator apps
+fn base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut result = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in data {
+        buffer = ((buffer << 8) | byte as u32) & 0xffff;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    result
+}
+
+fn otpauth_uri(issuer: &str, username: &str, secret: &[u8]) -> String {
+    let mut uri = Url::parse("otpauth://totp/").unwrap();
+    uri.set_path(&format!("{}:{}", issuer, username));
+    uri.query_pairs_mut()
+        .append_pair("secret", &base32(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &PERIOD_SECONDS.to_string());
+    uri.to_string()
+}
+
+fn generate_recovery_code() -> String {
+    use rand::{rngs::OsRng, RngCore};
+
+    let mut code = [0u8; RECOVERY_CODE_SIZE];
+    OsRng.fill_bytes(&mut code);
+    let code = base32(&code).to_lowercase();
+    code.as_bytes()
+        .chunks(4)
+        .map(|c| std::str::from_utf8(c).unwrap())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Recovery codes are random, so a fast hash is enough
+fn hash_recovery_code(code: &str) -> Vec<u8> {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    openssl::sha::sha256(code.as_bytes()).to_vec()
+}
+
+fn pending_cookie(authority: &Authority, value: String) -> Cookie<'static> {
+    Cookie::build(PENDING_COOKIE_NAME, value)
+        .path("/api")
+        .secure(authority.cookie_secure())
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish()
+}
+
+fn pending_removal_cookie(authority: &Authority) -> Cookie<'static> {
+    let mut cookie = pending_cookie(authority, String::new());
+    cookie.make_removal();
+    cookie
+}
+
+/// Produce the cookie for verifying the second factor instead of the session cookie, if the user has one
+pub async fn hold_login(
+    db: &DbData,
+    authority: &Authority,
+    user: &db_models::User,
+) -> ApiResult<Option<Cookie<'static>>> {
+    if user.role < REQUIRED_ROLE {
+        return Ok(None);
+    }
+    let totp = db
+        .send(db::GetTotp {
+            span: Span::current(),
+            user_id: user.id,
+        })
+        .await??;
+    if !matches!(totp, Some(totp) if totp.confirmed) {
+        return Ok(None);
+    }
+
+    let (token, _) = authority.sign_token(
+        PendingLogin {
+            username: user.username.clone(),
+            name: user.name.clone(),
+        },
+        Duration::minutes(PENDING_DURATION_MINUTES),
+    )?;
+
+    info!("User {:?} has to verify their second factor", user.username);
+
+    Ok(Some(pending_cookie(authority, token)))
+}
+
+/// Check a code from the authenticator app or a recovery code, locking the user out after too many wrong ones
+async fn check_second_factor(
+    db: &DbData,
+    data: &Data,
+    user_id: UserId,
+    code: &str,
+) -> ApiResult<()> {
+    let totp = db
+        .send(db::GetTotp {
+            span: Span::current(),
+            user_id,
+        })
+        .await??
+        .filter(|totp| totp.confirmed)
+        .ok_or(TotpError::NotEnrolled)?;
+
+    let now = Utc::now();
+    // counted before the check, so that concurrent requests can't make more attempts
+    let attempt = db
+        .send(db::CountTotpAttempt {
+            span: Span::current(),
+            user_id,
+            max_failed_attempts: MAX_FAILED_ATTEMPTS,
+            lockout_until: (now + Duration::minutes(LOCKOUT_MINUTES)).naive_utc(),
+        })
+        .await??
+        .ok_or(TotpError::LockedOut)?;
+    if attempt >= MAX_FAILED_ATTEMPTS {
+        warn!("Too many invalid second factor codes for {:?}", user_id);
+    }
+
+    let accepted = match parse_code(code) {
+        Some(code) => {
+            let secret = data.decrypt(user_id, &totp.secret)?;
+            match verify_code(&secret, code, current_step(now), totp.last_used_step) {
+                // fails if a concurrent request has used the same code
+                Some(step) => {
+                    db.send(db::UseTotpStep {
+                        span: Span::current(),
+                        user_id,
+                        step,
+                    })
+                    .await??
+                }
+                None => false,
+            }
+        }
+        None => {
+            db.send(db::UseRecoveryCode {
+                span: Span::current(),
+                user_id,
+                code_hash: hash_recovery_code(code),
+            })
+            .await??
+        }
+    };
+
+    if !accepted {
+        return Err(TotpError::InvalidCode.into());
+    }
+
+    db.send(db::ResetTotpAttempts {
+        span: Span::current(),
+        user_id,
+    })
+    .await??;
+
+    Ok(())
+}
+
+/// The user from the second factor cookie, if it is there and valid
+pub(super) fn read_pending_login(
+    req: &HttpRequest,
+    authority: &Authority,
+) -> Result<PendingLogin, TotpError> {
+    let pending = req
+        .cookie(PENDING_COOKIE_NAME)
+        .ok_or(TotpError::NoPendingLogin)?;
+    Ok(authority
+        .validate_token::<PendingLogin>(pending.value())
+        .map_err(|e| {
+            warn!("Could not validate the second factor cookie: {:?}", e);
+            TotpError::NoPendingLogin
+        })?
+        .custom)
+}
+
+#[post("/totp/verify")]
+async fn verify(
+    req: HttpRequest,
+    db: DbData,
+    data: web::Data<Data>,
+    authority: web::Data<Authority>,
+    body: web::Json<models::TotpCode>,
+) -> ApiResult<HttpResponse> {
+    let pending = read_pending_login(&req, &authority)?;
+
+    let user = db
+        .send(db::GetOrCreateUser {
+            span: Span::current(),
+            name: pending.name.unwrap_or_else(|| pending.username.clone()),
+            username: pending.username,
+        })
+        .await??;
+
+    check_second_factor(&db, &data, user.id, &body.code).await?;
+
+    info!("User {:?} verified their second factor", user.username);
+
+    let cookie = super::issue_session_cookie(&req, &db, &authority, user).await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .cookie(pending_removal_cookie(&authority))
+        .json(HashMap::<(), ()>::new()))
+}
+
+/// Generate a new secret. It is used for logging in only after it is confirmed with a code
+#[post("/me/totp")]
+async fn enroll(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    data: web::Data<Data>,
+) -> ApiResult<web::Json<models::TotpEnrollment>> {
+    user.require_role(REQUIRED_ROLE)?;
+
+    let totp = db
+        .send(db::GetTotp {
+            span: Span::current(),
+            user_id: user.user_id,
+        })
+        .await??;
+    if matches!(totp, Some(totp) if totp.confirmed) {
+        return Err(TotpError::AlreadyEnrolled.into());
+    }
+
+    use rand::{rngs::OsRng, RngCore};
+    let mut secret = [0u8; SECRET_SIZE];
+    OsRng.fill_bytes(&mut secret);
+
+    db.send(db::SetTotpSecret {
+        span: Span::current(),
+        user_id: user.user_id,
+        secret: data.encrypt(user.user_id, &secret)?,
+    })
+    .await??;
+
+    Ok(web::Json(models::TotpEnrollment {
+        secret: base32(&secret),
+        uri: otpauth_uri(&data.config.issuer, &user.username, &secret),
+    }))
+}
+
+/// Confirm the new secret with a code from it, producing the recovery codes
+#[post("/me/totp/confirm")]
+async fn confirm(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    data: web::Data<Data>,
+    body: web::Json<models::TotpCode>,
+) -> ApiResult<web::Json<models::RecoveryCodes>> {
+    let totp = db
+        .send(db::GetTotp {
+            span: Span::current(),
+            user_id: user.user_id,
+        })
+        .await??
+        .ok_or(TotpError::NotEnrolled)?;
+    if totp.confirmed {
+        return Err(TotpError::AlreadyEnrolled.into());
+    }
+
+    let secret = data.decrypt(user.user_id, &totp.secret)?;
+    let used_step = parse_code(&body.code)
+        .and_then(|code| verify_code(&secret, code, current_step(Utc::now()), None))
+        .ok_or(TotpError::InvalidCode)?;
+
+    let recovery_codes: Vec<_> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    db.send(db::ConfirmTotp {
+        span: Span::current(),
+        user_id: user.user_id,
+        used_step,
+        recovery_code_hashes: recovery_codes
+            .iter()
+            .map(|c| hash_recovery_code(c))
+            .collect(),
+    })
+    .await??;
+
+    info!("User {:?} set up their second factor", user.username);
+
+    Ok(web::Json(models::RecoveryCodes { recovery_codes }))
+}
+
+/// Turn the second factor off, which needs a code or a recovery code, so that a stolen session is not enough
+#[delete("/me/totp")]
+async fn remove(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    data: web::Data<Data>,
+    body: web::Json<models::TotpCode>,
+) -> ApiResult<web::Json<HashMap<(), ()>>> {
+    check_second_factor(&db, &data, user.user_id, &body.code).await?;
+
+    db.send(db::DeleteTotp {
+        span: Span::current(),
+        user_id: user.user_id,
+    })
+    .await??;
+
+    info!("User {:?} removed their second factor", user.username);
+
+    Ok(web::Json(HashMap::new()))
+}
+
+pub fn configure(config: Option<Config>) -> Result<impl Fn(&mut ServiceConfig) + Clone> {
+    let data = config.map(Data::new).transpose()?.map(web::Data::new);
+
+    Ok(move |cfg: &mut ServiceConfig| {
+        if let Some(data) = &data {
+            cfg.app_data(data.clone())
+                .service(verify)
+                .service(enroll)
+                .service(confirm)
+                .service(remove);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_rfc_6238_vectors() {
+        let secret = b"12345678901234567890";
+        let step = |timestamp: i64| current_step(Utc.timestamp_opt(timestamp, 0).unwrap());
+
+        // the RFC lists 8 digit codes, these are their last 6 digits
+        assert_eq!(hotp(secret, step(59) as u64), 287082);
+        assert_eq!(hotp(secret, step(1111111109) as u64), 81804);
+        assert_eq!(hotp(secret, step(1111111111) as u64), 50471);
+        assert_eq!(hotp(secret, step(1234567890) as u64), 5924);
+        assert_eq!(hotp(secret, step(2000000000) as u64), 279037);
+        assert_eq!(hotp(secret, step(20000000000) as u64), 353130);
+    }
+
+    #[test]
+    fn test_verify_code() {
+        let secret = b"12345678901234567890";
+        let step = 1000;
+        let code = hotp(secret, step as u64);
+
+        assert_eq!(verify_code(secret, code, step, None), Some(step));
+        // clock skew
+        assert_eq!(verify_code(secret, code, step + 1, None), Some(step));
+        assert_eq!(verify_code(secret, code, step + 2, None), None);
+        // replay
+        assert_eq!(verify_code(secret, code, step, Some(step)), None);
+
+        assert_eq!(parse_code("028 705"), Some(28705));
+        assert_eq!(parse_code("abcd-efgh"), None);
+    }
+
+    #[test]
+    fn test_base32() {
+        // RFC 4648 test vectors, without padding
+        assert_eq!(base32(b""), "");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"fo"), "MZXQ");
+        assert_eq!(base32(b"foo"), "MZXW6");
+        assert_eq!(base32(b"foob"), "MZXW6YQ");
+        assert_eq!(base32(b"fooba"), "MZXW6YTB");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+
+        assert_eq!(
+            otpauth_uri("BAAM", "teacher", b"foobar"),
+            "otpauth://totp/BAAM:teacher?secret=MZXW6YTBOI&issuer=BAAM&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_secret_encryption() {
+        let data = Data::new(Config {
+            encryption_key: base64::encode([7u8; 32]),
+            issuer: default_issuer(),
+            verify_page: default_verify_page(),
+        })
+        .unwrap();
+
+        let encrypted = data.encrypt(UserId(1), b"secret").unwrap();
+        assert_eq!(data.decrypt(UserId(1), &encrypted).unwrap(), b"secret");
+        // a secret copied to another user is not accepted
+        assert!(data.decrypt(UserId(2), &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), 16 + 3);
+        assert_eq!(
+            hash_recovery_code(&code),
+            hash_recovery_code(&code.replace('-', "").to_uppercase())
+        );
+    }
+}
@@ -0,0 +1,616 @@
+//! Passkey (WebAuthn) login for staff users
+//!
+//! Both ceremonies are split into a start and a finish request. The state in between is kept in a
+//!   short-lived signed cookie, like the OpenID Connect login flow. The login state has an id that is
+//!   remembered once the login is finished, so that the cookie can't be replayed.
+
+use crate::api::auth::{Authority, TokenClaims, UserToken};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::models;
+use crate::db;
+use crate::db::models::{self as db_models, Role, UserId};
+use crate::db::DbData;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::StatusCode;
+use actix_web::web::ServiceConfig;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use anyhow::{anyhow, Context, Result};
+use chrono::{Duration, Utc};
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use tracing::{info, warn, Span};
+use url::Url;
+use webauthn_rs::prelude::{
+    CredentialID, Passkey, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential, Uuid,
+};
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+use webauthn_rs_proto::AllowCredentials;
+
+const FLOW_COOKIE_NAME: &str = "webauthn_flow";
+/// How long the user has to complete a ceremony with their authenticator
+const FLOW_DURATION_MINUTES: i64 = 5;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Domain the passkeys are bound to, e.g. `baam.example.com`
+    pub rp_id: String,
+    /// Origin the frontend is served from, e.g. `https://baam.example.com`
+    pub rp_origin: Url,
+    /// Shown by the authenticator when creating a passkey
+    #[serde(default = "default_rp_name")]
+    pub rp_name: String,
+}
+
+fn default_rp_name() -> String {
+    "BAAM".to_string()
+}
+
+#[derive(Debug)]
+pub enum PasskeyError {
+    NoFlowCookie,
+    InvalidFlow,
+    NoPasskeys,
+    VerificationFailed,
+}
+
+impl ApiError for PasskeyError {
+    fn to_http(&self) -> (StatusCode, String) {
+        match self {
+            PasskeyError::NoFlowCookie | PasskeyError::InvalidFlow => (
+                StatusCode::BAD_REQUEST,
+                "Passkey ceremony was not started or has expired, please try again".to_string(),
+            ),
+            PasskeyError::NoPasskeys => (
+                StatusCode::UNAUTHORIZED,
+                "This user can't log in with a passkey".to_string(),
+            ),
+            PasskeyError::VerificationFailed => (
+                StatusCode::UNAUTHORIZED,
+                "Passkey verification failed".to_string(),
+            ),
+        }
+    }
+}
+
+/// Registration state, stored in a signed cookie between the start and the finish requests
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RegistrationState {
+    user_id: UserId,
+    registration: PasskeyRegistration,
+}
+
+impl TokenClaims for RegistrationState {
+    const PURPOSE: &'static str = "webauthn_registration";
+}
+
+/// Authentication state, stored in a signed cookie between the start and the finish requests
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AuthenticationState {
+    username: String,
+    authentication: PasskeyAuthentication,
+    jti: String,
+}
+
+impl TokenClaims for AuthenticationState {
+    const PURPOSE: &'static str = "webauthn_login";
+}
+
+#[derive(Deserialize, Debug)]
+struct FinishRegistrationRequest {
+    /// Given by the user to tell their passkeys apart
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Deserialize, Debug)]
+struct StartLoginRequest {
+    username: String,
+}
+
+fn build(config: &Config) -> Result<Webauthn> {
+    WebauthnBuilder::new(&config.rp_id, &config.rp_origin)
+        .context("Invalid WebAuthn relying party")?
+        .rp_name(&config.rp_name)
+        .build()
+        .context("Building WebAuthn relying party")
+}
+
+/// WebAuthn user handle, the authenticators use it to tell the accounts apart
+fn user_handle(user_id: UserId) -> Uuid {
+    Uuid::from_u128(user_id.0 as u128)
+}
+
+/// Made up credentials for the users that can't log in with a passkey
+///
+/// `start_login` answers with them instead of an error, so that it can't be used to find out which
+///   usernames exist or have passkeys. The ids are derived from the username with a random key, so
+///   that asking twice gives the same answer, like it would for a real user.
+struct DummyCredentials {
+    key: [u8; 32],
+}
+
+impl DummyCredentials {
+    fn new() -> Self {
+        use rand::{rngs::OsRng, RngCore};
+
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self { key }
+    }
+
+    fn allow_credential(&self, username: &str) -> AllowCredentials {
+        let mut hmac = hmac::Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        hmac.update(username.as_bytes());
+
+        AllowCredentials {
+            type_: "public-key".to_string(),
+            id: CredentialID::from(hmac.finalize().into_bytes().to_vec()),
+            transports: None,
+        }
+    }
+}
+
+fn parse_passkey(credential: &db_models::WebauthnCredential) -> Result<Passkey> {
+    serde_json::from_str(&credential.passkey)
+        .with_context(|| format!("Parsing stored passkey {:?}", credential.id))
+}
+
+fn flow_cookie(authority: &Authority, value: String) -> Cookie<'static> {
+    Cookie::build(FLOW_COOKIE_NAME, value)
+        .path("/api/webauthn")
+        .secure(authority.cookie_secure())
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish()
+}
+
+fn flow_removal_cookie(authority: &Authority) -> Cookie<'static> {
+    let mut cookie = flow_cookie(authority, String::new());
+    cookie.make_removal();
+    cookie
+}
+
+fn read_flow<T: TokenClaims>(
+    req: &HttpRequest,
+    authority: &Authority,
+) -> Result<jwt_compact::Claims<T>, PasskeyError> {
+    let flow = req
+        .cookie(FLOW_COOKIE_NAME)
+        .ok_or(PasskeyError::NoFlowCookie)?;
+    authority.validate_token::<T>(flow.value()).map_err(|e| {
+        warn!("Could not validate the WebAuthn flow cookie: {:?}", e);
+        PasskeyError::InvalidFlow
+    })
+}
+
+#[post("/webauthn/register/start")]
+async fn start_registration(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    webauthn: web::Data<Webauthn>,
+    authority: web::Data<Authority>,
+) -> ApiResult<HttpResponse> {
+    user.require_role(Role::TeachingAssistant)?;
+
+    let (_, credentials) = db
+        .send(db::GetWebauthnCredentials {
+            span: Span::current(),
+            username: user.username.clone(),
+        })
+        .await??
+        .ok_or_else(|| anyhow!("Logged in user does not exist??"))?;
+    // so that the same authenticator is not registered twice
+    let exclude_credentials = credentials
+        .into_iter()
+        .map(|c| CredentialID::from(c.credential_id))
+        .collect();
+
+    let (challenge, registration) = webauthn
+        .start_passkey_registration(
+            user_handle(user.user_id),
+            &user.username,
+            &user.name,
+            Some(exclude_credentials),
+        )
+        .context("Starting passkey registration")?;
+
+    let (flow_token, _) = authority.sign_token(
+        RegistrationState {
+            user_id: user.user_id,
+            registration,
+        },
+        Duration::minutes(FLOW_DURATION_MINUTES),
+    )?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(flow_cookie(&authority, flow_token))
+        .json(challenge))
+}
+
+#[post("/webauthn/register/finish")]
+async fn finish_registration(
+    req: HttpRequest,
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    webauthn: web::Data<Webauthn>,
+    authority: web::Data<Authority>,
+    body: web::Json<FinishRegistrationRequest>,
+) -> ApiResult<HttpResponse> {
+    let body = body.into_inner();
+
+    let flow: RegistrationState = read_flow(&req, &authority)?.custom;
+    if flow.user_id != user.user_id {
+        return Err(PasskeyError::InvalidFlow.into());
+    }
+
+    let passkey = webauthn
+        .finish_passkey_registration(&body.credential, &flow.registration)
+        .map_err(|e| {
+            warn!(
+                "Passkey registration of {:?} failed: {:?}",
+                user.username, e
+            );
+            PasskeyError::VerificationFailed
+        })?;
+
+    let credential = db
+        .send(db::AddWebauthnCredential {
+            span: Span::current(),
+            credential: db_models::NewWebauthnCredential {
+                user_id: user.user_id,
+                name: body.name,
+                credential_id: passkey.cred_id().0.clone(),
+                passkey: serde_json::to_string(&passkey).context("Serializing passkey")?,
+                created_at: Utc::now().naive_utc(),
+            },
+        })
+        .await??;
+
+    info!(
+        "User {:?} registered passkey {:?}",
+        user.username, credential.id
+    );
+
+    Ok(HttpResponse::Ok()
+        .cookie(flow_removal_cookie(&authority))
+        .json(models::Passkey::from(credential)))
+}
+
+#[post("/webauthn/login/start")]
+async fn start_login(
+    db: DbData,
+    webauthn: web::Data<Webauthn>,
+    dummy_credentials: web::Data<DummyCredentials>,
+    authority: web::Data<Authority>,
+    body: web::Json<StartLoginRequest>,
+) -> ApiResult<HttpResponse> {
+    let username = body.into_inner().username;
+
+    let credentials = db
+        .send(db::GetWebauthnCredentials {
+            span: Span::current(),
+            username: username.clone(),
+        })
+        .await??
+        .filter(|(user, _)| user.role >= Role::TeachingAssistant)
+        .map(|(_, credentials)| credentials)
+        .unwrap_or_default();
+
+    let passkeys = credentials
+        .iter()
+        .map(parse_passkey)
+        .collect::<Result<Vec<_>>>()?;
+    let (mut challenge, authentication) = webauthn
+        .start_passkey_authentication(&passkeys)
+        .context("Starting passkey authentication")?;
+    if passkeys.is_empty() {
+        // no passkey is allowed by the state, so `finish_login` will fail
+        challenge
+            .public_key
+            .allow_credentials
+            .push(dummy_credentials.allow_credential(&username));
+    }
+
+    let (flow_token, _) = authority.sign_token(
+        AuthenticationState {
+            username,
+            authentication,
+            jti: super::auth::new_token_id(),
+        },
+        Duration::minutes(FLOW_DURATION_MINUTES),
+    )?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(flow_cookie(&authority, flow_token))
+        .json(challenge))
+}
+
+#[post("/webauthn/login/finish")]
+async fn finish_login(
+    req: HttpRequest,
+    db: DbData,
+    webauthn: web::Data<Webauthn>,
+    authority: web::Data<Authority>,
+    credential: web::Json<PublicKeyCredential>,
+) -> ApiResult<HttpResponse> {
+    let claims = read_flow::<AuthenticationState>(&req, &authority)?;
+    let expires_at = claims.expiration.unwrap_or_else(Utc::now);
+    let flow = claims.custom;
+
+    // used up even if the verification fails, the user has to start over then
+    let first_use = db
+        .send(db::UseWebauthnFlow {
+            span: Span::current(),
+            jti: flow.jti.clone(),
+            username: flow.username.clone(),
+            expires_at: expires_at.naive_utc(),
+        })
+        .await??;
+    if !first_use {
+        warn!("Passkey login flow of {:?} was used again", flow.username);
+        return Err(PasskeyError::InvalidFlow.into());
+    }
+
+    let result = webauthn
+        .finish_passkey_authentication(&credential, &flow.authentication)
+        .map_err(|e| {
+            warn!("Passkey login of {:?} failed: {:?}", flow.username, e);
+            PasskeyError::VerificationFailed
+        })?;
+
+    // the passkey or the role might have been taken away since the start of the login
+    let (user, credentials) = db
+        .send(db::GetWebauthnCredentials {
+            span: Span::current(),
+            username: flow.username.clone(),
+        })
+        .await??
+        .ok_or(PasskeyError::NoPasskeys)?;
+    if user.role < Role::TeachingAssistant {
+        return Err(PasskeyError::NoPasskeys.into());
+    }
+    let stored = credentials
+        .iter()
+        .find(|c| c.credential_id == result.cred_id().0)
+        .ok_or(PasskeyError::VerificationFailed)?;
+
+    let mut passkey = parse_passkey(stored)?;
+    let updated_passkey = match passkey.update_credential(&result) {
+        Some(true) => Some(serde_json::to_string(&passkey).context("Serializing passkey")?),
+        _ => None,
+    };
+    db.send(db::UpdateWebauthnCredential {
+        span: Span::current(),
+        credential_id: stored.credential_id.clone(),
+        passkey: updated_passkey,
+        used_at: Utc::now().naive_utc(),
+    })
+    .await??;
+
+    info!("User {:?} logged in with a passkey", user.username);
+
+    // passkeys are already a second factor, so there is no need to ask for a TOTP code
+    let cookie = super::issue_session_cookie(&req, &db, &authority, user).await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .cookie(flow_removal_cookie(&authority))
+        .json(HashMap::<(), ()>::new()))
+}
+
+#[get("/me/passkeys")]
+async fn get_passkeys(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+) -> ApiResult<web::Json<Vec<models::Passkey>>> {
+    let credentials = db
+        .send(db::GetWebauthnCredentials {
+            span: Span::current(),
+            username: user.username,
+        })
+        .await??
+        .map(|(_, credentials)| credentials)
+        .unwrap_or_default();
+
+    Ok(web::Json(
+        credentials.into_iter().map(|c| c.into()).collect(),
+    ))
+}
+
+#[delete("/me/passkeys/{passkey_id}")]
+async fn delete_passkey(
+    UserToken { claims: user, .. }: UserToken,
+    db: DbData,
+    req: web::Path<models::PasskeyRef>,
+) -> ApiResult<web::Json<HashMap<(), ()>>> {
+    let req = req.into_inner();
+    db.send(db::DeleteWebauthnCredential {
+        span: Span::current(),
+        user_id: user.user_id,
+        id: req.passkey_id,
+    })
+    .await??;
+
+    info!(
+        "User {:?} deleted passkey {:?}",
+        user.username, req.passkey_id
+    );
+
+    Ok(web::Json(HashMap::new()))
+}
+
+pub fn configure(config: Option<Config>) -> Result<impl Fn(&mut ServiceConfig) + Clone> {
+    let webauthn = config.as_ref().map(build).transpose()?.map(web::Data::new);
+    let dummy_credentials = web::Data::new(DummyCredentials::new());
+
+    Ok(move |cfg: &mut ServiceConfig| {
+        if let Some(webauthn) = &webauthn {
+            cfg.app_data(webauthn.clone())
+                .app_data(dummy_credentials.clone())
+                .service(start_registration)
+                .service(finish_registration)
+                .service(start_login)
+                .service(finish_login)
+                .service(get_passkeys)
+                .service(delete_passkey);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::{auth, totp};
+    use actix_web::test::TestRequest;
+    use serde::de::DeserializeOwned;
+    use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+    use webauthn_authenticator_rs::WebauthnAuthenticator;
+    use webauthn_rs::prelude::RequestChallengeResponse;
+
+    fn config() -> Config {
+        Config {
+            rp_id: "localhost".to_string(),
+            rp_origin: Url::parse("http://localhost:8080").unwrap(),
+            rp_name: default_rp_name(),
+        }
+    }
+
+    /// The states and the passkeys are serialized into the flow cookie and the database
+    fn roundtrip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_soft_passkey() {
+        let config = config();
+        let webauthn = build(&config).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+
+        let (challenge, registration) = webauthn
+            .start_passkey_registration(user_handle(UserId(1)), "teacher", "Teacher", None)
+            .unwrap();
+        let credential = authenticator
+            .do_registration(config.rp_origin.clone(), challenge)
+            .unwrap();
+        let passkey = webauthn
+            .finish_passkey_registration(&credential, &roundtrip(&registration))
+            .unwrap();
+        let passkey = roundtrip(&passkey);
+
+        let (challenge, authentication) = webauthn
+            .start_passkey_authentication(&[passkey.clone()])
+            .unwrap();
+        let credential = authenticator
+            .do_authentication(config.rp_origin.clone(), challenge)
+            .unwrap();
+        let result = webauthn
+            .finish_passkey_authentication(&credential, &roundtrip(&authentication))
+            .unwrap();
+        assert_eq!(result.cred_id(), passkey.cred_id());
+
+        // an assertion made for another site is not accepted
+        let (challenge, authentication) =
+            webauthn.start_passkey_authentication(&[passkey]).unwrap();
+        let phished = authenticator
+            .do_authentication(Url::parse("http://evil.localhost:8080").unwrap(), challenge);
+        if let Ok(credential) = phished {
+            assert!(webauthn
+                .finish_passkey_authentication(&credential, &authentication)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_dummy_challenge() {
+        let config = config();
+        let webauthn = build(&config).unwrap();
+        let dummy_credentials = DummyCredentials::new();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+
+        let (challenge, registration) = webauthn
+            .start_passkey_registration(user_handle(UserId(1)), "teacher", "Teacher", None)
+            .unwrap();
+        let credential = authenticator
+            .do_registration(config.rp_origin.clone(), challenge)
+            .unwrap();
+        let passkey = webauthn
+            .finish_passkey_registration(&credential, &registration)
+            .unwrap();
+        let (real, _) = webauthn.start_passkey_authentication(&[passkey]).unwrap();
+
+        let (mut dummy, authentication) = webauthn.start_passkey_authentication(&[]).unwrap();
+        dummy
+            .public_key
+            .allow_credentials
+            .push(dummy_credentials.allow_credential("nobody"));
+
+        // the same for the same username, so that asking twice does not give it away
+        let allowed = &dummy.public_key.allow_credentials[0];
+        assert_eq!(allowed.id, dummy_credentials.allow_credential("nobody").id);
+        assert_ne!(
+            allowed.id,
+            dummy_credentials.allow_credential("somebody").id
+        );
+
+        let shape = |challenge: &RequestChallengeResponse| {
+            let mut value = serde_json::to_value(challenge).unwrap();
+            value["publicKey"]["challenge"] = serde_json::Value::Null;
+            value["publicKey"]["allowCredentials"][0]["id"] = serde_json::Value::Null;
+            value
+        };
+        assert_eq!(shape(&dummy), shape(&real));
+
+        // even a valid assertion is not accepted for a made up credential
+        let credential = authenticator
+            .do_authentication(config.rp_origin.clone(), real)
+            .unwrap();
+        assert!(webauthn
+            .finish_passkey_authentication(&credential, &roundtrip(&authentication))
+            .is_err());
+    }
+
+    #[test]
+    fn test_flow_token_is_not_a_pending_login() {
+        let authority =
+            auth::test::authority(&auth::test::config(std::time::Duration::from_secs(60 * 60)));
+        let webauthn = build(&config()).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+
+        let (challenge, registration) = webauthn
+            .start_passkey_registration(user_handle(UserId(1)), "teacher", "Teacher", None)
+            .unwrap();
+        let credential = authenticator
+            .do_registration(config().rp_origin, challenge)
+            .unwrap();
+        let passkey = webauthn
+            .finish_passkey_registration(&credential, &registration)
+            .unwrap();
+        let (_, authentication) = webauthn.start_passkey_authentication(&[passkey]).unwrap();
+
+        // anyone can get this token for any username from `/api/webauthn/login/start`
+        let (flow_token, _) = authority
+            .sign_token(
+                AuthenticationState {
+                    username: "teacher".to_string(),
+                    authentication,
+                    jti: auth::new_token_id(),
+                },
+                Duration::minutes(FLOW_DURATION_MINUTES),
+            )
+            .unwrap();
+        assert!(authority
+            .validate_token::<AuthenticationState>(&flow_token)
+            .is_ok());
+
+        let req = TestRequest::default()
+            .cookie(Cookie::new(totp::PENDING_COOKIE_NAME, flow_token))
+            .to_http_request();
+        assert!(matches!(
+            totp::read_pending_login(&req, &authority),
+            Err(totp::TotpError::NoPendingLogin)
+        ));
+    }
+}
@@ -1,13 +1,26 @@
 use anyhow::{Context, Result};
+use ipnet::IpNet;
 use serde::Deserialize;
 use std::net::SocketAddr;
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
+    /// Enables the test login and debug endpoints, refused in the `prod` environment
+    #[serde(default)]
+    pub dev_mode: bool,
     pub frontend: Frontend,
     pub server: Server,
     pub challenge: Challenge,
+    pub auth: Auth,
     pub sentry_tunnel: Option<Sentry>,
+    pub oidc: Option<Oidc>,
+    pub saml: Option<Saml>,
+    pub ldap: Option<Ldap>,
+    pub webauthn: Option<Webauthn>,
+    pub totp: Option<Totp>,
+    pub local_accounts: Option<LocalAccounts>,
+    pub mail: Option<Mail>,
+    pub magic_link: Option<MagicLink>,
 }
 
 impl Config {
@@ -36,7 +49,19 @@ pub type Frontend = baam_frontend::Config;
 #[derive(Deserialize, Clone, Debug)]
 pub struct Server {
     pub endpoint: SocketAddr,
+    /// Networks of the reverse proxies whose `X-Forwarded-For` is believed, none by default
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
 }
 
 pub type Challenge = crate::api::ChallengeConfig;
+pub type Auth = crate::api::auth::Config;
 pub type Sentry = crate::sentry_tunnel::Config;
+pub type Oidc = crate::api::OidcConfig;
+pub type Saml = crate::api::SamlConfig;
+pub type Ldap = crate::api::LdapConfig;
+pub type Webauthn = crate::api::WebauthnConfig;
+pub type Totp = crate::api::TotpConfig;
+pub type LocalAccounts = crate::api::LocalAccountsConfig;
+pub type Mail = crate::mail::Config;
+pub type MagicLink = crate::api::MagicLinkConfig;
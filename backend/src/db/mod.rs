@@ -3,11 +3,16 @@ pub mod models;
 mod schema;
 
 use crate::api::error::{ApiError, ApiResult};
-use crate::db::models::{NewAttendanceMark, NewUser, SessionId, UserId};
+use crate::db::models::{
+    ApiTokenId, AttendanceMarkId, ChallengeAlgorithm, GeofencePolicy, GeofencePosition,
+    MarkEventId, MarkEventKind, NetworkPolicy, NewApiToken, NewAttendanceMark, NewLogin,
+    NewMarkEvent, NewPasswordToken, NewUser, NewWebauthnCredential, PasswordTokenPurpose,
+    Permission, Role, SessionId, StudentVisibility, UserId, WebauthnCredentialId,
+};
 use actix::prelude::*;
 use actix_http::StatusCode;
 use anyhow::{Context, Result};
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use diesel::Connection as DieselConnection;
@@ -20,18 +25,41 @@ use tracing::{info, instrument, Span};
 #[derive(Debug)]
 pub enum DbError {
     SessionNotFound,
+    SessionClosed,
     MarkNotFound,
+    LoginNotFound,
+    MemberNotFound,
+    SessionCreatorMember,
+    ApiTokenNotFound,
+    WebauthnCredentialNotFound,
 }
 
 impl ApiError for DbError {
     fn to_http(&self) -> (StatusCode, String) {
         match self {
             DbError::SessionNotFound => (StatusCode::NOT_FOUND, "Session not found".to_string()),
+            DbError::SessionClosed => (
+                StatusCode::CONFLICT,
+                "The session is closed and cannot be reopened".to_string(),
+            ),
             DbError::MarkNotFound => (StatusCode::NOT_FOUND, "Mark not found".to_string()),
+            DbError::LoginNotFound => (StatusCode::NOT_FOUND, "Login not found".to_string()),
+            DbError::MemberNotFound => (StatusCode::NOT_FOUND, "Member not found".to_string()),
+            DbError::ApiTokenNotFound => (StatusCode::NOT_FOUND, "API token not found".to_string()),
+            DbError::WebauthnCredentialNotFound => {
+                (StatusCode::NOT_FOUND, "Passkey not found".to_string())
+            }
+            DbError::SessionCreatorMember => (
+                StatusCode::BAD_REQUEST,
+                "The creator of the session always stays its owner".to_string(),
+            ),
         }
     }
 }
 
+/// Events sent to the attendance feed at once, the rest come with the next poll
+const MAX_MARK_EVENTS: i64 = 500;
+
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
 pub type DbData = actix_web::web::Data<Addr<DbExecutor>>;
@@ -77,14 +105,29 @@ pub struct GetSessions {
     pub span: Span,
     pub user_id: UserId,
 }
-/// Get session, checking its owner
+/// Get session, checking that the user is a member of it
 #[derive(Debug)]
 pub struct GetSession {
     pub span: Span,
-    pub owner_id: UserId,
+    pub user_id: UserId,
+    pub session_id: SessionId,
+}
+/// Get session with its marks and the cursor of the attendance feed at that point
+#[derive(Debug)]
+pub struct GetMarkSnapshot {
+    pub span: Span,
+    pub user_id: UserId,
+    pub session_id: SessionId,
+}
+/// Get the changes of the marks after the cursor of the attendance feed
+#[derive(Debug)]
+pub struct GetMarkEvents {
+    pub span: Span,
+    pub user_id: UserId,
     pub session_id: SessionId,
+    pub after: MarkEventId,
 }
-/// Just get session, without checking its owner
+/// Just get session, without checking its members
 #[derive(Debug)]
 pub struct LookupSession {
     pub span: Span,
@@ -97,33 +140,74 @@ pub struct CreateSession {
     pub title: Option<String>,
     pub start_time: NaiveDateTime,
     pub seed: String,
+    pub challenge_algorithm: ChallengeAlgorithm,
+    pub challenge_tag_length: i32,
+    pub qr_interval_ms: Option<i32>,
+    pub jitter_window_ms: Option<i32>,
+    pub geofence: Option<SessionGeofence>,
+    pub allowed_networks: Option<Vec<String>>,
+    pub network_policy: Option<NetworkPolicy>,
+    pub student_visibility: Option<StudentVisibility>,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct SessionGeofence {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_m: i32,
+    pub policy: GeofencePolicy,
 }
 #[derive(Debug)]
 pub struct DeleteSession {
     pub span: Span,
     pub session_id: SessionId,
-    pub owner_id: UserId,
+    pub user_id: UserId,
+}
+/// Pause, resume or close the session
+#[derive(Debug)]
+pub struct ChangeSessionState {
+    pub span: Span,
+    pub session_id: SessionId,
+    pub user_id: UserId,
+    pub change: SessionStateChange,
+    pub time: NaiveDateTime,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStateChange {
+    Pause,
+    Resume,
+    /// For good, the session keeps only accepting manual marks
+    Close,
 }
 #[derive(Debug)]
 pub struct AddManualAttendanceMark {
     pub span: Span,
     pub session_id: SessionId,
-    pub owner_id: UserId,
+    pub user_id: UserId,
+    /// Of the user adding the mark, for the audit note
+    pub username: String,
     pub student_username: String,
     pub mark_time: NaiveDateTime,
 }
+/// Returns the mark, the latest other students marked in the session and the number of all of them
 #[derive(Debug)]
 pub struct AddAutoAttendanceMark {
     pub span: Span,
     pub session_id: SessionId,
     pub student_id: UserId,
     pub mark_time: NaiveDateTime,
+    pub challenge_index: u32,
+    pub ip: Option<String>,
+    pub late_ms: i32,
+    pub geofence_position: Option<GeofencePosition>,
+    pub distance_m: Option<i32>,
+    /// How many of the students marked before to return, latest first
+    pub recent_students: i64,
 }
 #[derive(Debug)]
 pub struct DeleteAttendanceMark {
     pub span: Span,
     pub session_id: SessionId,
-    pub owner_id: UserId,
+    pub user_id: UserId,
     pub student_username: String,
 }
 #[derive(Debug)]
@@ -132,6 +216,231 @@ pub struct GetOrCreateUser {
     pub username: String,
     pub name: String,
 }
+#[derive(Debug)]
+pub struct CreateLogin {
+    pub span: Span,
+    pub login: NewLogin,
+}
+/// Get login together with its user
+#[derive(Debug)]
+pub struct GetLogin {
+    pub span: Span,
+    pub jti: String,
+}
+/// Get logins of the user that are not revoked or expired
+#[derive(Debug)]
+pub struct GetActiveLogins {
+    pub span: Span,
+    pub user_id: UserId,
+}
+/// Revoke a single login of the user, or all of them if `jti` is `None`
+#[derive(Debug)]
+pub struct RevokeLogins {
+    pub span: Span,
+    pub user_id: UserId,
+    pub jti: Option<String>,
+}
+#[derive(Debug)]
+pub struct CreateApiToken {
+    pub span: Span,
+    pub token: NewApiToken,
+}
+/// Find a usable API token by its hash together with its user, marking it as used
+#[derive(Debug)]
+pub struct UseApiToken {
+    pub span: Span,
+    pub token_hash: Vec<u8>,
+}
+/// Get API tokens of the user that are not revoked, including the expired ones
+#[derive(Debug)]
+pub struct GetApiTokens {
+    pub span: Span,
+    pub user_id: UserId,
+}
+#[derive(Debug)]
+pub struct RevokeApiToken {
+    pub span: Span,
+    pub user_id: UserId,
+    pub token_id: ApiTokenId,
+}
+/// Get the user together with their WebAuthn credentials
+#[derive(Debug)]
+pub struct GetWebauthnCredentials {
+    pub span: Span,
+    pub username: String,
+}
+#[derive(Debug)]
+pub struct AddWebauthnCredential {
+    pub span: Span,
+    pub credential: NewWebauthnCredential,
+}
+/// Record a use of the credential, storing the updated passkey if it has changed
+#[derive(Debug)]
+pub struct UpdateWebauthnCredential {
+    pub span: Span,
+    pub credential_id: Vec<u8>,
+    pub passkey: Option<String>,
+    pub used_at: NaiveDateTime,
+}
+#[derive(Debug)]
+pub struct DeleteWebauthnCredential {
+    pub span: Span,
+    pub user_id: UserId,
+    pub id: WebauthnCredentialId,
+}
+/// Remember that the passkey login ceremony was finished, returns whether it was not finished before
+#[derive(Debug)]
+pub struct UseWebauthnFlow {
+    pub span: Span,
+    pub jti: String,
+    pub username: String,
+    pub expires_at: NaiveDateTime,
+}
+#[derive(Debug)]
+pub struct GetTotp {
+    pub span: Span,
+    pub user_id: UserId,
+}
+/// Store a new, not yet confirmed TOTP secret of the user, replacing the previous one
+#[derive(Debug)]
+pub struct SetTotpSecret {
+    pub span: Span,
+    pub user_id: UserId,
+    pub secret: Vec<u8>,
+}
+/// Start using the TOTP secret for logging in, replacing the recovery codes
+#[derive(Debug)]
+pub struct ConfirmTotp {
+    pub span: Span,
+    pub user_id: UserId,
+    pub used_step: i64,
+    pub recovery_code_hashes: Vec<Vec<u8>>,
+}
+/// Count an attempt to pass the second factor before the code is checked, locking the user out once there were
+///   too many. Returns the number of the attempt, `None` if the user is locked out
+#[derive(Debug)]
+pub struct CountTotpAttempt {
+    pub span: Span,
+    pub user_id: UserId,
+    pub max_failed_attempts: i32,
+    pub lockout_until: NaiveDateTime,
+}
+/// Mark the code of the time step as used, returns whether the step is later than the last used one
+#[derive(Debug)]
+pub struct UseTotpStep {
+    pub span: Span,
+    pub user_id: UserId,
+    pub step: i64,
+}
+/// Forget the failed attempts and the lockout after the second factor is passed
+#[derive(Debug)]
+pub struct ResetTotpAttempts {
+    pub span: Span,
+    pub user_id: UserId,
+}
+/// Mark the recovery code as used, returns whether it was there and unused
+#[derive(Debug)]
+pub struct UseRecoveryCode {
+    pub span: Span,
+    pub user_id: UserId,
+    pub code_hash: Vec<u8>,
+}
+/// Remove the TOTP secret and the recovery codes of the user
+#[derive(Debug)]
+pub struct DeleteTotp {
+    pub span: Span,
+    pub user_id: UserId,
+}
+#[derive(Debug)]
+pub struct GetUser {
+    pub span: Span,
+    pub username: String,
+}
+/// Get the user together with their password, if they have one
+#[derive(Debug)]
+pub struct GetUserPassword {
+    pub span: Span,
+    pub username: String,
+}
+#[derive(Debug)]
+pub struct SetPassword {
+    pub span: Span,
+    pub user_id: UserId,
+    pub password_hash: String,
+}
+/// Count an attempt to log in with the password before it is checked, locking the account once there were
+///   too many. Returns the number of the attempt, `None` if the account is locked
+#[derive(Debug)]
+pub struct CountPasswordAttempt {
+    pub span: Span,
+    pub user_id: UserId,
+    pub max_failed_attempts: i32,
+    pub lockout_until: NaiveDateTime,
+}
+/// Set the failed attempts and the lockout of the password, e.g. reset them after a successful login
+#[derive(Debug)]
+pub struct UpdatePasswordAttempts {
+    pub span: Span,
+    pub user_id: UserId,
+    pub failed_attempts: i32,
+    pub locked_until: Option<NaiveDateTime>,
+}
+#[derive(Debug)]
+pub struct CreatePasswordToken {
+    pub span: Span,
+    pub token: NewPasswordToken,
+}
+/// Use the token to set the password of its user, returns `None` if the token is not usable
+#[derive(Debug)]
+pub struct RedeemPasswordToken {
+    pub span: Span,
+    pub token_hash: Vec<u8>,
+    pub purpose: PasswordTokenPurpose,
+    pub password_hash: String,
+}
+/// Remember that the magic link was used, returns whether it was not used before
+#[derive(Debug)]
+pub struct UseMagicLink {
+    pub span: Span,
+    pub jti: String,
+    pub email: String,
+    pub expires_at: NaiveDateTime,
+}
+#[derive(Debug)]
+pub struct GetSessionMembers {
+    pub span: Span,
+    pub session_id: SessionId,
+    pub user_id: UserId,
+}
+/// Add a member to the session or change their permission, creating the user if they have never logged in
+#[derive(Debug)]
+pub struct SetSessionMember {
+    pub span: Span,
+    pub session_id: SessionId,
+    pub user_id: UserId,
+    pub member_username: String,
+    pub permission: Permission,
+}
+/// Remove a member from the session. Owners can remove anyone, other members only themselves
+#[derive(Debug)]
+pub struct RemoveSessionMember {
+    pub span: Span,
+    pub session_id: SessionId,
+    pub user_id: UserId,
+    pub member_username: String,
+}
+/// Get users with a role other than student
+#[derive(Debug)]
+pub struct GetStaff {
+    pub span: Span,
+}
+/// Set role of the user, creating the user if they have never logged in
+#[derive(Debug)]
+pub struct SetUserRole {
+    pub span: Span,
+    pub username: String,
+    pub role: Role,
+}
 
 fn get_or_create_user(
     conn: &mut Connection,
@@ -160,18 +469,138 @@ fn get_or_create_user(
             .context("Failed to insert user")
     })?;
 
-    Ok(user)
+    // users created before their first login (e.g. by `set-role`) get their name when they log in
+    match (&user.name, name_) {
+        (None, Some(name_)) => Ok(diesel::update(users.filter(id.eq(&user.id.0)))
+            .set(name.eq(name_))
+            .get_result::<models::User>(conn)
+            .context("Failed to update user name")?),
+        _ => Ok(user),
+    }
+}
+
+/// Set the password of the user, which also unlocks the account
+fn set_password(conn: &mut Connection, user_id_: UserId, password_hash_: &str) -> ApiResult<()> {
+    use schema::user_passwords::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    diesel::insert_into(user_passwords)
+        .values((
+            user_id.eq(&user_id_.0),
+            password_hash.eq(password_hash_),
+            updated_at.eq(now),
+        ))
+        .on_conflict(user_id)
+        .do_update()
+        .set((
+            password_hash.eq(password_hash_),
+            updated_at.eq(now),
+            failed_attempts.eq(0),
+            locked_until.eq(None as Option<NaiveDateTime>),
+        ))
+        .execute(conn)
+        .context("Failed to set password")?;
+
+    Ok(())
+}
+
+/// Marks of the session along with their users
+fn get_marks(
+    conn: &mut Connection,
+    session_id_: SessionId,
+) -> ApiResult<(
+    HashMap<AttendanceMarkId, models::AttendanceMark>,
+    HashMap<UserId, models::User>,
+)> {
+    let marks: Vec<models::AttendanceMark> = {
+        use schema::marks::dsl::*;
+        marks
+            .filter(session_id.eq(&session_id_.0))
+            .load(conn)
+            .context("Failed to load marks")?
+    };
+
+    let mark_user_ids = marks.iter().map(|m| m.user_id.0).collect::<Vec<_>>();
+
+    let users: Vec<models::User> = {
+        use schema::users::dsl::*;
+        users
+            .filter(id.eq_any(mark_user_ids))
+            .load(conn)
+            .context("Failed to load users")?
+    };
+
+    let marks = marks
+        .into_iter()
+        .map(|m| (m.id, m))
+        .collect::<HashMap<_, _>>();
+
+    let users = users
+        .into_iter()
+        .map(|u| (u.id, u))
+        .collect::<HashMap<_, _>>();
+
+    Ok((marks, users))
+}
+
+/// Record a change of the mark for the attendance feed, in the transaction of the change
+fn record_mark_event(
+    conn: &mut Connection,
+    mark: &models::AttendanceMark,
+    kind: MarkEventKind,
+) -> ApiResult<()> {
+    // the feed reads the events after its cursor, so they have to be committed in the order of their ids.
+    //   Locking the session until the commit makes the changes of its marks take turns
+    {
+        use schema::sessions::dsl::*;
+        sessions
+            .filter(id.eq(&mark.session_id.0))
+            .select(id)
+            .for_update()
+            .first::<i32>(conn)
+            .context("Failed to lock session")?;
+    }
+
+    diesel::insert_into(schema::mark_events::table)
+        .values(NewMarkEvent {
+            session_id: mark.session_id,
+            user_id: mark.user_id,
+            mark_id: mark.id,
+            kind,
+            created_at: Utc::now().naive_utc(),
+        })
+        .execute(conn)
+        .context("Failed to record mark event")?;
+
+    Ok(())
 }
 
+/// Get session, checking that the user has at least the supplied permission in it
 fn get_session(
     conn: &mut Connection,
     session_id_: SessionId,
-    owner_id_: UserId,
+    user_id_: UserId,
+    permission_: Permission,
 ) -> ApiResult<models::Session> {
-    use schema::sessions::dsl::*;
-    sessions
-        .filter(id.eq(&session_id_.0))
-        .filter(owner_id.eq(&owner_id_.0))
+    get_session_with_permission(conn, session_id_, user_id_, permission_)
+        .map(|(session, _)| session)
+}
+
+/// Like [`get_session`], also returning the permission the user actually has
+fn get_session_with_permission(
+    conn: &mut Connection,
+    session_id_: SessionId,
+    user_id_: UserId,
+    permission_: Permission,
+) -> ApiResult<(models::Session, Permission)> {
+    use schema::session_members::dsl::*;
+    use schema::sessions;
+    sessions::table
+        .inner_join(session_members)
+        .filter(sessions::id.eq(&session_id_.0))
+        .filter(user_id.eq(&user_id_.0))
+        .filter(permission.eq_any(permission_.at_least()))
+        .select((sessions::all_columns, permission))
         .first(conn)
         .optional()
         .context("Failed to load session")?
@@ -186,10 +615,13 @@ impl Handler<GetSessions> for DbExecutor {
 
     #[instrument(name = "GetSessions", parent = &msg.span, skip(self))]
     fn handle(&mut self, msg: GetSessions, _: &mut Self::Context) -> Self::Result {
-        use schema::sessions::dsl::*;
+        use schema::session_members::dsl::*;
+        use schema::sessions;
 
-        let results = sessions
-            .filter(owner_id.eq(&msg.user_id.0))
+        let results = sessions::table
+            .inner_join(session_members)
+            .filter(user_id.eq(&msg.user_id.0))
+            .select(sessions::all_columns)
             .load::<models::Session>(&mut self.get_conn()?)
             .context("Failed to load sessions")?;
 
@@ -198,7 +630,7 @@ impl Handler<GetSessions> for DbExecutor {
 }
 
 impl Message for GetSession {
-    type Result = ApiResult<models::SessionWithMarks>;
+    type Result = ApiResult<(models::SessionWithMarks, Permission)>;
 }
 impl Handler<GetSession> for DbExecutor {
     type Result = <GetSession as Message>::Result;
@@ -206,37 +638,82 @@ impl Handler<GetSession> for DbExecutor {
     #[instrument(name = "GetSession", parent = &msg.span, skip(self))]
     fn handle(&mut self, msg: GetSession, _: &mut Self::Context) -> Self::Result {
         self.get_conn()?.transaction(|conn| -> ApiResult<_> {
-            let session = get_session(conn, msg.session_id, msg.owner_id)?;
+            let (session, permission) =
+                get_session_with_permission(conn, msg.session_id, msg.user_id, Permission::Viewer)?;
+            let (marks, users) = get_marks(conn, session.id)?;
 
-            let marks: Vec<models::AttendanceMark> = {
-                use schema::marks::dsl::*;
-                marks
+            Ok(((session, marks, users), permission))
+        })
+    }
+}
+
+impl Message for GetMarkSnapshot {
+    type Result = ApiResult<(models::SessionWithMarks, Permission, MarkEventId)>;
+}
+impl Handler<GetMarkSnapshot> for DbExecutor {
+    type Result = <GetMarkSnapshot as Message>::Result;
+
+    #[instrument(name = "GetMarkSnapshot", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetMarkSnapshot, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            let (session, permission) =
+                get_session_with_permission(conn, msg.session_id, msg.user_id, Permission::Viewer)?;
+
+            // before the marks: the changes in between are then sent again, which the feed clients tolerate,
+            //   instead of being lost
+            let cursor = {
+                use schema::mark_events::dsl::*;
+                mark_events
                     .filter(session_id.eq(&msg.session_id.0))
-                    .load(conn)
-                    .context("Failed to load marks")?
+                    .select(diesel::dsl::max(id))
+                    .first::<Option<i64>>(conn)
+                    .context("Failed to load the last mark event")?
             };
+            let (marks, users) = get_marks(conn, session.id)?;
+
+            Ok((
+                (session, marks, users),
+                permission,
+                MarkEventId(cursor.unwrap_or(0)),
+            ))
+        })
+    }
+}
+
+impl Message for GetMarkEvents {
+    type Result = ApiResult<models::MarkEvents>;
+}
+impl Handler<GetMarkEvents> for DbExecutor {
+    type Result = <GetMarkEvents as Message>::Result;
 
-            let mark_user_ids = marks.iter().map(|m| m.user_id.0).collect::<Vec<_>>();
+    #[instrument(name = "GetMarkEvents", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetMarkEvents, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            // checked every time, so that the feed stops for the removed members
+            let session = get_session(conn, msg.session_id, msg.user_id, Permission::Viewer)?;
 
-            let users: Vec<models::User> = {
-                use schema::users::dsl::*;
-                users
-                    .filter(id.eq_any(mark_user_ids))
+            let events: Vec<(models::MarkEvent, models::User)> = {
+                use schema::mark_events::dsl::*;
+                mark_events
+                    .inner_join(schema::users::table)
+                    .filter(session_id.eq(&msg.session_id.0))
+                    .filter(id.gt(msg.after.0))
+                    .order(id)
+                    .limit(MAX_MARK_EVENTS)
                     .load(conn)
-                    .context("Failed to load users")?
+                    .context("Failed to load mark events")?
             };
 
-            let marks = marks
-                .into_iter()
-                .map(|m| (m.id, m))
-                .collect::<HashMap<_, _>>();
-
-            let users = users
-                .into_iter()
-                .map(|u| (u.id, u))
-                .collect::<HashMap<_, _>>();
+            let marks = if events
+                .iter()
+                .any(|(event, _)| event.kind == MarkEventKind::Added)
+            {
+                get_marks(conn, session.id)?.0
+            } else {
+                HashMap::new()
+            };
 
-            Ok((session, marks, users))
+            Ok((session, events, marks))
         })
     }
 }
@@ -269,19 +746,47 @@ impl Handler<CreateSession> for DbExecutor {
 
     #[instrument(name = "GetOrCreateUser", parent = &msg.span, skip(self))]
     fn handle(&mut self, msg: CreateSession, _: &mut Self::Context) -> Self::Result {
-        use schema::sessions::dsl::*;
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            let session = {
+                use schema::sessions::dsl::*;
 
-        let result = diesel::insert_into(sessions)
-            .values((
-                owner_id.eq(&msg.owner_id.0),
-                title.eq(&msg.title),
-                start_time.eq(&msg.start_time),
-                seed.eq(&msg.seed),
-            ))
-            .get_result::<models::Session>(&mut self.get_conn()?)
-            .context("Failed to create session")?;
+                diesel::insert_into(sessions)
+                    .values((
+                        owner_id.eq(&msg.owner_id.0),
+                        title.eq(&msg.title),
+                        start_time.eq(&msg.start_time),
+                        seed.eq(&msg.seed),
+                        challenge_algorithm.eq(msg.challenge_algorithm),
+                        challenge_tag_length.eq(msg.challenge_tag_length),
+                        qr_interval_ms.eq(msg.qr_interval_ms),
+                        jitter_window_ms.eq(msg.jitter_window_ms),
+                        latitude.eq(msg.geofence.map(|g| g.latitude)),
+                        longitude.eq(msg.geofence.map(|g| g.longitude)),
+                        radius_m.eq(msg.geofence.map(|g| g.radius_m)),
+                        geofence_policy.eq(msg.geofence.map(|g| g.policy)),
+                        allowed_networks.eq(msg.allowed_networks),
+                        network_policy.eq(msg.network_policy),
+                        student_visibility.eq(msg.student_visibility),
+                    ))
+                    .get_result::<models::Session>(conn)
+                    .context("Failed to create session")?
+            };
 
-        Ok(result)
+            {
+                use schema::session_members::dsl::*;
+
+                diesel::insert_into(session_members)
+                    .values((
+                        session_id.eq(&session.id.0),
+                        user_id.eq(&msg.owner_id.0),
+                        permission.eq(Permission::Owner),
+                    ))
+                    .execute(conn)
+                    .context("Failed to add session owner")?;
+            }
+
+            Ok(session)
+        })
     }
 }
 
@@ -294,23 +799,66 @@ impl Handler<DeleteSession> for DbExecutor {
     #[instrument(name = "DeleteSession", parent = &msg.span, skip(self))]
     fn handle(&mut self, msg: DeleteSession, _: &mut Self::Context) -> Self::Result {
         self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            let _session = get_session(conn, msg.session_id, msg.user_id, Permission::Owner)?;
+
+            {
+                use schema::mark_events::dsl::*;
+                diesel::delete(mark_events.filter(session_id.eq(&msg.session_id.0)))
+                    .execute(conn)
+                    .context("Failed to delete session mark events")?;
+            }
             {
                 use schema::marks::dsl::*;
                 diesel::delete(marks.filter(session_id.eq(&msg.session_id.0)))
                     .execute(conn)
                     .context("Failed to delete session marks")?;
             }
+            {
+                use schema::session_members::dsl::*;
+                diesel::delete(session_members.filter(session_id.eq(&msg.session_id.0)))
+                    .execute(conn)
+                    .context("Failed to delete session members")?;
+            }
             {
                 use schema::sessions::dsl::*;
 
-                diesel::delete(
-                    sessions.filter(id.eq(&msg.session_id.0).and(owner_id.eq(&msg.owner_id.0))),
-                )
-                .get_result::<models::Session>(conn)
-                .optional()
-                .context("Failed to delete session")?
+                Ok(diesel::delete(sessions.filter(id.eq(&msg.session_id.0)))
+                    .get_result::<models::Session>(conn)
+                    .context("Failed to delete session")?)
+            }
+        })
+    }
+}
+
+impl Message for ChangeSessionState {
+    type Result = ApiResult<models::Session>;
+}
+impl Handler<ChangeSessionState> for DbExecutor {
+    type Result = <ChangeSessionState as Message>::Result;
+
+    #[instrument(name = "ChangeSessionState", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: ChangeSessionState, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            // markers run the displays, but only the owners can end the session
+            let required = match msg.change {
+                SessionStateChange::Pause | SessionStateChange::Resume => Permission::Marker,
+                SessionStateChange::Close => Permission::Owner,
+            };
+            let session = get_session(conn, msg.session_id, msg.user_id, required)?;
+            if session.end_time.is_some() {
+                return Err(DbError::SessionClosed.into());
             }
-            .ok_or_else(|| DbError::SessionNotFound.into())
+
+            use schema::sessions::dsl::*;
+            let update = diesel::update(sessions.filter(id.eq(&msg.session_id.0)));
+            Ok(match msg.change {
+                SessionStateChange::Pause => update.set(active.eq(false)).get_result(conn),
+                SessionStateChange::Resume => update.set(active.eq(true)).get_result(conn),
+                SessionStateChange::Close => update
+                    .set((active.eq(false), end_time.eq(msg.time)))
+                    .get_result(conn),
+            }
+            .context("Failed to change session state")?)
         })
     }
 }
@@ -326,36 +874,57 @@ impl Handler<AddManualAttendanceMark> for DbExecutor {
         self.get_conn()?.transaction(|conn| -> ApiResult<_> {
             let user = get_or_create_user(conn, &msg.student_username, None)?;
 
-            // check that the session is owned by the supplied owner_id
-            let _session = get_session(conn, msg.session_id, msg.owner_id)?;
+            // check that the user can manage marks in the session
+            let session = get_session(conn, msg.session_id, msg.user_id, Permission::Marker)?;
+            let note_ = session.end_time.map(|closed_at| {
+                info!(
+                    "User {:?} marked {} in session {:?} after it was closed",
+                    msg.user_id, msg.student_username, msg.session_id
+                );
+                format!(
+                    "Added by {} after the session was closed at {} UTC",
+                    msg.username,
+                    closed_at.format("%Y-%m-%d %H:%M")
+                )
+            });
 
             use schema::marks::dsl::*;
-            Ok(diesel::insert_into(marks)
+            let inserted = diesel::insert_into(marks)
                 .values(NewAttendanceMark {
                     session_id: msg.session_id,
                     user_id: user.id,
                     mark_time: msg.mark_time,
                     is_manual: true,
+                    challenge_index: None,
+                    ip: None,
+                    late_ms: None,
+                    geofence_position: None,
+                    distance_m: None,
+                    note: note_,
                 })
                 .on_conflict((session_id, user_id))
                 .do_nothing()
-                .get_result(conn)
+                .get_result::<models::AttendanceMark>(conn)
                 .optional()
-                .context("Failed to insert mark")
-                .transpose()
-                .unwrap_or_else(|| {
-                    marks
-                        .filter(session_id.eq(&msg.session_id.0))
-                        .filter(user_id.eq(&user.id.0))
-                        .first(conn)
-                        .context("Failed to load an already existing mark")
-                })?)
+                .context("Failed to insert mark")?;
+
+            Ok(match inserted {
+                Some(mark) => {
+                    record_mark_event(conn, &mark, MarkEventKind::Added)?;
+                    mark
+                }
+                None => marks
+                    .filter(session_id.eq(&msg.session_id.0))
+                    .filter(user_id.eq(&user.id.0))
+                    .first(conn)
+                    .context("Failed to load an already existing mark")?,
+            })
         })
     }
 }
 
 impl Message for AddAutoAttendanceMark {
-    type Result = ApiResult<(models::AttendanceMark, Vec<models::User>)>;
+    type Result = ApiResult<(models::AttendanceMark, Vec<models::User>, i64)>;
 }
 impl Handler<AddAutoAttendanceMark> for DbExecutor {
     type Result = <AddAutoAttendanceMark as Message>::Result;
@@ -364,33 +933,59 @@ impl Handler<AddAutoAttendanceMark> for DbExecutor {
     fn handle(&mut self, msg: AddAutoAttendanceMark, _: &mut Self::Context) -> Self::Result {
         self.get_conn()?.transaction(|conn| -> ApiResult<_> {
             use schema::marks::dsl::*;
-            let mark = diesel::insert_into(marks)
+            let inserted = diesel::insert_into(marks)
                 .values(NewAttendanceMark {
                     session_id: msg.session_id,
                     user_id: msg.student_id,
                     mark_time: msg.mark_time,
                     is_manual: false,
+                    challenge_index: Some(msg.challenge_index as i32),
+                    ip: msg.ip,
+                    late_ms: Some(msg.late_ms),
+                    geofence_position: msg.geofence_position,
+                    distance_m: msg.distance_m,
+                    note: None,
                 })
                 .on_conflict((session_id, user_id))
                 .do_nothing()
-                .get_result(conn)
+                .get_result::<models::AttendanceMark>(conn)
                 .optional()
-                .context("Failed to insert mark")
-                .transpose()
-                .unwrap_or_else(|| {
-                    marks
-                        .filter(session_id.eq(&msg.session_id.0))
-                        .filter(user_id.eq(&msg.student_id.0))
-                        .first(conn)
-                        .context("Failed to load an already existing mark")
-                })?;
+                .context("Failed to insert mark")?;
 
-            Ok((
-                mark,
-                vec![], // TODO: return the list of students who were marked before this one
-            ))
-        })
-    }
+            let mark = match inserted {
+                Some(mark) => {
+                    record_mark_event(conn, &mark, MarkEventKind::Added)?;
+                    mark
+                }
+                None => marks
+                    .filter(session_id.eq(&msg.session_id.0))
+                    .filter(user_id.eq(&msg.student_id.0))
+                    .first(conn)
+                    .context("Failed to load an already existing mark")?,
+            };
+
+            let others = marks
+                .filter(session_id.eq(&msg.session_id.0))
+                .filter(user_id.ne(&msg.student_id.0));
+            let recent_students = if msg.recent_students > 0 {
+                others
+                    .inner_join(schema::users::table)
+                    .order((mark_time.desc(), id.desc()))
+                    .limit(msg.recent_students)
+                    .select(schema::users::all_columns)
+                    .load::<models::User>(conn)
+                    .context("Failed to load the other students")?
+            } else {
+                vec![]
+            };
+            let total = others
+                .count()
+                .get_result::<i64>(conn)
+                .context("Failed to count the other students")?;
+
+            Ok((mark, recent_students, total))
+        })
+    }
 }
 
 impl Message for DeleteAttendanceMark {
@@ -404,12 +999,12 @@ impl Handler<DeleteAttendanceMark> for DbExecutor {
         self.get_conn()?.transaction(|conn| -> ApiResult<_> {
             let user = get_or_create_user(conn, &msg.student_username, None)?;
 
-            // check that the session is owned by the supplied owner_id
-            let _session = get_session(conn, msg.session_id, msg.owner_id)?;
+            // check that the user can manage marks in the session
+            let _session = get_session(conn, msg.session_id, msg.user_id, Permission::Marker)?;
 
             use schema::marks::dsl::*;
 
-            Ok(diesel::delete(
+            let mark: models::AttendanceMark = diesel::delete(
                 marks
                     .filter(session_id.eq(&msg.session_id.0))
                     .filter(user_id.eq(&user.id.0)),
@@ -417,7 +1012,10 @@ impl Handler<DeleteAttendanceMark> for DbExecutor {
             .get_result(conn)
             .optional()
             .context("Failed to insert mark")?
-            .ok_or(DbError::MarkNotFound)?)
+            .ok_or(DbError::MarkNotFound)?;
+            record_mark_event(conn, &mark, MarkEventKind::Removed)?;
+
+            Ok(mark)
         })
     }
 }
@@ -435,3 +1033,907 @@ impl Handler<GetOrCreateUser> for DbExecutor {
         Ok(user)
     }
 }
+
+impl Message for CreateLogin {
+    type Result = ApiResult<models::Login>;
+}
+impl Handler<CreateLogin> for DbExecutor {
+    type Result = <CreateLogin as Message>::Result;
+
+    #[instrument(name = "CreateLogin", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: CreateLogin, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            use schema::logins::dsl::*;
+
+            // a good time to forget the logins that can't be used anymore
+            diesel::delete(
+                logins
+                    .filter(user_id.eq(&msg.login.user_id.0))
+                    .filter(expires_at.lt(&msg.login.created_at)),
+            )
+            .execute(conn)
+            .context("Failed to delete expired logins")?;
+
+            Ok(diesel::insert_into(logins)
+                .values(msg.login)
+                .get_result(conn)
+                .context("Failed to create login")?)
+        })
+    }
+}
+
+impl Message for GetLogin {
+    type Result = ApiResult<Option<(models::Login, models::User)>>;
+}
+impl Handler<GetLogin> for DbExecutor {
+    type Result = <GetLogin as Message>::Result;
+
+    #[instrument(name = "GetLogin", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetLogin, _: &mut Self::Context) -> Self::Result {
+        use schema::logins::dsl::*;
+
+        let result = logins
+            .inner_join(schema::users::table)
+            .filter(jti.eq(&msg.jti))
+            .first::<(models::Login, models::User)>(&mut self.get_conn()?)
+            .optional()
+            .context("Failed to load login")?;
+
+        Ok(result)
+    }
+}
+
+impl Message for GetActiveLogins {
+    type Result = ApiResult<Vec<models::Login>>;
+}
+impl Handler<GetActiveLogins> for DbExecutor {
+    type Result = <GetActiveLogins as Message>::Result;
+
+    #[instrument(name = "GetActiveLogins", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetActiveLogins, _: &mut Self::Context) -> Self::Result {
+        use schema::logins::dsl::*;
+
+        let results = logins
+            .filter(user_id.eq(&msg.user_id.0))
+            .filter(revoked.eq(false))
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .order(created_at.desc())
+            .load::<models::Login>(&mut self.get_conn()?)
+            .context("Failed to load logins")?;
+
+        Ok(results)
+    }
+}
+
+impl Message for RevokeLogins {
+    type Result = ApiResult<usize>;
+}
+impl Handler<RevokeLogins> for DbExecutor {
+    type Result = <RevokeLogins as Message>::Result;
+
+    #[instrument(name = "RevokeLogins", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: RevokeLogins, _: &mut Self::Context) -> Self::Result {
+        use schema::logins::dsl::*;
+
+        let conn = &mut self.get_conn()?;
+        let user_logins = logins
+            .filter(user_id.eq(&msg.user_id.0))
+            .filter(revoked.eq(false));
+
+        match &msg.jti {
+            Some(jti_) => match diesel::update(user_logins.filter(jti.eq(jti_)))
+                .set(revoked.eq(true))
+                .execute(conn)
+                .context("Failed to revoke login")?
+            {
+                0 => Err(DbError::LoginNotFound.into()),
+                count => Ok(count),
+            },
+            None => Ok(diesel::update(user_logins)
+                .set(revoked.eq(true))
+                .execute(conn)
+                .context("Failed to revoke logins")?),
+        }
+    }
+}
+
+impl Message for CreateApiToken {
+    type Result = ApiResult<models::ApiToken>;
+}
+impl Handler<CreateApiToken> for DbExecutor {
+    type Result = <CreateApiToken as Message>::Result;
+
+    #[instrument(name = "CreateApiToken", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: CreateApiToken, _: &mut Self::Context) -> Self::Result {
+        use schema::api_tokens::dsl::*;
+
+        Ok(diesel::insert_into(api_tokens)
+            .values(msg.token)
+            .get_result(&mut self.get_conn()?)
+            .context("Failed to create API token")?)
+    }
+}
+
+impl Message for UseApiToken {
+    type Result = ApiResult<Option<(models::ApiToken, models::User)>>;
+}
+impl Handler<UseApiToken> for DbExecutor {
+    type Result = <UseApiToken as Message>::Result;
+
+    #[instrument(name = "UseApiToken", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: UseApiToken, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            use schema::api_tokens::dsl::*;
+
+            let now = Utc::now().naive_utc();
+            let token = diesel::update(
+                api_tokens
+                    .filter(token_hash.eq(&msg.token_hash))
+                    .filter(revoked.eq(false))
+                    .filter(expires_at.is_null().or(expires_at.gt(now))),
+            )
+            .set(last_used_at.eq(now))
+            .get_result::<models::ApiToken>(conn)
+            .optional()
+            .context("Failed to load API token")?;
+
+            let token = match token {
+                Some(token) => token,
+                None => return Ok(None),
+            };
+            let user = schema::users::table
+                .find(token.user_id.0)
+                .first::<models::User>(conn)
+                .context("Failed to load API token user")?;
+
+            Ok(Some((token, user)))
+        })
+    }
+}
+
+impl Message for GetApiTokens {
+    type Result = ApiResult<Vec<models::ApiToken>>;
+}
+impl Handler<GetApiTokens> for DbExecutor {
+    type Result = <GetApiTokens as Message>::Result;
+
+    #[instrument(name = "GetApiTokens", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetApiTokens, _: &mut Self::Context) -> Self::Result {
+        use schema::api_tokens::dsl::*;
+
+        let results = api_tokens
+            .filter(user_id.eq(&msg.user_id.0))
+            .filter(revoked.eq(false))
+            .order(created_at.desc())
+            .load::<models::ApiToken>(&mut self.get_conn()?)
+            .context("Failed to load API tokens")?;
+
+        Ok(results)
+    }
+}
+
+impl Message for RevokeApiToken {
+    type Result = ApiResult<()>;
+}
+impl Handler<RevokeApiToken> for DbExecutor {
+    type Result = <RevokeApiToken as Message>::Result;
+
+    #[instrument(name = "RevokeApiToken", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: RevokeApiToken, _: &mut Self::Context) -> Self::Result {
+        use schema::api_tokens::dsl::*;
+
+        let count = diesel::update(
+            api_tokens
+                .filter(id.eq(&msg.token_id.0))
+                .filter(user_id.eq(&msg.user_id.0))
+                .filter(revoked.eq(false)),
+        )
+        .set(revoked.eq(true))
+        .execute(&mut self.get_conn()?)
+        .context("Failed to revoke API token")?;
+
+        match count {
+            0 => Err(DbError::ApiTokenNotFound.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Message for GetWebauthnCredentials {
+    type Result = ApiResult<Option<(models::User, Vec<models::WebauthnCredential>)>>;
+}
+impl Handler<GetWebauthnCredentials> for DbExecutor {
+    type Result = <GetWebauthnCredentials as Message>::Result;
+
+    #[instrument(name = "GetWebauthnCredentials", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetWebauthnCredentials, _: &mut Self::Context) -> Self::Result {
+        let conn = &mut self.get_conn()?;
+
+        let user = schema::users::table
+            .filter(schema::users::username.eq(&msg.username))
+            .first::<models::User>(conn)
+            .optional()
+            .context("Failed to load user")?;
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        use schema::webauthn_credentials::dsl::*;
+        let credentials = webauthn_credentials
+            .filter(user_id.eq(&user.id.0))
+            .order(created_at)
+            .load::<models::WebauthnCredential>(conn)
+            .context("Failed to load WebAuthn credentials")?;
+
+        Ok(Some((user, credentials)))
+    }
+}
+
+impl Message for AddWebauthnCredential {
+    type Result = ApiResult<models::WebauthnCredential>;
+}
+impl Handler<AddWebauthnCredential> for DbExecutor {
+    type Result = <AddWebauthnCredential as Message>::Result;
+
+    #[instrument(name = "AddWebauthnCredential", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: AddWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use schema::webauthn_credentials::dsl::*;
+
+        Ok(diesel::insert_into(webauthn_credentials)
+            .values(msg.credential)
+            .get_result(&mut self.get_conn()?)
+            .context("Failed to add WebAuthn credential")?)
+    }
+}
+
+impl Message for UpdateWebauthnCredential {
+    type Result = ApiResult<()>;
+}
+impl Handler<UpdateWebauthnCredential> for DbExecutor {
+    type Result = <UpdateWebauthnCredential as Message>::Result;
+
+    #[instrument(name = "UpdateWebauthnCredential", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: UpdateWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use schema::webauthn_credentials::dsl::*;
+
+        let conn = &mut self.get_conn()?;
+        let credential = webauthn_credentials.filter(credential_id.eq(&msg.credential_id));
+        match msg.passkey {
+            Some(passkey_) => diesel::update(credential)
+                .set((last_used_at.eq(msg.used_at), passkey.eq(passkey_)))
+                .execute(conn),
+            None => diesel::update(credential)
+                .set(last_used_at.eq(msg.used_at))
+                .execute(conn),
+        }
+        .context("Failed to update WebAuthn credential")?;
+
+        Ok(())
+    }
+}
+
+impl Message for DeleteWebauthnCredential {
+    type Result = ApiResult<()>;
+}
+impl Handler<DeleteWebauthnCredential> for DbExecutor {
+    type Result = <DeleteWebauthnCredential as Message>::Result;
+
+    #[instrument(name = "DeleteWebauthnCredential", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: DeleteWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use schema::webauthn_credentials::dsl::*;
+
+        let count = diesel::delete(
+            webauthn_credentials
+                .filter(id.eq(&msg.id.0))
+                .filter(user_id.eq(&msg.user_id.0)),
+        )
+        .execute(&mut self.get_conn()?)
+        .context("Failed to delete WebAuthn credential")?;
+
+        match count {
+            0 => Err(DbError::WebauthnCredentialNotFound.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Message for UseWebauthnFlow {
+    type Result = ApiResult<bool>;
+}
+impl Handler<UseWebauthnFlow> for DbExecutor {
+    type Result = <UseWebauthnFlow as Message>::Result;
+
+    #[instrument(name = "UseWebauthnFlow", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: UseWebauthnFlow, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            use schema::used_webauthn_flows::dsl::*;
+
+            let now = Utc::now().naive_utc();
+            // expired flows are rejected by their signature anyway
+            diesel::delete(used_webauthn_flows.filter(expires_at.lt(now)))
+                .execute(conn)
+                .context("Failed to remove expired WebAuthn flows")?;
+
+            let inserted = diesel::insert_into(used_webauthn_flows)
+                .values((
+                    jti.eq(&msg.jti),
+                    username.eq(&msg.username),
+                    used_at.eq(now),
+                    expires_at.eq(msg.expires_at),
+                ))
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .context("Failed to use WebAuthn flow")?;
+
+            Ok(inserted == 1)
+        })
+    }
+}
+
+impl Message for GetTotp {
+    type Result = ApiResult<Option<models::TotpSecret>>;
+}
+impl Handler<GetTotp> for DbExecutor {
+    type Result = <GetTotp as Message>::Result;
+
+    #[instrument(name = "GetTotp", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetTotp, _: &mut Self::Context) -> Self::Result {
+        use schema::totp_secrets::dsl::*;
+
+        Ok(totp_secrets
+            .find(&msg.user_id.0)
+            .first::<models::TotpSecret>(&mut self.get_conn()?)
+            .optional()
+            .context("Failed to load TOTP secret")?)
+    }
+}
+
+impl Message for SetTotpSecret {
+    type Result = ApiResult<()>;
+}
+impl Handler<SetTotpSecret> for DbExecutor {
+    type Result = <SetTotpSecret as Message>::Result;
+
+    #[instrument(name = "SetTotpSecret", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: SetTotpSecret, _: &mut Self::Context) -> Self::Result {
+        use schema::totp_secrets::dsl::*;
+
+        let now = Utc::now().naive_utc();
+        diesel::insert_into(totp_secrets)
+            .values((
+                user_id.eq(&msg.user_id.0),
+                secret.eq(&msg.secret),
+                confirmed.eq(false),
+                created_at.eq(now),
+            ))
+            .on_conflict(user_id)
+            .do_update()
+            .set((
+                secret.eq(&msg.secret),
+                confirmed.eq(false),
+                created_at.eq(now),
+                last_used_step.eq(None as Option<i64>),
+                failed_attempts.eq(0),
+                locked_until.eq(None as Option<NaiveDateTime>),
+            ))
+            .execute(&mut self.get_conn()?)
+            .context("Failed to store TOTP secret")?;
+
+        Ok(())
+    }
+}
+
+impl Message for ConfirmTotp {
+    type Result = ApiResult<()>;
+}
+impl Handler<ConfirmTotp> for DbExecutor {
+    type Result = <ConfirmTotp as Message>::Result;
+
+    #[instrument(name = "ConfirmTotp", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: ConfirmTotp, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            {
+                use schema::totp_secrets::dsl::*;
+                diesel::update(totp_secrets.find(&msg.user_id.0))
+                    .set((confirmed.eq(true), last_used_step.eq(msg.used_step)))
+                    .execute(conn)
+                    .context("Failed to confirm TOTP secret")?;
+            }
+
+            use schema::recovery_codes::dsl::*;
+            diesel::delete(recovery_codes.filter(user_id.eq(&msg.user_id.0)))
+                .execute(conn)
+                .context("Failed to delete recovery codes")?;
+            diesel::insert_into(recovery_codes)
+                .values(
+                    msg.recovery_code_hashes
+                        .iter()
+                        .map(|hash| (user_id.eq(&msg.user_id.0), code_hash.eq(hash)))
+                        .collect::<Vec<_>>(),
+                )
+                .execute(conn)
+                .context("Failed to insert recovery codes")?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Message for CountTotpAttempt {
+    type Result = ApiResult<Option<i32>>;
+}
+impl Handler<CountTotpAttempt> for DbExecutor {
+    type Result = <CountTotpAttempt as Message>::Result;
+
+    #[instrument(name = "CountTotpAttempt", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: CountTotpAttempt, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            use schema::totp_secrets::dsl::*;
+
+            let now = Utc::now().naive_utc();
+            // the row stays locked until the end of the transaction, so concurrent attempts are counted one by one
+            let attempt = diesel::update(
+                totp_secrets
+                    .find(&msg.user_id.0)
+                    .filter(locked_until.is_null().or(locked_until.le(now))),
+            )
+            .set(failed_attempts.eq(failed_attempts + 1))
+            .returning(failed_attempts)
+            .get_result::<i32>(conn)
+            .optional()
+            .context("Failed to count TOTP attempt")?;
+
+            if matches!(attempt, Some(attempt) if attempt >= msg.max_failed_attempts) {
+                // this attempt is still checked, a successful one lifts the lockout
+                diesel::update(totp_secrets.find(&msg.user_id.0))
+                    .set((failed_attempts.eq(0), locked_until.eq(msg.lockout_until)))
+                    .execute(conn)
+                    .context("Failed to lock TOTP secret")?;
+            }
+
+            Ok(attempt)
+        })
+    }
+}
+
+impl Message for UseTotpStep {
+    type Result = ApiResult<bool>;
+}
+impl Handler<UseTotpStep> for DbExecutor {
+    type Result = <UseTotpStep as Message>::Result;
+
+    #[instrument(name = "UseTotpStep", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: UseTotpStep, _: &mut Self::Context) -> Self::Result {
+        use schema::totp_secrets::dsl::*;
+
+        let count = diesel::update(
+            totp_secrets
+                .find(&msg.user_id.0)
+                .filter(last_used_step.is_null().or(last_used_step.lt(msg.step))),
+        )
+        .set(last_used_step.eq(msg.step))
+        .execute(&mut self.get_conn()?)
+        .context("Failed to use TOTP step")?;
+
+        Ok(count > 0)
+    }
+}
+
+impl Message for ResetTotpAttempts {
+    type Result = ApiResult<()>;
+}
+impl Handler<ResetTotpAttempts> for DbExecutor {
+    type Result = <ResetTotpAttempts as Message>::Result;
+
+    #[instrument(name = "ResetTotpAttempts", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: ResetTotpAttempts, _: &mut Self::Context) -> Self::Result {
+        use schema::totp_secrets::dsl::*;
+
+        diesel::update(totp_secrets.find(&msg.user_id.0))
+            .set((
+                failed_attempts.eq(0),
+                locked_until.eq(None as Option<NaiveDateTime>),
+            ))
+            .execute(&mut self.get_conn()?)
+            .context("Failed to reset TOTP attempts")?;
+
+        Ok(())
+    }
+}
+
+impl Message for UseRecoveryCode {
+    type Result = ApiResult<bool>;
+}
+impl Handler<UseRecoveryCode> for DbExecutor {
+    type Result = <UseRecoveryCode as Message>::Result;
+
+    #[instrument(name = "UseRecoveryCode", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: UseRecoveryCode, _: &mut Self::Context) -> Self::Result {
+        use schema::recovery_codes::dsl::*;
+
+        let count = diesel::update(
+            recovery_codes
+                .filter(user_id.eq(&msg.user_id.0))
+                .filter(code_hash.eq(&msg.code_hash))
+                .filter(used_at.is_null()),
+        )
+        .set(used_at.eq(Utc::now().naive_utc()))
+        .execute(&mut self.get_conn()?)
+        .context("Failed to use recovery code")?;
+
+        Ok(count > 0)
+    }
+}
+
+impl Message for DeleteTotp {
+    type Result = ApiResult<()>;
+}
+impl Handler<DeleteTotp> for DbExecutor {
+    type Result = <DeleteTotp as Message>::Result;
+
+    #[instrument(name = "DeleteTotp", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: DeleteTotp, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            diesel::delete(
+                schema::recovery_codes::table
+                    .filter(schema::recovery_codes::user_id.eq(&msg.user_id.0)),
+            )
+            .execute(conn)
+            .context("Failed to delete recovery codes")?;
+            diesel::delete(schema::totp_secrets::table.find(&msg.user_id.0))
+                .execute(conn)
+                .context("Failed to delete TOTP secret")?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Message for GetUser {
+    type Result = ApiResult<Option<models::User>>;
+}
+impl Handler<GetUser> for DbExecutor {
+    type Result = <GetUser as Message>::Result;
+
+    #[instrument(name = "GetUser", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetUser, _: &mut Self::Context) -> Self::Result {
+        use schema::users::dsl::*;
+
+        Ok(users
+            .filter(username.eq(&msg.username))
+            .first::<models::User>(&mut self.get_conn()?)
+            .optional()
+            .context("Failed to load user")?)
+    }
+}
+
+impl Message for GetUserPassword {
+    type Result = ApiResult<Option<(models::User, Option<models::UserPassword>)>>;
+}
+impl Handler<GetUserPassword> for DbExecutor {
+    type Result = <GetUserPassword as Message>::Result;
+
+    #[instrument(name = "GetUserPassword", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetUserPassword, _: &mut Self::Context) -> Self::Result {
+        use schema::users::dsl::*;
+
+        Ok(users
+            .left_join(schema::user_passwords::table)
+            .filter(username.eq(&msg.username))
+            .first::<(models::User, Option<models::UserPassword>)>(&mut self.get_conn()?)
+            .optional()
+            .context("Failed to load user password")?)
+    }
+}
+
+impl Message for SetPassword {
+    type Result = ApiResult<()>;
+}
+impl Handler<SetPassword> for DbExecutor {
+    type Result = <SetPassword as Message>::Result;
+
+    #[instrument(name = "SetPassword", parent = &msg.span, skip(self, msg), fields(user_id = ?msg.user_id))]
+    fn handle(&mut self, msg: SetPassword, _: &mut Self::Context) -> Self::Result {
+        set_password(&mut self.get_conn()?, msg.user_id, &msg.password_hash)
+    }
+}
+
+impl Message for CountPasswordAttempt {
+    type Result = ApiResult<Option<i32>>;
+}
+impl Handler<CountPasswordAttempt> for DbExecutor {
+    type Result = <CountPasswordAttempt as Message>::Result;
+
+    #[instrument(name = "CountPasswordAttempt", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: CountPasswordAttempt, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            use schema::user_passwords::dsl::*;
+
+            let now = Utc::now().naive_utc();
+            // the row stays locked until the end of the transaction, so concurrent attempts are counted one by one
+            let attempt = diesel::update(
+                user_passwords
+                    .find(&msg.user_id.0)
+                    .filter(locked_until.is_null().or(locked_until.le(now))),
+            )
+            .set(failed_attempts.eq(failed_attempts + 1))
+            .returning(failed_attempts)
+            .get_result::<i32>(conn)
+            .optional()
+            .context("Failed to count password attempt")?;
+
+            if matches!(attempt, Some(attempt) if attempt >= msg.max_failed_attempts) {
+                // this attempt is still checked, a successful one lifts the lockout
+                diesel::update(user_passwords.find(&msg.user_id.0))
+                    .set((failed_attempts.eq(0), locked_until.eq(msg.lockout_until)))
+                    .execute(conn)
+                    .context("Failed to lock password")?;
+            }
+
+            Ok(attempt)
+        })
+    }
+}
+
+impl Message for UpdatePasswordAttempts {
+    type Result = ApiResult<()>;
+}
+impl Handler<UpdatePasswordAttempts> for DbExecutor {
+    type Result = <UpdatePasswordAttempts as Message>::Result;
+
+    #[instrument(name = "UpdatePasswordAttempts", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: UpdatePasswordAttempts, _: &mut Self::Context) -> Self::Result {
+        use schema::user_passwords::dsl::*;
+
+        diesel::update(user_passwords.find(&msg.user_id.0))
+            .set((
+                failed_attempts.eq(msg.failed_attempts),
+                locked_until.eq(msg.locked_until),
+            ))
+            .execute(&mut self.get_conn()?)
+            .context("Failed to update password attempts")?;
+
+        Ok(())
+    }
+}
+
+impl Message for CreatePasswordToken {
+    type Result = ApiResult<()>;
+}
+impl Handler<CreatePasswordToken> for DbExecutor {
+    type Result = <CreatePasswordToken as Message>::Result;
+
+    #[instrument(name = "CreatePasswordToken", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: CreatePasswordToken, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            use schema::password_tokens::dsl::*;
+
+            // only the latest token of the user can be used
+            diesel::update(
+                password_tokens
+                    .filter(user_id.eq(&msg.token.user_id.0))
+                    .filter(used.eq(false)),
+            )
+            .set(used.eq(true))
+            .execute(conn)
+            .context("Failed to invalidate password tokens")?;
+
+            diesel::insert_into(password_tokens)
+                .values(msg.token)
+                .execute(conn)
+                .context("Failed to create password token")?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Message for RedeemPasswordToken {
+    type Result = ApiResult<Option<models::User>>;
+}
+impl Handler<RedeemPasswordToken> for DbExecutor {
+    type Result = <RedeemPasswordToken as Message>::Result;
+
+    #[instrument(name = "RedeemPasswordToken", parent = &msg.span, skip(self, msg), fields(purpose = %msg.purpose))]
+    fn handle(&mut self, msg: RedeemPasswordToken, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            use schema::password_tokens::dsl::*;
+
+            let token = diesel::update(
+                password_tokens
+                    .filter(token_hash.eq(&msg.token_hash))
+                    .filter(purpose.eq(msg.purpose))
+                    .filter(used.eq(false))
+                    .filter(expires_at.gt(Utc::now().naive_utc())),
+            )
+            .set(used.eq(true))
+            .returning(user_id)
+            .get_result::<i32>(conn)
+            .optional()
+            .context("Failed to use password token")?;
+
+            let token_user_id = match token {
+                Some(token_user_id) => UserId(token_user_id),
+                None => return Ok(None),
+            };
+            set_password(conn, token_user_id, &msg.password_hash)?;
+
+            Ok(Some(
+                schema::users::table
+                    .find(token_user_id.0)
+                    .first::<models::User>(conn)
+                    .context("Failed to load user")?,
+            ))
+        })
+    }
+}
+
+impl Message for UseMagicLink {
+    type Result = ApiResult<bool>;
+}
+impl Handler<UseMagicLink> for DbExecutor {
+    type Result = <UseMagicLink as Message>::Result;
+
+    #[instrument(name = "UseMagicLink", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: UseMagicLink, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            use schema::used_magic_links::dsl::*;
+
+            let now = Utc::now().naive_utc();
+            // expired links are rejected by their signature anyway
+            diesel::delete(used_magic_links.filter(expires_at.lt(now)))
+                .execute(conn)
+                .context("Failed to remove expired magic links")?;
+
+            let inserted = diesel::insert_into(used_magic_links)
+                .values((
+                    jti.eq(&msg.jti),
+                    email.eq(&msg.email),
+                    used_at.eq(now),
+                    expires_at.eq(msg.expires_at),
+                ))
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .context("Failed to use magic link")?;
+
+            Ok(inserted == 1)
+        })
+    }
+}
+
+impl Message for GetStaff {
+    type Result = ApiResult<Vec<models::User>>;
+}
+impl Handler<GetStaff> for DbExecutor {
+    type Result = <GetStaff as Message>::Result;
+
+    #[instrument(name = "GetStaff", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetStaff, _: &mut Self::Context) -> Self::Result {
+        use schema::users::dsl::*;
+
+        let results = users
+            .filter(role.ne(Role::Student))
+            .order(username)
+            .load::<models::User>(&mut self.get_conn()?)
+            .context("Failed to load users")?;
+
+        Ok(results)
+    }
+}
+
+impl Message for SetUserRole {
+    type Result = ApiResult<models::User>;
+}
+impl Handler<SetUserRole> for DbExecutor {
+    type Result = <SetUserRole as Message>::Result;
+
+    #[instrument(name = "SetUserRole", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: SetUserRole, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            let user = get_or_create_user(conn, &msg.username, None)?;
+
+            use schema::users::dsl::*;
+            Ok(diesel::update(users.filter(id.eq(&user.id.0)))
+                .set(role.eq(msg.role))
+                .get_result(conn)
+                .context("Failed to update user role")?)
+        })
+    }
+}
+
+impl Message for GetSessionMembers {
+    type Result = ApiResult<Vec<(models::SessionMember, models::User)>>;
+}
+impl Handler<GetSessionMembers> for DbExecutor {
+    type Result = <GetSessionMembers as Message>::Result;
+
+    #[instrument(name = "GetSessionMembers", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: GetSessionMembers, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            let _session = get_session(conn, msg.session_id, msg.user_id, Permission::Viewer)?;
+
+            use schema::session_members::dsl::*;
+            Ok(session_members
+                .inner_join(schema::users::table)
+                .filter(session_id.eq(&msg.session_id.0))
+                .load(conn)
+                .context("Failed to load session members")?)
+        })
+    }
+}
+
+impl Message for SetSessionMember {
+    type Result = ApiResult<(models::SessionMember, models::User)>;
+}
+impl Handler<SetSessionMember> for DbExecutor {
+    type Result = <SetSessionMember as Message>::Result;
+
+    #[instrument(name = "SetSessionMember", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: SetSessionMember, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            let session = get_session(conn, msg.session_id, msg.user_id, Permission::Owner)?;
+            let member = get_or_create_user(conn, &msg.member_username, None)?;
+            if member.id == session.owner_id {
+                return Err(DbError::SessionCreatorMember.into());
+            }
+
+            use schema::session_members::dsl::*;
+            let result = diesel::insert_into(session_members)
+                .values((
+                    session_id.eq(&msg.session_id.0),
+                    user_id.eq(&member.id.0),
+                    permission.eq(msg.permission),
+                ))
+                .on_conflict((session_id, user_id))
+                .do_update()
+                .set(permission.eq(msg.permission))
+                .get_result(conn)
+                .context("Failed to set session member")?;
+
+            Ok((result, member))
+        })
+    }
+}
+
+impl Message for RemoveSessionMember {
+    type Result = ApiResult<models::SessionMember>;
+}
+impl Handler<RemoveSessionMember> for DbExecutor {
+    type Result = <RemoveSessionMember as Message>::Result;
+
+    #[instrument(name = "RemoveSessionMember", parent = &msg.span, skip(self))]
+    fn handle(&mut self, msg: RemoveSessionMember, _: &mut Self::Context) -> Self::Result {
+        self.get_conn()?.transaction(|conn| -> ApiResult<_> {
+            let member = {
+                use schema::users::dsl::*;
+                users
+                    .filter(username.eq(&msg.member_username))
+                    .first::<models::User>(conn)
+                    .optional()
+                    .context("Failed to load user")?
+            };
+            let required_permission = match &member {
+                Some(member) if member.id == msg.user_id => Permission::Viewer,
+                _ => Permission::Owner,
+            };
+            let session = get_session(conn, msg.session_id, msg.user_id, required_permission)?;
+            // checked after the permission, so that only owners learn whether the user exists
+            let member = member.ok_or(DbError::MemberNotFound)?;
+            if member.id == session.owner_id {
+                return Err(DbError::SessionCreatorMember.into());
+            }
+
+            use schema::session_members::dsl::*;
+            Ok(diesel::delete(
+                session_members
+                    .filter(session_id.eq(&msg.session_id.0))
+                    .filter(user_id.eq(&member.id.0)),
+            )
+            .get_result(conn)
+            .optional()
+            .context("Failed to remove session member")?
+            .ok_or(DbError::MemberNotFound)?)
+        })
+    }
+}
@@ -1,9 +1,16 @@
 use crate::db::schema;
 use chrono::NaiveDateTime;
 use derive_more::{From, Into};
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
 use diesel::prelude::*;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, From, Into, Serialize, Deserialize)]
 pub struct UserId(pub i32);
@@ -11,6 +18,168 @@ pub struct UserId(pub i32);
 pub struct SessionId(pub i32);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, From, Into, Serialize, Deserialize)]
 pub struct AttendanceMarkId(pub i32);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, From, Into, Serialize, Deserialize)]
+pub struct ApiTokenId(pub i32);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, From, Into, Serialize, Deserialize)]
+pub struct WebauthnCredentialId(pub i32);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, From, Into, Serialize, Deserialize)]
+pub struct MarkEventId(pub i64);
+
+/// Declares an ordered enum stored as the given text both in the database and in the api
+macro_rules! text_enum {
+    ($(#[$meta:meta])* pub enum $name:ident { $($variant:ident => $text:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(
+            Debug,
+            Clone,
+            Copy,
+            PartialEq,
+            Eq,
+            PartialOrd,
+            Ord,
+            Hash,
+            Serialize,
+            Deserialize,
+            AsExpression,
+            FromSqlRow,
+        )]
+        #[diesel(sql_type = Text)]
+        pub enum $name {
+            $(#[serde(rename = $text)] $variant),+
+        }
+
+        impl $name {
+            const ALL: &'static [$name] = &[$($name::$variant),+];
+
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $text),+
+                }
+            }
+
+            /// This value and all the ones after it
+            pub fn at_least(self) -> Vec<$name> {
+                Self::ALL.iter().copied().filter(|v| *v >= self).collect()
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = anyhow::Error;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($text => Ok($name::$variant),)+
+                    _ => Err(anyhow::anyhow!(concat!("Unknown ", stringify!($name), " {:?}"), s)),
+                }
+            }
+        }
+
+        impl ToSql<Text, Pg> for $name {
+            fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+                <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+            }
+        }
+
+        impl FromSql<Text, Pg> for $name {
+            fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+                let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+                Ok(value.parse()?)
+            }
+        }
+    };
+}
+
+text_enum! {
+    /// Role of the user, each role includes the rights of the ones before it
+    pub enum Role {
+        Student => "student",
+        TeachingAssistant => "teaching_assistant",
+        Teacher => "teacher",
+        Admin => "admin",
+    }
+}
+
+text_enum! {
+    /// What an API token can be used for. Session cookies can do everything
+    pub enum Scope {
+        SessionsRead => "sessions:read",
+        SessionsWrite => "sessions:write",
+        MarksWrite => "marks:write",
+    }
+}
+
+text_enum! {
+    /// What a password token can be used for
+    pub enum PasswordTokenPurpose {
+        Invite => "invite",
+        Reset => "reset",
+    }
+}
+
+text_enum! {
+    /// MAC of the QR code challenges of a session, HMAC-SHA1 is the legacy format with a 4-byte tag
+    pub enum ChallengeAlgorithm {
+        HmacSha1 => "hmac-sha1",
+        HmacSha256 => "hmac-sha256",
+    }
+}
+
+text_enum! {
+    /// What happens to the challenges from outside the session geofence
+    pub enum GeofencePolicy {
+        Reject => "reject",
+        Flag => "flag",
+    }
+}
+
+text_enum! {
+    /// What happens to the challenges from outside the allowed networks
+    pub enum NetworkPolicy {
+        Reject => "reject",
+        Flag => "flag",
+    }
+}
+
+text_enum! {
+    /// What the students are told about the others marked in the session, each one tells more than the ones before
+    pub enum StudentVisibility {
+        Hidden => "hidden",
+        Count => "count",
+        Names => "names",
+    }
+}
+
+text_enum! {
+    /// Where the device was relative to the session geofence, `Unknown` if it did not tell precisely enough
+    pub enum GeofencePosition {
+        Inside => "inside",
+        Outside => "outside",
+        Unknown => "unknown",
+    }
+}
+
+text_enum! {
+    /// What happened to a mark, for the attendance feed
+    pub enum MarkEventKind {
+        Added => "added",
+        Removed => "removed",
+    }
+}
+
+text_enum! {
+    /// Permission of a member of a session, each one includes the rights of the ones before it
+    pub enum Permission {
+        Viewer => "viewer",
+        Marker => "marker",
+        Owner => "owner",
+    }
+}
 
 #[derive(Debug, Clone, Queryable)]
 pub struct User {
@@ -18,6 +187,7 @@ pub struct User {
     pub id: UserId,
     pub username: String,
     pub name: Option<String>,
+    pub role: Role,
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -37,6 +207,14 @@ pub struct AttendanceMark {
     pub session_id: SessionId,
     pub mark_time: NaiveDateTime,
     pub is_manual: bool,
+    /// Evidence of automatic marks, for spotting forwarded QR codes
+    pub challenge_index: Option<i32>,
+    pub ip: Option<String>,
+    pub late_ms: Option<i32>,
+    pub geofence_position: Option<GeofencePosition>,
+    pub distance_m: Option<i32>,
+    /// Audit note of the manual marks added after the session was closed
+    pub note: Option<String>,
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -48,6 +226,12 @@ pub struct NewAttendanceMark {
     pub session_id: SessionId,
     pub mark_time: NaiveDateTime,
     pub is_manual: bool,
+    pub challenge_index: Option<i32>,
+    pub ip: Option<String>,
+    pub late_ms: Option<i32>,
+    pub geofence_position: Option<GeofencePosition>,
+    pub distance_m: Option<i32>,
+    pub note: Option<String>,
 }
 
 #[derive(Debug, Clone, Queryable)]
@@ -60,6 +244,23 @@ pub struct Session {
     pub active: bool,
     pub start_time: NaiveDateTime,
     pub seed: String,
+    pub challenge_algorithm: ChallengeAlgorithm,
+    pub challenge_tag_length: i32,
+    /// Overrides of the challenge config
+    pub qr_interval_ms: Option<i32>,
+    pub jitter_window_ms: Option<i32>,
+    /// The geofence, all of these are set or none of them
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub radius_m: Option<i32>,
+    pub geofence_policy: Option<GeofencePolicy>,
+    /// Overrides of the allowed networks from the config
+    pub allowed_networks: Option<Vec<String>>,
+    pub network_policy: Option<NetworkPolicy>,
+    /// Set when the session is closed, `active` is false while it is paused or closed
+    pub end_time: Option<NaiveDateTime>,
+    /// Override of the student visibility from the config
+    pub student_visibility: Option<StudentVisibility>,
 }
 
 pub type SessionWithMarks = (
@@ -67,3 +268,156 @@ pub type SessionWithMarks = (
     HashMap<AttendanceMarkId, AttendanceMark>,
     HashMap<UserId, User>,
 );
+
+#[derive(Debug, Clone, Queryable)]
+pub struct MarkEvent {
+    #[diesel(deserialize_as = i64)]
+    pub id: MarkEventId,
+    #[diesel(deserialize_as = i32)]
+    pub session_id: SessionId,
+    #[diesel(deserialize_as = i32)]
+    pub user_id: UserId,
+    #[diesel(deserialize_as = i32)]
+    pub mark_id: AttendanceMarkId,
+    pub kind: MarkEventKind,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::mark_events)]
+pub struct NewMarkEvent {
+    #[diesel(serialize_as = i32)]
+    pub session_id: SessionId,
+    #[diesel(serialize_as = i32)]
+    pub user_id: UserId,
+    #[diesel(serialize_as = i32)]
+    pub mark_id: AttendanceMarkId,
+    pub kind: MarkEventKind,
+    pub created_at: NaiveDateTime,
+}
+
+/// The events after the cursor with their users, and the current marks of the session for the added ones
+pub type MarkEvents = (
+    Session,
+    Vec<(MarkEvent, User)>,
+    HashMap<AttendanceMarkId, AttendanceMark>,
+);
+
+#[derive(Debug, Clone, Queryable)]
+pub struct Login {
+    pub jti: String,
+    #[diesel(deserialize_as = i32)]
+    pub user_id: UserId,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked: bool,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::logins)]
+pub struct NewLogin {
+    pub jti: String,
+    #[diesel(serialize_as = i32)]
+    pub user_id: UserId,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Queryable)]
+pub struct SessionMember {
+    #[diesel(deserialize_as = i32)]
+    pub session_id: SessionId,
+    #[diesel(deserialize_as = i32)]
+    pub user_id: UserId,
+    pub permission: Permission,
+}
+
+#[derive(Debug, Clone, Queryable)]
+pub struct ApiToken {
+    #[diesel(deserialize_as = i32)]
+    pub id: ApiTokenId,
+    #[diesel(deserialize_as = i32)]
+    pub user_id: UserId,
+    pub name: String,
+    pub token_hash: Vec<u8>,
+    pub scopes: Vec<Scope>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked: bool,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::api_tokens)]
+pub struct NewApiToken {
+    #[diesel(serialize_as = i32)]
+    pub user_id: UserId,
+    pub name: String,
+    pub token_hash: Vec<u8>,
+    pub scopes: Vec<Scope>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Queryable)]
+pub struct WebauthnCredential {
+    #[diesel(deserialize_as = i32)]
+    pub id: WebauthnCredentialId,
+    #[diesel(deserialize_as = i32)]
+    pub user_id: UserId,
+    pub name: String,
+    pub credential_id: Vec<u8>,
+    /// Serialized `webauthn_rs::prelude::Passkey`
+    pub passkey: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::webauthn_credentials)]
+pub struct NewWebauthnCredential {
+    #[diesel(serialize_as = i32)]
+    pub user_id: UserId,
+    pub name: String,
+    pub credential_id: Vec<u8>,
+    pub passkey: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Queryable)]
+pub struct TotpSecret {
+    #[diesel(deserialize_as = i32)]
+    pub user_id: UserId,
+    /// Encrypted, see `api::totp`
+    pub secret: Vec<u8>,
+    pub confirmed: bool,
+    pub created_at: NaiveDateTime,
+    pub last_used_step: Option<i64>,
+    pub failed_attempts: i32,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Queryable)]
+pub struct UserPassword {
+    #[diesel(deserialize_as = i32)]
+    pub user_id: UserId,
+    pub password_hash: String,
+    pub updated_at: NaiveDateTime,
+    pub failed_attempts: i32,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::password_tokens)]
+pub struct NewPasswordToken {
+    pub token_hash: Vec<u8>,
+    #[diesel(serialize_as = i32)]
+    pub user_id: UserId,
+    pub purpose: PasswordTokenPurpose,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
@@ -1,5 +1,42 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        token_hash -> Bytea,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        revoked -> Bool,
+    }
+}
+
+diesel::table! {
+    logins (jti) {
+        jti -> Text,
+        user_id -> Int4,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked -> Bool,
+    }
+}
+
+diesel::table! {
+    mark_events (id) {
+        id -> Int8,
+        session_id -> Int4,
+        user_id -> Int4,
+        mark_id -> Int4,
+        kind -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     marks (id) {
         id -> Int4,
@@ -7,6 +44,40 @@ diesel::table! {
         session_id -> Int4,
         mark_time -> Timestamp,
         is_manual -> Bool,
+        challenge_index -> Nullable<Int4>,
+        ip -> Nullable<Text>,
+        late_ms -> Nullable<Int4>,
+        geofence_position -> Nullable<Text>,
+        distance_m -> Nullable<Int4>,
+        note -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    password_tokens (token_hash) {
+        token_hash -> Bytea,
+        user_id -> Int4,
+        purpose -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used -> Bool,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Bytea,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    session_members (session_id, user_id) {
+        session_id -> Int4,
+        user_id -> Int4,
+        permission -> Text,
     }
 }
 
@@ -18,6 +89,58 @@ diesel::table! {
         active -> Bool,
         start_time -> Timestamp,
         seed -> Text,
+        challenge_algorithm -> Text,
+        challenge_tag_length -> Int4,
+        qr_interval_ms -> Nullable<Int4>,
+        jitter_window_ms -> Nullable<Int4>,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        radius_m -> Nullable<Int4>,
+        geofence_policy -> Nullable<Text>,
+        allowed_networks -> Nullable<Array<Text>>,
+        network_policy -> Nullable<Text>,
+        end_time -> Nullable<Timestamp>,
+        student_visibility -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    totp_secrets (user_id) {
+        user_id -> Int4,
+        secret -> Bytea,
+        confirmed -> Bool,
+        created_at -> Timestamp,
+        last_used_step -> Nullable<Int8>,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    used_magic_links (jti) {
+        jti -> Text,
+        email -> Text,
+        used_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    used_webauthn_flows (jti) {
+        jti -> Text,
+        username -> Text,
+        used_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_passwords (user_id) {
+        user_id -> Int4,
+        password_hash -> Text,
+        updated_at -> Timestamp,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
     }
 }
 
@@ -26,15 +149,50 @@ diesel::table! {
         id -> Int4,
         username -> Text,
         name -> Nullable<Text>,
+        role -> Text,
+    }
+}
+
+diesel::table! {
+    webauthn_credentials (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        credential_id -> Bytea,
+        passkey -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
     }
 }
 
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(logins -> users (user_id));
+diesel::joinable!(mark_events -> sessions (session_id));
+diesel::joinable!(mark_events -> users (user_id));
 diesel::joinable!(marks -> sessions (session_id));
 diesel::joinable!(marks -> users (user_id));
+diesel::joinable!(password_tokens -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(session_members -> sessions (session_id));
+diesel::joinable!(session_members -> users (user_id));
 diesel::joinable!(sessions -> users (owner_id));
+diesel::joinable!(totp_secrets -> users (user_id));
+diesel::joinable!(user_passwords -> users (user_id));
+diesel::joinable!(webauthn_credentials -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    logins,
+    mark_events,
     marks,
+    password_tokens,
+    recovery_codes,
+    session_members,
     sessions,
+    totp_secrets,
+    used_magic_links,
+    used_webauthn_flows,
+    user_passwords,
     users,
+    webauthn_credentials,
 );
@@ -0,0 +1,176 @@
+//! Outgoing mail
+//!
+//! Sent over SMTP in production. For development and tests, the mails can be written to a directory
+//!   as `.eml` files or just logged.
+
+use anyhow::{Context, Result};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Deserialize;
+use std::path::PathBuf;
+use tracing::{info, instrument};
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Sender of the mails, like `BAAM <baam@example.com>`
+    pub from: String,
+    pub transport: TransportConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransportConfig {
+    Smtp {
+        host: String,
+        /// The default port of the chosen security
+        port: Option<u16>,
+        #[serde(default)]
+        security: SmtpSecurity,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// Write the mails to `.eml` files in the directory
+    File { directory: PathBuf },
+    /// Only log the mails
+    Log,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// TLS from the start, usually on port 465
+    #[default]
+    Tls,
+    /// Upgrade to TLS with STARTTLS, usually on port 587
+    Starttls,
+    /// Plain text, only for relays on the local network
+    None,
+}
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    /// Plain text
+    pub body: String,
+}
+
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+    Log,
+}
+
+pub struct Mailer {
+    from: Mailbox,
+    transport: Transport,
+}
+
+impl Mailer {
+    pub fn new(config: Config) -> Result<Self> {
+        let from = config.from.parse().context("Parsing the sender address")?;
+
+        let transport = match config.transport {
+            TransportConfig::Smtp {
+                host,
+                port,
+                security,
+                username,
+                password,
+            } => {
+                let mut builder = match security {
+                    SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)
+                        .context("Setting up the SMTP transport")?,
+                    SmtpSecurity::Starttls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+                            .context("Setting up the SMTP transport")?
+                    }
+                    SmtpSecurity::None => {
+                        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host)
+                    }
+                };
+                if let Some(port) = port {
+                    builder = builder.port(port);
+                }
+                if let Some(username) = username {
+                    builder = builder
+                        .credentials(Credentials::new(username, password.unwrap_or_default()));
+                }
+                Transport::Smtp(builder.build())
+            }
+            TransportConfig::File { directory } => {
+                std::fs::create_dir_all(&directory)
+                    .with_context(|| format!("Creating the mail directory {:?}", directory))?;
+                Transport::File(AsyncFileTransport::new(directory))
+            }
+            TransportConfig::Log => Transport::Log,
+        };
+
+        Ok(Self { from, transport })
+    }
+
+    #[instrument(skip(self, email), fields(to = %email.to, subject = %email.subject))]
+    pub async fn send(&self, email: Email) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse().context("Parsing the recipient address")?)
+            .subject(email.subject.clone())
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body.clone())
+            .context("Building the mail")?;
+
+        match &self.transport {
+            Transport::Smtp(transport) => {
+                transport.send(message).await.context("Sending the mail")?;
+            }
+            Transport::File(transport) => {
+                let id = transport.send(message).await.context("Writing the mail")?;
+                info!("Mail written to {}.eml", id);
+            }
+            Transport::Log => {
+                info!("Not sending the mail:\n{}", email.body);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_file_transport() {
+        let directory = std::env::temp_dir().join(format!("baam-mail-{}", std::process::id()));
+        let mailer = Mailer::new(Config {
+            from: "BAAM <baam@example.com>".to_string(),
+            transport: TransportConfig::File {
+                directory: directory.clone(),
+            },
+        })
+        .unwrap();
+
+        mailer
+            .send(Email {
+                to: "guest@example.com".to_string(),
+                subject: "Hello".to_string(),
+                body: "Hello there".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let files = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|f| f.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        let mail = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(mail.contains("To: guest@example.com"));
+        assert!(mail.contains("Subject: Hello"));
+        assert!(mail.contains("Hello there"));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
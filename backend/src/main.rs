@@ -8,21 +8,23 @@ mod api;
 mod config;
 mod db;
 mod diagnostics;
+mod mail;
 mod middlewares;
 
-use crate::api::AuthKeys;
-use crate::middlewares::AddDiagnosticIds;
+use crate::api::{AuthKeys, Authority};
+use crate::db::models::Role;
+use crate::middlewares::{AddDiagnosticIds, RenewSession};
 use actix::SyncArbiter;
 use actix_web::middleware::ErrorHandlers;
 use actix_web::{web, App, HttpServer};
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use api::sentry_tunnel;
 use opentelemetry::sdk::resource::{EnvResourceDetector, SdkProvidedResourceDetector};
 use opentelemetry::sdk::{trace as sdktrace, Resource};
 use opentelemetry_otlp::{HasExportConfig, WithExportConfig};
 use std::io::ErrorKind;
 use std::time::Duration;
-use tracing::info;
+use tracing::{info, warn};
 use tracing_actix_web::TracingLogger;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::layer::SubscriberExt;
@@ -107,20 +109,24 @@ async fn main_impl() -> Result<()> {
     let database_url = get_database_url();
 
     let config = config::Config::load(&environment).context("Loading config")?;
+    if config.dev_mode && environment == "prod" {
+        bail!("dev_mode lets anyone log in as anyone, refusing to enable it in prod");
+    }
 
-    let auth_keys = AuthKeys::new([
-        // TODO: replace this hard-coded key with something more secure
-        0x5c, 0x6a, 0xc5, 0xf2, 0xb8, 0x12, 0xf1, 0x9d, 0x7e, 0x70, 0xd1, 0xe4, 0x9a, 0x28, 0x20,
-        0xa6, 0x5b, 0xba, 0xb8, 0x9a, 0xa3, 0x76, 0x0d, 0xb0, 0x80, 0x53, 0xe4, 0x3d, 0x7a, 0x5d,
-        0x27, 0x08, 0x3a, 0xb6, 0xf8, 0x28, 0xf2, 0x69, 0x04, 0x61, 0xd7, 0x05, 0xdb, 0x89, 0x1d,
-        0x0d, 0xef, 0x94, 0x6e, 0xdd, 0xc2, 0x44, 0xf2, 0x92, 0xa3, 0x67, 0x71, 0x80, 0x31, 0xe5,
-        0xb2, 0xcb, 0x8f, 0xc0,
-    ])
-    .context("Generating auth keys")?;
+    let auth_providers = api::auth_providers(&config);
+    if auth_providers.is_empty() {
+        warn!("No auth providers are configured, nobody will be able to log in");
+    } else {
+        info!("Active auth providers: {}", auth_providers.join(", "));
+    }
+
+    let auth_keys = AuthKeys::load(&config.auth).context("Loading auth keys")?;
 
     let database = db::DbExecutor::new(&database_url).context("Connecting to the database")?;
     let database = SyncArbiter::start(3, move || database.clone());
-    let api = api::configure(config.clone(), auth_keys).context("Configuring api")?;
+    let authority =
+        web::Data::new(Authority::new(&config.auth, auth_keys).context("Configuring authority")?);
+    let api = api::configure(config.clone(), authority.clone()).context("Configuring api")?;
     let frontend = baam_frontend::configure(config.frontend).context("Configuring frontend")?;
 
     info!("Starting server on http://{}/", config.server.endpoint);
@@ -133,6 +139,7 @@ async fn main_impl() -> Result<()> {
             .service(
                 web::scope("/api")
                     .configure(api.clone())
+                    .wrap(RenewSession::new(authority.clone()))
                     .wrap(ErrorHandlers::new().default_handler(api::error::api_error_handler)),
             )
             .configure(frontend.clone())
@@ -144,9 +151,57 @@ async fn main_impl() -> Result<()> {
     Ok(())
 }
 
+/// Prints a fresh auth keypair in the format of the `auth` config section
+fn generate_key(key_id: Option<String>) -> Result<()> {
+    let key_id = key_id.unwrap_or_else(|| chrono::Utc::now().format("%Y-%m-%d").to_string());
+    let (keypair, public_key) = AuthKeys::generate().context("Generating auth keys")?;
+
+    println!("signing_key: \"{key_id}\"");
+    println!("keys:");
+    println!("  - id: \"{key_id}\"");
+    println!("    keypair: \"{keypair}\"");
+    println!("# the public part, for validation-only use after the key is rotated out:");
+    println!("#   public_key: \"{public_key}\"");
+
+    Ok(())
+}
+
+/// Sets the role of a user directly in the database, e.g. to make the first admin
+async fn set_role(username: Option<String>, role: Option<String>) -> Result<()> {
+    let (username, role) = username
+        .zip(role)
+        .ok_or_else(|| anyhow!("Usage: set-role <username> <role>"))?;
+    let role: Role = role.parse()?;
+
+    let database =
+        db::DbExecutor::new(&get_database_url()).context("Connecting to the database")?;
+    let database = SyncArbiter::start(1, move || database.clone());
+
+    let user = database
+        .send(db::SetUserRole {
+            span: tracing::Span::current(),
+            username,
+            role,
+        })
+        .await?
+        .map_err(|e| anyhow!("Setting the role: {:?}", e))?;
+
+    println!("User {:?} now has the {} role", user.username, user.role);
+
+    Ok(())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    main_impl()
-        .await
-        .map_err(|e| std::io::Error::new(ErrorKind::Other, e))
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => main_impl().await,
+        Some("generate-key") => generate_key(args.next()),
+        Some("set-role") => set_role(args.next(), args.next()).await,
+        Some(command) => Err(anyhow!(
+            "Unknown command {:?}, expected no arguments, `generate-key [key id]` or `set-role <username> <role>`",
+            command
+        )),
+    }
+    .map_err(|e| std::io::Error::new(ErrorKind::Other, e))
 }
@@ -1,9 +1,11 @@
+use crate::api::Authority;
 use crate::diagnostics::RequestIds;
 use actix_http::header::HeaderName;
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error,
+    web, Error,
 };
+use chrono::Utc;
 use futures::future::LocalBoxFuture;
 use std::future::{ready, Ready};
 use std::str::FromStr;
@@ -74,3 +76,77 @@ where
         })
     }
 }
+
+/// Re-issues the session cookie when the token in it is past half of its lifetime,
+///   so that an active user is not logged out in the middle of their work
+pub struct RenewSession {
+    authority: web::Data<Authority>,
+}
+
+impl RenewSession {
+    pub fn new(authority: web::Data<Authority>) -> Self {
+        Self { authority }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RenewSession
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RenewSessionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RenewSessionMiddleware {
+            service,
+            authority: self.authority.clone(),
+        }))
+    }
+}
+
+pub struct RenewSessionMiddleware<S> {
+    service: S,
+    authority: web::Data<Authority>,
+}
+
+impl<S, B> Service<ServiceRequest> for RenewSessionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let authority = self.authority.clone();
+        let renewed = req
+            .cookie(&authority.cookie_name)
+            .and_then(|cookie| authority.renew_cookie(&cookie, Utc::now()));
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res: ServiceResponse<B> = fut.await?;
+
+            // login and logout set the cookie themselves
+            let sets_cookie = res
+                .response()
+                .cookies()
+                .any(|c| c.name() == authority.cookie_name);
+
+            if let Some(cookie) = renewed.filter(|_| !sets_cookie) {
+                res.response_mut().add_cookie(&cookie)?;
+            }
+
+            Ok(res)
+        })
+    }
+}
@@ -6,9 +6,13 @@ import type {
   ApiEmpty,
   ApiGetSession,
   ApiLogin,
+  ApiLoginResult,
   ApiNewSession,
   ApiSession,
   ApiSessionWithMarks,
+  ApiTimeRequest,
+  ApiTimeResponse,
+  ApiTotpCode,
   ApiUser,
   AttendanceMark,
   Session,
@@ -134,7 +138,8 @@ export function showError(error: unknown) {
 function map_session(s: ApiSession): Session {
   return {
     ...s,
-    start_time: new Date(s.start_time)
+    start_time: new Date(s.start_time),
+    end_time: s.end_time ? new Date(s.end_time) : undefined
   };
 }
 
@@ -142,6 +147,7 @@ function map_session_with_marks(s: ApiSessionWithMarks): SessionWithMarks {
   return {
     ...s,
     start_time: new Date(s.start_time),
+    end_time: s.end_time ? new Date(s.end_time) : undefined,
     attendance_marks: Array.from(s.attendance_marks).map(map_attendance_mark)
   };
 }
@@ -157,7 +163,11 @@ export function make_api(fetch: Fetch) {
   const api = new Fetcher(fetch, '/api');
   return {
     me: () => api.get<ApiUser>('/me'),
-    login: (data: ApiLogin) => api.post<ApiLogin, ApiEmpty>('/login', data),
+    login: (data: ApiLogin) => api.post<ApiLogin, ApiLoginResult>('/login', data),
+    totp: {
+      verify: (data: ApiTotpCode) => api.post<ApiTotpCode, ApiEmpty>('/totp/verify', data)
+    },
+    time: (data: ApiTimeRequest) => api.post<ApiTimeRequest, ApiTimeResponse>('/time', data),
     sessions: {
       list: () =>
         api.get<ApiSession[], Session[]>('/sessions', (data) => Array.from(data).map(map_session)),
@@ -167,6 +177,12 @@ export function make_api(fetch: Fetch) {
         api.post<ApiNewSession, ApiSession, Session>('/sessions', data, map_session),
       delete: (data: ApiDeleteSession) =>
         api.delete<ApiSession, Session>(`/sessions/${data.id}`, map_session),
+      pause: ({ id }: ApiGetSession) =>
+        api.post<undefined, ApiSession, Session>(`/sessions/${id}/pause`, undefined, map_session),
+      resume: ({ id }: ApiGetSession) =>
+        api.post<undefined, ApiSession, Session>(`/sessions/${id}/resume`, undefined, map_session),
+      close: ({ id }: ApiGetSession) =>
+        api.post<undefined, ApiSession, Session>(`/sessions/${id}/close`, undefined, map_session),
 
       add_mark: (data: ApiAttendanceMarkRef) =>
         api.put<undefined, ApiAttendanceMark, AttendanceMark>(
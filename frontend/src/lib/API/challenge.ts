@@ -0,0 +1,39 @@
+import type { ApiChallengeResult } from './models';
+
+const seconds = (ms: number) => (ms / 1000).toFixed(1);
+
+// what to tell the student about a failed challenge
+export function describeChallengeResult(result: ApiChallengeResult): string {
+  switch (result.result) {
+    case 'Success':
+      // null if the session hides it
+      if (result.other_students_count == null) return 'You are marked as present';
+      return `You are marked as present, along with ${result.other_students_count} others`;
+    case 'Invalid':
+      return 'This is not a BAAM code';
+    case 'Failed':
+      switch (result.reason) {
+        case 'unknown_session':
+          return 'The session of this code does not exist';
+        case 'session_paused':
+          return 'The check-in is paused, try again when the teacher resumes it';
+        case 'session_closed':
+          return 'The session is closed';
+        case 'wrong_format':
+        case 'bad_signature':
+          return 'The code is damaged, try scanning it again';
+        case 'too_early':
+          return `The code is ${seconds(result.early_ms)} s ahead, check the clock of the display`;
+        case 'expired':
+          return `The code expired ${seconds(result.late_ms)} s ago, scan the one on the screen`;
+        case 'network_not_allowed':
+          return 'Connect to the campus network to check in';
+        case 'location_required':
+          return 'Allow the location access to check in';
+        case 'outside_geofence':
+          return `You are ${result.distance_m} m away from the session`;
+        case undefined:
+          return 'Could not check you in';
+      }
+  }
+}
@@ -0,0 +1,30 @@
+import type { ApiAttendanceMark, ApiMarkRemoved, ApiSessionWithMarks } from './models';
+
+export interface MarkFeedHandlers {
+  // on connect, replaces everything known about the session
+  snapshot: (session: ApiSessionWithMarks) => void;
+  // may repeat a mark that is already there
+  mark_added: (mark: ApiAttendanceMark) => void;
+  mark_removed: (removed: ApiMarkRemoved) => void;
+}
+
+/*
+  Follows the marks of the session as they come and go.
+  The browser reconnects by itself, sending the id of the last event, and the server
+  then sends only the events that were missed.
+
+  Returns the function to stop following.
+*/
+export function followMarks(session_id: number, handlers: MarkFeedHandlers): () => void {
+  const source = new EventSource(`/api/sessions/${session_id}/feed`);
+
+  source.addEventListener('snapshot', (e) => handlers.snapshot(JSON.parse(e.data)));
+  source.addEventListener('mark_added', (e) => handlers.mark_added(JSON.parse(e.data)));
+  source.addEventListener('mark_removed', (e) => handlers.mark_removed(JSON.parse(e.data)));
+  source.onerror = () => {
+    // the session is gone or the user has no access to it anymore, retrying will not help
+    if (source.readyState === EventSource.CLOSED) console.warn('The attendance feed has stopped');
+  };
+
+  return () => source.close();
+}
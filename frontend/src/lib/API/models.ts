@@ -10,26 +10,86 @@ export interface ApiLogin {
   name: string;
 }
 
+export interface ApiLoginResult {
+  // the code has to be posted to /totp/verify before the user is logged in
+  second_factor_required: boolean;
+}
+
+// a code from the authenticator app or a recovery code
+export interface ApiTotpCode {
+  code: string;
+}
+
 export interface ApiSession {
   id: number;
   title?: string;
   active: boolean;
+  state: ApiSessionState;
 
-  // ISO 8601 date string
+  // ISO 8601 date strings, no end time while the session is ongoing
   start_time: string;
+  end_time?: string;
 }
-export type Session = Omit<ApiSession, 'start_time'> & { start_time: Date };
+export type Session = Omit<ApiSession, 'start_time' | 'end_time'> & {
+  start_time: Date;
+  end_time?: Date;
+};
+
+// a closed session only takes manual marks
+export type ApiSessionState = 'running' | 'paused' | 'closed';
+
+export type ApiChallengeAlgorithm = 'hmac-sha1' | 'hmac-sha256';
 
 export interface ApiSessionWithMarks extends ApiSession {
+  // only for the members who can mark
+  seed?: string;
+  challenge_algorithm: ApiChallengeAlgorithm;
+  challenge_tag_length: number;
+  // how often the QR code changes and how late or early it is still accepted
+  qr_interval_ms: number;
+  jitter_window_ms: number;
+  geofence?: ApiGeofence;
+  // CIDR ranges the challenges have to come from, anywhere if empty
+  allowed_networks: string[];
+  network_policy: ApiNetworkPolicy;
+  student_visibility: ApiStudentVisibility;
   attendance_marks: ApiAttendanceMark[];
+  // number of the marks with flags
+  flagged_marks: number;
 }
-export type SessionWithMarks = Omit<ApiSessionWithMarks, 'start_time' | 'attendance_marks'> & {
+export type SessionWithMarks = Omit<
+  ApiSessionWithMarks,
+  'start_time' | 'end_time' | 'attendance_marks'
+> & {
   start_time: Date;
+  end_time?: Date;
   attendance_marks: AttendanceMark[];
 };
 
 export interface ApiNewSession {
   title?: string;
+  challenge_algorithm?: ApiChallengeAlgorithm;
+  challenge_tag_length?: number;
+  // the config is used if not set
+  qr_interval_ms?: number;
+  jitter_window_ms?: number;
+  geofence?: ApiGeofence;
+  allowed_networks?: string[];
+  network_policy?: ApiNetworkPolicy;
+  student_visibility?: ApiStudentVisibility;
+}
+
+// what the students are told about the others marked in the session
+export type ApiStudentVisibility = 'hidden' | 'count' | 'names';
+
+export type ApiNetworkPolicy = 'reject' | 'flag';
+
+export interface ApiGeofence {
+  latitude: number;
+  longitude: number;
+  radius_m: number;
+  // what happens to the challenges from outside
+  policy: 'reject' | 'flag';
 }
 
 export interface ApiGetSession {
@@ -43,11 +103,84 @@ export interface ApiAttendanceMarkRef {
   username: string;
 }
 
+export type ApiMarkFlag =
+  | 'shared_code'
+  | 'late_from_other_network'
+  | 'outside_allowed_networks'
+  | 'outside_geofence'
+  | 'unknown_location';
+
 export interface ApiAttendanceMark {
   username: string;
 
   // ISO 8601 date string
   mark_time: string;
   is_manual: boolean;
+  // who added the mark after the session was closed
+  note?: string;
+  // why the mark looks suspicious, for the teacher to review
+  flags: ApiMarkFlag[];
 }
 export type AttendanceMark = Omit<ApiAttendanceMark, 'mark_time'> & { mark_time: Date };
+
+// an event of the attendance feed
+export interface ApiMarkRemoved {
+  username: string;
+}
+
+export interface ApiClockEstimate {
+  // how far the local clock is ahead of the server one
+  offset_ms: number;
+  rtt_ms: number;
+}
+
+export interface ApiTimeRequest {
+  // Date.now() when sending the request
+  client_time: number;
+  session_id?: number;
+  last_estimate?: ApiClockEstimate;
+}
+
+export interface ApiTimeResponse {
+  client_time: number;
+  receive_time: number;
+  transmit_time: number;
+  jitter_window_ms: number;
+  // the last estimate is too close to the jitter window
+  skew_warning: boolean;
+}
+
+export interface ApiLocation {
+  latitude: number;
+  longitude: number;
+  accuracy_m: number;
+}
+
+export interface ApiChallenge {
+  challenge: string;
+  // needed for the sessions with a geofence
+  location?: ApiLocation;
+}
+
+export type ApiChallengeFailure =
+  | { reason: 'unknown_session' }
+  | { reason: 'session_paused' }
+  | { reason: 'session_closed' }
+  | { reason: 'wrong_format' }
+  | { reason: 'bad_signature' }
+  | { reason: 'too_early'; early_ms: number }
+  | { reason: 'expired'; late_ms: number }
+  | { reason: 'network_not_allowed' }
+  | { reason: 'location_required' }
+  | { reason: 'outside_geofence'; distance_m: number };
+
+export type ApiChallengeResult =
+  | {
+      result: 'Success';
+      // the latest other students marked, and the number of all of them, as far as the session shows
+      other_students: ApiUser[];
+      other_students_count?: number;
+    }
+  | { result: 'Invalid' }
+  // the reason is hidden from the untrusted clients if the server is configured so
+  | ({ result: 'Failed' } & (ApiChallengeFailure | { reason?: undefined }));
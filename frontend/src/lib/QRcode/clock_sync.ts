@@ -0,0 +1,43 @@
+import { api } from '$lib/API/api';
+import type { ApiClockEstimate } from '$lib/API/models';
+
+export interface ClockSync extends ApiClockEstimate {
+  jitter_window_ms: number;
+  // the clock is off by too much of the jitter window, the codes may be rejected
+  skew_warning: boolean;
+}
+
+/*
+  Estimates how far the local clock is ahead of the server one, the NTP way.
+  The sample with the shortest round trip is the most precise one, and it is reported
+  to the server with one more request, which tells whether the teacher should be warned.
+
+  session_id -- session shown on the display, for its jitter window
+  samples -- number of exchanges to pick the estimate from
+*/
+export async function syncClock(session_id?: number, samples = 5): Promise<ClockSync> {
+  let best: ApiClockEstimate | undefined;
+  for (let i = 0; i < samples; i++) {
+    const client_time = Date.now();
+    const response = await api.time({ client_time, session_id });
+    const t = Date.now();
+
+    const estimate = {
+      offset_ms: Math.round(
+        (client_time - response.receive_time + (t - response.transmit_time)) / 2
+      ),
+      rtt_ms: t - client_time - (response.transmit_time - response.receive_time)
+    };
+    if (best === undefined || estimate.rtt_ms < best.rtt_ms) {
+      best = estimate;
+    }
+  }
+
+  const report = await api.time({ client_time: Date.now(), session_id, last_estimate: best });
+  return {
+    offset_ms: best?.offset_ms ?? 0,
+    rtt_ms: best?.rtt_ms ?? 0,
+    jitter_window_ms: report.jitter_window_ms,
+    skew_warning: report.skew_warning
+  };
+}
@@ -1,5 +1,5 @@
 import PreciseTimer from './precise_timer';
-import { generateSessionCode } from './session_code_encode';
+import { generateSessionCode, type ChallengeAlgorithm } from './session_code_encode';
 
 export default class SessionCodeTimer {
   t0: number;
@@ -10,6 +10,7 @@ export default class SessionCodeTimer {
   sess_id: number;
   secret: string;
   bytes_to_slice: number;
+  algorithm: ChallengeAlgorithm;
 
   /*
     secret -- base64 secret string
@@ -18,6 +19,8 @@ export default class SessionCodeTimer {
     tx -- number of seconds in epoch
     bytes_to_slice -- number of bytes to get from hmac
     callback -- callback to call whenever epoch changes
+    algorithm -- challenge algorithm of the session
+    clock_offset -- how far the local clock is ahead of the server one, in ms
   */
   constructor(
     secret: string,
@@ -25,17 +28,26 @@ export default class SessionCodeTimer {
     t0: Date,
     tx: number,
     callback: (x: string) => void,
-    bytes_to_slice = 4
+    bytes_to_slice = 4,
+    algorithm: ChallengeAlgorithm = 'hmac-sha1',
+    clock_offset = 0
   ) {
     this.secret = secret;
     this.sess_id = sess_id;
     // remove ms and cast to int
     this.t0 = t0.getTime();
     this.tx = tx;
-    this.counter = Math.floor((Date.now() - this.t0) / this.tx);
+    this.counter = Math.floor((Date.now() - clock_offset - this.t0) / this.tx);
     this.bytes_to_slice = bytes_to_slice;
+    this.algorithm = algorithm;
     this.callback = callback;
-    this.timer = new PreciseTimer(() => this.getSessionCode(), this.t0, this.tx);
+    this.timer = new PreciseTimer(() => this.getSessionCode(), this.t0, this.tx, clock_offset);
+  }
+
+  // apply a new estimate of the clock offset, from the next code on
+  setClockOffset(clock_offset: number) {
+    this.timer.clock_offset = clock_offset;
+    this.counter = Math.floor((this.timer.now() - this.t0) / this.tx);
   }
 
   run() {
@@ -49,7 +61,8 @@ export default class SessionCodeTimer {
       this.sess_id,
       this.counter,
       this.secret,
-      this.bytes_to_slice
+      this.bytes_to_slice,
+      this.algorithm
     );
 
     this.callback(encoded_code);
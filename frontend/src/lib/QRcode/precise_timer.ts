@@ -2,20 +2,28 @@ export default class PreciseTimer {
   fn: () => void;
   start_time: number;
   duration: number;
+  // how far the local clock is ahead of the server one, in ms
+  clock_offset: number;
   timer?: NodeJS.Timeout;
 
-  constructor(fn: () => void, start_time: number, duration: number) {
+  constructor(fn: () => void, start_time: number, duration: number, clock_offset = 0) {
     this.fn = fn;
     this.start_time = start_time;
     this.duration = duration;
+    this.clock_offset = clock_offset;
     this.timer = undefined;
   }
 
+  // the server time
+  now(): number {
+    return Date.now() - this.clock_offset;
+  }
+
   run(): void {
-    let delta = (new Date().getTime() - this.start_time) / this.duration;
+    let delta = (this.now() - this.start_time) / this.duration;
     this.fn();
 
-    delta = (new Date().getTime() - this.start_time) / this.duration;
+    delta = (this.now() - this.start_time) / this.duration;
     delta -= Math.round(delta);
     this.timer = setTimeout(() => this.run(), (1 - delta) * this.duration);
   }
@@ -15,3 +15,11 @@ test('test_encoder_wrong_counter', () => {
 
   expect(code.toString(CryptoJS.enc.Hex)).toSatisfy((x: string) => !x.startsWith('308975b4'));
 }, 1000);
+
+test('test_encoder_hmac_sha256', () => {
+  const encoded_code = generateSessionCode(12, 4, 'YNxExINfvxmC0q6g', 8, 'hmac-sha256');
+  const code = CryptoJS.enc.Base64url.parse(encoded_code).toString(CryptoJS.enc.Hex);
+
+  // session id and counter first, then 8 bytes of the tag and the format byte
+  expect(code).toBe('0c04' + 'e94982452da2585e' + '81');
+}, 1000);
@@ -1,5 +1,6 @@
 import CryptoJS from 'crypto-js';
 import hmacSHA1 from 'crypto-js/hmac-sha1';
+import hmacSHA256 from 'crypto-js/hmac-sha256';
 import WordArray from 'crypto-js/lib-typedarrays';
 import varint from 'varint';
 
@@ -49,19 +50,34 @@ function efficientEncodeToWordArray(x: number) {
   return WordArray.create(word_array, bytes.length);
 }
 
+export type ChallengeAlgorithm = 'hmac-sha1' | 'hmac-sha256';
+
+// the last byte of the versioned formats, the legacy one (HMAC-SHA1) never ends with the high bit set
+const FORMAT_HMAC_SHA256 = 0x81;
+
 export function generateSessionCode(
   sess_id: number,
   counter: number,
   secret_key: string,
-  bytes_to_slice: number
+  bytes_to_slice: number,
+  algorithm: ChallengeAlgorithm = 'hmac-sha1'
 ): string {
   const secret = CryptoJS.enc.Base64.parse(secret_key);
 
   const index_encoded = encodeNumberToWordArray(counter);
-  let code = hmacSHA1(index_encoded, secret);
-  code = truncate(code, bytes_to_slice);
-  code = code.concat(efficientEncodeToWordArray(sess_id));
-  code = code.concat(efficientEncodeToWordArray(counter));
+  let code: WordArray;
+  if (algorithm === 'hmac-sha256') {
+    // [session id][counter][tag][format]
+    code = efficientEncodeToWordArray(sess_id);
+    code = code.concat(efficientEncodeToWordArray(counter));
+    code = code.concat(truncate(hmacSHA256(index_encoded, secret), bytes_to_slice));
+    code = code.concat(WordArray.create([FORMAT_HMAC_SHA256 << 24], 1));
+  } else {
+    // [tag][session id][counter]
+    code = truncate(hmacSHA1(index_encoded, secret), bytes_to_slice);
+    code = code.concat(efficientEncodeToWordArray(sess_id));
+    code = code.concat(efficientEncodeToWordArray(counter));
+  }
   const encoded_code = code.toString(CryptoJS.enc.Base64url);
 
   return encoded_code;